@@ -13,16 +13,17 @@ interface IComposition {
   resetTrajectory: () => void,
   submit_waiting: boolean,
   setSubmitWaiting: React.Dispatch<React.SetStateAction<boolean>>,
+  catalogue_version: number,
 }
 
 const Composition: React.FC<IComposition>
-  = ({socket, socket_connected, running, setRunning, resetTrajectory, submit_waiting, setSubmitWaiting}) =>
+  = ({socket, socket_connected, running, setRunning, resetTrajectory, submit_waiting, setSubmitWaiting, catalogue_version}) =>
 {
   const [molecules, setMolecules] = useState<Array<MixtureComponentWith3D>>([]);
   const [input_config, setInputConfig] = useState<Map<string, InputConfig>>(new Map([]));
   const [config, setConfig] = useState<PytfConfig>({settings: new Map([]), mixture: []});
 
-  // Get the list of available molecules on load
+  // Get the list of available molecules on load, and again whenever the server reloads it
   useEffect(() => {
     let abort = new AbortController();
     const fetchMolecules = async () => {
@@ -46,7 +47,7 @@ const Composition: React.FC<IComposition>
     };
     fetchMolecules();
     return () => abort.abort();
-  }, [setMolecules]);
+  }, [setMolecules, catalogue_version]);
 
   // Get configuration options
   useEffect(() => {
@@ -77,7 +78,7 @@ const Composition: React.FC<IComposition>
     };
     fetchInputConfig();
     return () => abort.abort();
-  }, [setInputConfig]);
+  }, [setInputConfig, catalogue_version]);
 
   // Set up base config with everything zeroed
   useEffect(() => {
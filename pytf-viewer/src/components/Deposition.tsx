@@ -30,6 +30,7 @@ interface IDeposition {
 const Deposition: React.FC<IDeposition> = ({ token, setToken, dark_mode, setDarkMode }) => {
   const [running, setRunning] = useState(false);
   const [failed, setFailed] = useState(false);
+  const [failed_worker, setFailedWorker] = useState("");
   const socket = useRef<WebSocket | null>(null);
   const [socket_connected, setSocketConnected] = useState(false);
   const [last_message, setLastMessage] = useState<MessageEvent<any> | null>(null);
@@ -42,6 +43,7 @@ const Deposition: React.FC<IDeposition> = ({ token, setToken, dark_mode, setDark
   const [num_segments, setNumSegments] = useState<number>(0);
   const [sim_done, setSimDone] = useState<boolean>(false);
   const [submit_waiting, setSubmitWaiting] = useState(false);
+  const [catalogue_version, setCatalogueVersion] = useState(0);
 
   const [particles_roughness, setParticlesRoughness] = useState<Particles | null>(null);
   const [roughness_ready, setRoughnessReady] = useState<boolean>(false);
@@ -91,6 +93,11 @@ const Deposition: React.FC<IDeposition> = ({ token, setToken, dark_mode, setDark
   useEffect(() => {
     if (last_message === null) return;
     setLastMessage(null);
+    if (last_message.data === "catalogue") {
+      // Molecules or protocol settings changed on the server, so fetch them again
+      setCatalogueVersion((v) => v + 1);
+      return;
+    }
     if (!running) {
       console.log("Unexpected message while not running");
       return;
@@ -152,11 +159,17 @@ const Deposition: React.FC<IDeposition> = ({ token, setToken, dark_mode, setDark
     } else if (last_message.data === "cancel") {
       setRunning(false);
 
-    } else if (last_message.data === "failed") {
+    } else if (last_message.data.startsWith("failed")) {
       // console.log("Job failed!");
       setRunning(false);
       setFailed(true);
+      setFailedWorker(last_message.data.slice(6));
       // setWaitForSegment(false);
+    } else if (last_message.data.startsWith("denied")) {
+      // Job not allowed (e.g. guest limits)
+      setRunning(false);
+      alert(last_message.data.slice(6));
+
     } else if (last_message.data !== "queued") {
       // queued sent when job has been queued.
       // No need to handle apart from unsetting submit_waiting below.
@@ -217,7 +230,9 @@ const Deposition: React.FC<IDeposition> = ({ token, setToken, dark_mode, setDark
     } else if (submit_waiting) {
       setStatusText("Submitting");
     } else if (failed) {
-      setStatusText("Simulation failed! Try a different configuration.");
+      setStatusText("Simulation failed"
+        + (failed_worker ? " on worker " + failed_worker : "")
+        + "! Try a different configuration.");
     } else if (running) {
       if (num_segments > 0) {
         if (latest_segment < num_segments) {
@@ -233,7 +248,7 @@ const Deposition: React.FC<IDeposition> = ({ token, setToken, dark_mode, setDark
     } else {
       setStatusText("Idle");
     }
-  }, [submit_waiting, failed, running, next_segment, latest_segment, num_segments, roughness_ready, socket_connected]);
+  }, [submit_waiting, failed, failed_worker, running, next_segment, latest_segment, num_segments, roughness_ready, socket_connected]);
 
   const tabs = [
     {
@@ -244,6 +259,7 @@ const Deposition: React.FC<IDeposition> = ({ token, setToken, dark_mode, setDark
           socket={socket} socket_connected={socket_connected}
           running={running} setRunning={setRunning}
           submit_waiting={submit_waiting} setSubmitWaiting={setSubmitWaiting}
+          catalogue_version={catalogue_version}
           resetTrajectory={() => {
             // console.log("Resetting trajectory");
             particles.map((p) => p.dispose());
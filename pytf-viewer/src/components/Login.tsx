@@ -1,4 +1,4 @@
-import React, { useState } from 'react';
+import React, { useEffect, useState } from 'react';
 import '../App.css';
 
 
@@ -13,17 +13,65 @@ export async function logout(token: any) {
     }).then(data => data.json());
 }
 
+// Messages for the error codes the server sends back when logging in through an identity provider fails
+const OIDC_ERRORS: Record<string, string> = {
+  expired: "Login expired. Please try again.",
+  failed: "Login failed. Please try again.",
+  disabled: "User account is disabled.",
+};
+
 interface ILogin {
   setToken: React.Dispatch<React.SetStateAction<string | null>>;
 }
 const Login: React.FC<ILogin> = ({ setToken }) => {
   const [username, setUsername] = useState('');
   const [password, setPassword] = useState('');
-  const [loginFailed, setLoginFailed] = useState(false);
+  // Errors from logging in through an identity provider come back in the URL
+  const [loginFailed, setLoginFailed] = useState<string | null>(() => {
+    const code = new URLSearchParams(window.location.search).get("login_error");
+    return code === null ? null : (OIDC_ERRORS[code] ?? OIDC_ERRORS.failed);
+  });
+  const [guestMode, setGuestMode] = useState(false);
+  const [oidcName, setOidcName] = useState<string | null>(null);
+
+  // Only offer guest login if the server has guest mode enabled
+  useEffect(() => {
+    if (window.location.search) {
+      window.history.replaceState(null, "", window.location.pathname);
+    }
+    fetch("/guest-mode")
+      .then(data => data.ok ? data.json() : { enabled: false })
+      .then(mode => setGuestMode(mode.enabled))
+      .catch(() => setGuestMode(false));
+    fetch("/oidc")
+      .then(data => data.ok ? data.json() : { enabled: false })
+      .then(oidc => setOidcName(oidc.enabled ? oidc.name : null))
+      .catch(() => setOidcName(null));
+  }, []);
+
+  const guestLogin = async () => {
+    setLoginFailed(null);
+    const token = await fetch("/login", {
+      method: "post",
+      headers: {
+        'Content-Type': 'application/json'
+      },
+      body: JSON.stringify({guest: true})
+    }).then(data => {
+      if (data.ok) return data.json();
+      return data.text().then(msg => {
+        setLoginFailed(msg);
+        return null;
+      });
+    });
+    if (token) {
+      setToken(JSON.stringify(token));
+    }
+  }
 
   const handleSubmit = async (e: React.FormEvent) => {
     async function login(credentials: any) {
-      setLoginFailed(false);
+      setLoginFailed(null);
       return fetch("/login", {
         method: "post",
         headers: {
@@ -32,10 +80,17 @@ const Login: React.FC<ILogin> = ({ setToken }) => {
         body: JSON.stringify({username: credentials.username, password: credentials.password})
       }).then(data => {
         if (data.ok) {
-          setLoginFailed(false);
+          setLoginFailed(null);
           return data.json();
         }
-        setLoginFailed(true);
+        if (data.status === 429) {
+          // Locked out after too many failed attempts
+          return data.text().then(msg => {
+            setLoginFailed(msg);
+            return null;
+          });
+        }
+        setLoginFailed("Incorrect username or password!");
         return null
       });
     }
@@ -69,9 +124,21 @@ const Login: React.FC<ILogin> = ({ setToken }) => {
             />
             <button className="submit-button login" type="submit" color="var(--col-smiles-bg)">Sign in</button>
             <input type="hidden" name="login" value="login"/>
+            {oidcName ?
+              <button className="submit-button login" type="button"
+                onClick={() => { window.location.href = "/oidc/login"; }}
+              >
+                Sign in with {oidcName}
+              </button>
+              : <></>}
+            {guestMode ?
+              <button className="submit-button login" type="button" onClick={guestLogin}>
+                Continue as guest
+              </button>
+              : <></>}
           </form>
           <div className="login-fail" style={{display: loginFailed ? 'flex' : 'none'}}>
-            Incorrect username or password!
+            {loginFailed}
           </div>
         </div>
       </div>
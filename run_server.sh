@@ -11,7 +11,7 @@ source pyenv/bin/activate
 
 # Start the server
 redis-server > redis.log 2>&1 &
-cargo run ${mode} --bin pytf-server -- -u "${users}"
+cargo run ${mode} --bin pytf-server -- -u "${users}" --cookie-key cookie.key
 
 # Make sure redis-server exits when the server shuts down
 trap "trap - SIGTERM && kill -- -$$" SIGINT SIGTERM EXIT
@@ -2,5 +2,6 @@
 set -oeu pipefail
 server="${1:-127.0.0.1:8080}"
 key="${2:-foobar}"
+name="${3:-worker}"
 source pyenv/bin/activate
-cargo run --release --bin pytf-worker -- "${server}" "${key}"
+cargo run --release --bin pytf-worker -- "${server}" "${key}" --name "${name}"
@@ -0,0 +1,180 @@
+use std::io::ErrorKind;
+
+use actix::Addr;
+use actix_web::{delete, get, http, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use pytf_web::{
+    authentication::{Role, SessionUser, UserCredentials, USER_DB},
+    user_sessions::{SessionInfo, SESSIONS},
+};
+
+use crate::{
+    client_ip,
+    job_queue::{JobServer, SessionsRevoked},
+    login_throttle::LoginThrottle,
+};
+
+/// Request body for changing your own password
+#[derive(Debug, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Session details sent to the user, marking the session the request was made from
+#[derive(Debug, Serialize)]
+pub struct SessionSummary {
+    #[serde(flatten)]
+    pub info: SessionInfo,
+    pub current: bool,
+}
+
+/// Register a new login session for `user`, recording where it was started from
+pub async fn start_session(request: &HttpRequest, user: &mut SessionUser, trust_proxy: bool) -> Result<(), actix_web::Error> {
+    let ip = client_ip(request, trust_proxy).map(|ip| ip.to_string()).unwrap_or_default();
+    let user_agent = request.headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let username = user.username.clone();
+    match web::block(move || SESSIONS.get().unwrap().create(&username, &ip, &user_agent)).await? {
+        Ok(id) => {
+            user.session = Some(id);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Failed to save session for \"{}\": {e}", user.username);
+            Err(actix_web::error::ErrorInternalServerError("Failed to start session."))
+        }
+    }
+}
+
+/// Close any web socket connected with one of the revoked sessions
+async fn disconnect(srv: &Addr<JobServer>, name: &str, sessions: Vec<String>) -> usize {
+    if sessions.is_empty() { return 0 }
+    srv.send(SessionsRevoked { name: name.into(), sessions }).await.unwrap_or(0)
+}
+
+/// Revoke all of a user's sessions apart from `keep`, and close their connections
+pub async fn revoke_sessions(srv: &Addr<JobServer>, name: &str, keep: Option<&str>) -> usize {
+    let (username, keep) = (name.to_string(), keep.map(str::to_string));
+    match web::block(move || SESSIONS.get().unwrap().revoke_user(&username, keep.as_deref())).await {
+        Ok(Ok(revoked)) => {
+            let count = revoked.len();
+            disconnect(srv, name, revoked).await;
+            count
+        }
+        Ok(Err(e)) => {
+            log::error!("Failed to revoke sessions of \"{name}\": {e}");
+            0
+        }
+        Err(e) => {
+            log::error!("Failed to revoke sessions of \"{name}\": {e}");
+            0
+        }
+    }
+}
+
+/// Change your own password. Logs out all of your other sessions.
+#[post("/password")]
+async fn change_password(
+    request: HttpRequest,
+    user: SessionUser,
+    change: web::Json<PasswordChange>,
+    throttle: web::Data<LoginThrottle>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Student)?;
+    if user.external {
+        return Ok(HttpResponse::BadRequest().body("Your password is managed by your identity provider."))
+    }
+    let PasswordChange { current_password, new_password } = change.into_inner();
+
+    // Someone with access to an unlocked session shouldn't be able to guess the password
+    let ip = client_ip(&request, throttle.settings.trust_proxy);
+    if let Err(remaining) = throttle.check(&user.username, ip) {
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((http::header::RETRY_AFTER, remaining.as_secs() + 1))
+            .body("Too many incorrect passwords. Try again later."));
+    }
+    // Password hashing is slow, so keep it off the worker thread
+    let credentials = UserCredentials { username: user.username.clone(), password: current_password };
+    let valid = web::block(move || {
+        USER_DB.get().unwrap().validate_user(&credentials).is_some()
+    }).await?;
+    if !valid {
+        throttle.record_failure(&user.username, ip);
+        return Ok(HttpResponse::Unauthorized().body("Current password is incorrect."))
+    }
+    throttle.record_success(&user.username);
+
+    let username = user.username.clone();
+    if let Err(e) = web::block(move || USER_DB.get().unwrap().reset_password(&username, &new_password)).await? {
+        log::error!("Failed to change password for \"{}\": {e}", user.username);
+        return Ok(match e.kind() {
+            ErrorKind::InvalidInput => HttpResponse::BadRequest().body(e.to_string()),
+            _ => HttpResponse::InternalServerError().body("Failed to change password."),
+        })
+    }
+    let revoked = revoke_sessions(&srv, &user.username, user.session.as_deref()).await;
+    log::info!("User \"{}\" changed their password ({revoked} other session(s) logged out)", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// List your own logged in sessions
+#[get("/sessions")]
+async fn list_sessions(user: SessionUser) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Student)?;
+    let sessions: Vec<SessionSummary> = SESSIONS.get().unwrap()
+        .list(&user.username)
+        .into_iter()
+        .map(|info| SessionSummary { current: user.session.as_ref() == Some(&info.id), info })
+        .collect();
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// Log out one of your own sessions
+#[delete("/sessions/{id}")]
+async fn revoke_session(
+    user: SessionUser,
+    id: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Student)?;
+    let id = id.into_inner();
+    let (username, session) = (user.username.clone(), id.clone());
+    match web::block(move || SESSIONS.get().unwrap().revoke(&username, &session)).await? {
+        Ok(()) => {
+            disconnect(&srv, &user.username, vec![id]).await;
+            log::info!("User \"{}\" logged out one of their sessions", user.username);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HttpResponse::NotFound().body(e.to_string())),
+        Err(e) => {
+            log::error!("Failed to revoke session of \"{}\": {e}", user.username);
+            Ok(HttpResponse::InternalServerError().body(e.to_string()))
+        }
+    }
+}
+
+/// Log out all of your sessions apart from the current one
+#[delete("/sessions")]
+async fn revoke_other_sessions(
+    user: SessionUser,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Student)?;
+    let revoked = revoke_sessions(&srv, &user.username, user.session.as_deref()).await;
+    log::info!("User \"{}\" logged out {revoked} other session(s)", user.username);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "revoked": revoked })))
+}
+
+/// Register the account self-service endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(change_password)
+        .service(list_sessions)
+        .service(revoke_session)
+        .service(revoke_other_sessions);
+}
@@ -0,0 +1,81 @@
+use actix::Addr;
+use actix_web::{get, web, HttpResponse};
+
+use pytf_web::authentication::{Role, SessionUser};
+
+use crate::job_queue::{GetServerStatus, JobServer};
+
+/// Workers, jobs and connected clients of the job server
+#[get("/admin/status")]
+async fn server_status(
+    user: SessionUser,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    let status = srv.send(GetServerStatus {}).await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Register the admin status endpoint
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(server_status);
+}
+
+#[cfg(test)]
+mod test {
+    use actix::Actor;
+    use pytf_web::authentication::Role;
+
+    use super::*;
+    use crate::job_queue::{test::{TestClient, TestWorker}, test_archive_dir};
+
+    #[actix_web::test]
+    async fn test_server_status() {
+        test_archive_dir();
+        let srv = JobServer::new().start();
+        let status = srv.send(GetServerStatus {}).await.unwrap();
+        assert!(status.workers.is_empty());
+        assert!(status.clients.is_empty());
+        // Only the placeholder job exists
+        assert_eq!(status.jobs.len(), 1);
+        assert_eq!(status.jobs[0].status, "Waiting");
+        assert_eq!(status.jobs[0].clients, 0);
+        assert!(!status.jobs[0].queued);
+
+        // One job running on a worker, and one waiting for a worker with two clients attached
+        let alice = TestClient::connect("alice", Role::Student, &srv).await;
+        let bob = TestClient::connect("bob", Role::Student, &srv).await;
+        let carol = TestClient::connect("carol", Role::Student, &srv).await;
+        alice.submit("status-running", &srv).await.unwrap();
+        bob.submit("status-waiting", &srv).await.unwrap();
+        carol.submit("status-waiting", &srv).await.unwrap();
+        let mut worker = TestWorker::connect("w1", &srv);
+        assert_eq!(worker.next_job().await, "status-running");
+
+        let status = srv.send(GetServerStatus {}).await.unwrap();
+        assert_eq!(status.workers.len(), 1);
+        assert_eq!(status.workers[0].name, "w1");
+        assert!(!status.workers[0].idle);
+        assert_eq!(status.workers[0].job.as_deref(), Some("status-running"));
+
+        let job = |name: &str| status.jobs.iter().find(|job| job.name == name).unwrap();
+        assert_eq!(status.jobs.len(), 3);
+        let running = job("status-running");
+        assert_eq!(running.status, "Running");
+        assert_eq!(running.worker.as_deref(), Some("w1"));
+        assert_eq!(running.clients, 1);
+        let waiting = job("status-waiting");
+        assert_eq!(waiting.status, "Waiting");
+        assert_eq!(waiting.worker, None);
+        assert_eq!(waiting.clients, 2);
+        assert!(waiting.queued);
+
+        let clients: Vec<_> = status.clients.iter().map(|c| (c.name.as_str(), c.job.as_deref())).collect();
+        assert_eq!(clients, [
+            ("alice", Some("status-running")),
+            ("bob", Some("status-waiting")),
+            ("carol", Some("status-waiting")),
+        ]);
+    }
+}
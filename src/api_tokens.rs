@@ -0,0 +1,226 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::{OnceLock, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{authentication::write_atomic, to_hex};
+
+/// Database of API tokens
+pub static TOKEN_DB: OnceLock<TokenDB> = OnceLock::new();
+
+/// All API tokens start with this, so they're easy to recognise (e.g. in leaked logs)
+pub const TOKEN_PREFIX: &str = "pytf_";
+
+/// Details of an API token. The token itself is only shown once, when it is created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    /// Identifier used to revoke the token
+    pub id: String,
+    /// User the token authenticates as
+    pub username: String,
+    /// Description of what the token is for
+    pub name: String,
+    /// Creation time, in seconds since the unix epoch
+    pub created: u64,
+}
+
+type TokenMap = HashMap<String, TokenInfo>;
+
+/// Tokens are long and random, so a fast hash is enough to make a leaked tokens file useless,
+/// and avoids running Argon2 for every request.
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn random_hex(n_bytes: usize) -> String {
+    let mut bytes = vec![0u8; n_bytes];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Database of API tokens, indexed by the hash of the token.
+/// Stored as lines of id,username,created,sha256_hash,name
+#[derive(Debug, Default)]
+pub struct TokenDB {
+    tokens: RwLock<TokenMap>,
+    /// File the database was loaded from, and which changes are written back to.
+    file: Option<PathBuf>,
+}
+
+impl TokenDB {
+    /// Load the tokens database from the specified file, or start an empty database
+    /// (saved to that file once a token is created) if it doesn't exist.
+    pub fn load(fname: impl AsRef<Path>) -> io::Result<Self> {
+        let fname = fname.as_ref();
+        let tokens = match fs::File::open(fname) {
+            Ok(fid) => Self::parse_csv(fid)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::info!("No API tokens file at {}. It will be created when needed.", fname.to_string_lossy());
+                TokenMap::new()
+            }
+            Err(e) => return Err(e),
+        };
+        log::debug!("Loaded {} API tokens", tokens.len());
+        Ok(Self { tokens: RwLock::new(tokens), file: Some(fname.to_owned()) })
+    }
+
+    fn parse_csv(file: impl io::Read) -> io::Result<TokenMap> {
+        let mut tokens = TokenMap::new();
+        for (idx, line) in io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() { continue }
+            // Name goes last, so it can contain commas
+            let fields: Vec<&str> = line.splitn(5, ',').collect();
+            let [id, username, created, hash, name] = fields[..] else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Wrong number of fields on line {} of API tokens file", idx + 1)));
+            };
+            let Ok(created) = created.parse() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Invalid creation time on line {} of API tokens file", idx + 1)));
+            };
+            tokens.insert(hash.into(), TokenInfo {
+                id: id.into(),
+                username: username.into(),
+                name: name.into(),
+                created,
+            });
+        }
+        Ok(tokens)
+    }
+
+    fn write_csv(tokens: &TokenMap, file: impl io::Write) -> io::Result<()> {
+        let mut entries: Vec<_> = tokens.iter().collect();
+        entries.sort_by_key(|(_, info)| (info.created, &info.id));
+        let mut fid = io::BufWriter::new(file);
+        for (hash, info) in entries {
+            writeln!(fid, "{},{},{},{hash},{}", info.id, info.username, info.created, info.name)?;
+        }
+        fid.flush()
+    }
+
+    /// Apply `change` to a copy of the tokens, write the result to the tokens file,
+    /// then swap it in. The in-memory database is left untouched if anything fails.
+    fn update<T>(&self, change: impl FnOnce(&mut TokenMap) -> io::Result<T>) -> io::Result<T> {
+        let mut tokens = self.tokens.write().unwrap();
+        let mut new_tokens = tokens.clone();
+        let out = change(&mut new_tokens)?;
+        match &self.file {
+            Some(fname) => write_atomic(fname, |fid| {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    fid.set_permissions(fs::Permissions::from_mode(0o600))?;
+                }
+                Self::write_csv(&new_tokens, fid)
+            })?,
+            None => log::warn!("No API tokens file to save changes to. Changes will be lost on restart."),
+        }
+        *tokens = new_tokens;
+        Ok(out)
+    }
+
+    /// Create a new token for `username`. Returns the token's details and the token itself,
+    /// which can't be retrieved again later.
+    pub fn create(&self, username: &str, name: &str) -> io::Result<(TokenInfo, String)> {
+        if name.contains(|c: char| c.is_control()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Token name must be a single line"))
+        }
+        let token = format!("{TOKEN_PREFIX}{}", random_hex(32));
+        let info = TokenInfo {
+            id: random_hex(8),
+            username: username.into(),
+            name: name.into(),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        };
+        self.update(|tokens| {
+            tokens.insert(hash_token(&token), info.clone());
+            Ok(())
+        })?;
+        log::info!("Created API token {} for user \"{username}\"", info.id);
+        Ok((info, token))
+    }
+
+    /// Get the user a token belongs to, if the token is valid
+    pub fn validate(&self, token: &str) -> Option<String> {
+        if !token.starts_with(TOKEN_PREFIX) { return None }
+        self.tokens.read().unwrap()
+            .get(&hash_token(token))
+            .map(|info| info.username.clone())
+    }
+
+    /// List tokens, oldest first. Only includes tokens belonging to `username` if specified.
+    pub fn list(&self, username: Option<&str>) -> Vec<TokenInfo> {
+        let mut out: Vec<TokenInfo> = self.tokens.read().unwrap()
+            .values()
+            .filter(|info| username.map_or(true, |u| info.username == u))
+            .cloned()
+            .collect();
+        out.sort_by_key(|info| (info.created, info.id.clone()));
+        out
+    }
+
+    /// Revoke the token with the specified id. If `username` is specified, only
+    /// tokens belonging to that user can be revoked.
+    pub fn revoke(&self, id: &str, username: Option<&str>) -> io::Result<TokenInfo> {
+        let info = self.update(|tokens| {
+            let Some(hash) = tokens.iter()
+                .find(|(_, info)| info.id == id && username.map_or(true, |u| info.username == u))
+                .map(|(hash, _)| hash.clone())
+            else {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown API token {id}")))
+            };
+            Ok(tokens.remove(&hash).unwrap())
+        })?;
+        log::info!("Revoked API token {id} of user \"{}\"", info.username);
+        Ok(info)
+    }
+
+    /// Revoke all tokens belonging to `username`. Returns the number of tokens revoked.
+    pub fn revoke_user(&self, username: &str) -> io::Result<usize> {
+        if !self.tokens.read().unwrap().values().any(|info| info.username == username) {
+            return Ok(0)
+        }
+        let count = self.update(|tokens| {
+            let before = tokens.len();
+            tokens.retain(|_, info| info.username != username);
+            Ok(before - tokens.len())
+        })?;
+        log::info!("Revoked {count} API token(s) of user \"{username}\"");
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let db = TokenDB::default();
+        let (info, token) = db.create("Foo", "notebook, laptop").unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(db.validate(&token).as_deref(), Some("Foo"));
+        assert_eq!(db.validate(&format!("{token}0")), None);
+
+        let mut csv = Vec::new();
+        TokenDB::write_csv(&db.tokens.read().unwrap(), &mut csv).unwrap();
+        assert!(!String::from_utf8_lossy(&csv).contains(&token));
+        assert_eq!(TokenDB::parse_csv(csv.as_slice()).unwrap(), *db.tokens.read().unwrap());
+
+        assert!(db.revoke(&info.id, Some("Bar")).is_err());
+        db.create("Foo", "script").unwrap();
+        assert_eq!(db.list(Some("Foo")).len(), 2);
+        db.revoke(&info.id, Some("Foo")).unwrap();
+        assert_eq!(db.validate(&token), None);
+        assert_eq!(db.revoke_user("Foo").unwrap(), 1);
+        assert!(db.list(None).is_empty());
+    }
+}
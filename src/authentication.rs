@@ -1,27 +1,40 @@
+use actix_identity::Identity;
+use actix_web::{dev::Payload, http::header::{self, HeaderValue}, FromRequest, HttpRequest};
 use argon2::{
-    password_hash::{PasswordHash, PasswordVerifier},
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
     Argon2,
 };
+use futures::future::LocalBoxFuture;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    fmt::Display,
     fs,
-    io::{self, BufRead},
-    sync::OnceLock,
-    path::Path,
+    io::{self, BufRead, Write},
+    str::FromStr,
+    sync::{Mutex, OnceLock, RwLock},
+    path::{Path, PathBuf},
 };
 
+use crate::{api_tokens::TOKEN_DB, guests::GUESTS, user_sessions::SESSIONS};
+
 /// Database of usernames and associated password hashes
 pub static USER_DB: OnceLock<UserDB> = OnceLock::new();
 
-/// Login token (username) to send back to client
+/// Separates the identity provider's prefix from the rest of an external user's username.
+/// Users with it in their name can't log in with a password, so local accounts can never
+/// take over an external user's sessions and jobs.
+pub const EXTERNAL_SEPARATOR: char = ':';
+
+/// Login token (username and role) to send back to client
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoginToken {
     token: String,
+    role: Role,
 }
-impl From<String> for LoginToken {
-    fn from(token: String) -> Self {
-        Self { token }
+impl From<SessionUser> for LoginToken {
+    fn from(user: SessionUser) -> Self {
+        Self { token: user.username, role: user.role }
     }
 }
 
@@ -32,68 +45,578 @@ pub struct UserCredentials {
     pub password: String,
 }
 
-impl UserCredentials {
-    pub fn get_id(&self) -> String {
-        self.username.clone()
+/// Body of a login request. Either user credentials, or `{"guest": true}`
+/// to log in as a guest when guest mode is enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum LoginRequest {
+    Credentials(UserCredentials),
+    Guest { guest: bool },
+}
+
+/// Account type of a user, which determines the endpoints
+/// and web socket type they have access to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Full access, including user management
+    Admin,
+    /// Teaching staff. Can do everything a student can, plus class management features.
+    Instructor,
+    /// Regular user who submits jobs through the web interface
+    Student,
+    /// Temporary visitor in guest mode, limited to pre-approved configurations.
+    /// Never stored in the users file.
+    Guest,
+    /// Worker node which runs jobs. Cannot access any of the user-facing endpoints.
+    Worker,
+}
+
+impl Role {
+    /// Check whether this role has at least the permissions of `required`.
+    /// User roles are ordered Admin > Instructor > Student > Guest, while workers
+    /// are kept entirely separate.
+    pub fn permits(&self, required: Role) -> bool {
+        match (self, required) {
+            (Self::Worker, Self::Worker) => true,
+            (Self::Worker, _) | (_, Self::Worker) => false,
+            (Self::Admin, _) => true,
+            (Self::Instructor, Self::Instructor | Self::Student | Self::Guest) => true,
+            (Self::Student, Self::Student | Self::Guest) => true,
+            (Self::Guest, Self::Guest) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Admin      => "admin",
+            Self::Instructor => "instructor",
+            Self::Student    => "student",
+            Self::Guest      => "guest",
+            Self::Worker     => "worker",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin"      => Ok(Self::Admin),
+            "instructor" => Ok(Self::Instructor),
+            "student"    => Ok(Self::Student),
+            "guest"      => Ok(Self::Guest),
+            "worker"     => Ok(Self::Worker),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown role \"{other}\""))),
+        }
+    }
+}
+
+/// Details of a logged in user, stored (as JSON) as the id of their `Identity`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionUser {
+    pub username: String,
+    pub role: Role,
+    /// Logged in through an external identity provider (OIDC) rather than the users file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub external: bool,
+    /// Id of the user's session in `SESSIONS`, if logged in through an `Identity`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+}
+
+impl SessionUser {
+    /// Serialize to a string to be used as the id of an `Identity`
+    pub fn to_id(&self) -> String {
+        serde_json::to_string(self).expect("SessionUser should always serialize")
+    }
+
+    /// Parse the id of an `Identity` created with `SessionUser::to_id()`
+    pub fn from_id(id: &str) -> Option<Self> {
+        serde_json::from_str(id).ok()
+    }
+
+    /// Extract the session user from a logged in `Identity`
+    pub fn from_identity(user: &Identity) -> Option<Self> {
+        user.id().ok().and_then(|id| Self::from_id(&id))
+    }
+
+    /// Check that this user has at least the permissions of `required`,
+    /// returning a `403 Forbidden` error if not.
+    pub fn require(&self, required: Role) -> Result<(), actix_web::Error> {
+        if self.role.permits(required) {
+            Ok(())
+        } else {
+            log::warn!("User \"{}\" ({}) denied access to {required} endpoint", self.username, self.role);
+            Err(actix_web::error::ErrorForbidden("Insufficient permissions."))
+        }
     }
 }
 
-/// Database of usernames and associated password hashes.
+impl FromRequest for SessionUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    /// Extract the user from an API token in the `Authorization: Bearer` header if there is one,
+    /// or otherwise from their `Identity`. Checks that their account and session are still active
+    /// and updates their role in case it has changed.
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if let Some(auth) = req.headers().get(header::AUTHORIZATION) {
+            let user = bearer_user(auth);
+            return Box::pin(async move { user });
+        }
+        let identity = Identity::from_request(req, payload);
+        Box::pin(async move {
+            let identity = identity.await?;
+            let Some(mut user) = Self::from_identity(&identity) else {
+                log::error!("Failed to get user details from user identity.");
+                return Err(actix_web::error::ErrorUnauthorized("User session corrupted."))
+            };
+            // Guests only exist in memory, and never in the users database
+            if user.role == Role::Guest {
+                if GUESTS.get().is_some_and(|guests| guests.is_active(&user.username)) {
+                    return Ok(user)
+                }
+                log::info!("Rejected expired guest session of \"{}\"", user.username);
+                identity.logout();
+                return Err(actix_web::error::ErrorUnauthorized("Guest session has expired."))
+            }
+            let role = if user.external {
+                USER_DB.get().and_then(|db| db.external_role(&user.username, user.role))
+            } else {
+                USER_DB.get().and_then(|db| db.active_role(&user.username))
+            };
+            let Some(role) = role else {
+                log::warn!("Rejected session for inactive user \"{}\"", user.username);
+                identity.logout();
+                return Err(actix_web::error::ErrorUnauthorized("User account is no longer active."))
+            };
+            let active = user.session.as_ref().is_some_and(|id| {
+                SESSIONS.get().is_some_and(|sessions| sessions.touch(&user.username, id))
+            });
+            if !active {
+                log::info!("Rejected revoked or expired session of \"{}\"", user.username);
+                identity.logout();
+                return Err(actix_web::error::ErrorUnauthorized("Session has been logged out."))
+            }
+            user.role = role;
+            Ok(user)
+        })
+    }
+}
+
+/// Get the user an API token in an `Authorization: Bearer` header belongs to
+fn bearer_user(auth: &HeaderValue) -> Result<SessionUser, actix_web::Error> {
+    let Some(token) = auth.to_str().ok().and_then(|auth| auth.strip_prefix("Bearer ")) else {
+        return Err(actix_web::error::ErrorUnauthorized("Expected a Bearer token."))
+    };
+    let Some(username) = TOKEN_DB.get().and_then(|db| db.validate(token.trim())) else {
+        log::warn!("Rejected invalid API token");
+        return Err(actix_web::error::ErrorUnauthorized("Invalid API token."))
+    };
+    let Some(role) = USER_DB.get().and_then(|db| db.active_role(&username)) else {
+        log::warn!("Rejected API token for inactive user \"{username}\"");
+        return Err(actix_web::error::ErrorUnauthorized("User account is no longer active."))
+    };
+    Ok(SessionUser { username, role, external: false, session: None })
+}
+
+/// Stored details for a single user
+#[derive(Debug, Clone)]
+pub struct UserEntry {
+    pub hash: String,
+    pub role: Role,
+    /// Locked users can't log in. Stored as a '!' prefix on the password hash.
+    pub locked: bool,
+}
+
+/// Summary of a user's details (without their password hash) for listing users
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserSummary {
+    pub username: String,
+    pub role: Role,
+    pub locked: bool,
+}
+
+type UserMap = HashMap<String, UserEntry>;
+
+/// Database of usernames and associated password hashes and roles.
+/// The set of users can be modified or reloaded from file at runtime.
 #[derive(Debug, Default)]
-pub struct UserDB(HashMap<String, String>);
+pub struct UserDB {
+    users: RwLock<UserMap>,
+    /// File the database was loaded from, and which changes are written back to.
+    file: Option<PathBuf>,
+    /// Held while changing the users, so that `users` is only locked to swap in the result
+    writer: Mutex<()>,
+}
 
 impl UserDB {
     /// Load the users database from the specified file.
-    /// File should contain lines of username,argon2_password_hash (no space between!)
+    /// File should contain lines of username,role,argon2_password_hash (no spaces between!)
     pub fn load(fname: impl AsRef<Path>) -> std::io::Result<Self> {
         log::debug!("Reading users from {}", fname.as_ref().to_string_lossy());
-        let out = UserDB::from_csv(fs::File::open(fname)?)?;
+        let mut out = UserDB::from_csv(fs::File::open(&fname)?)?;
+        out.file = Some(fname.as_ref().to_owned());
         log::debug!("Done reading users");
         Ok(out)
     }
 
-    /// Read a comma separated list of username,argon2_password_hash (no space between!)
-    pub fn from_csv(file: fs::File) -> io::Result<Self> {
+    /// Read a comma separated list of username,role,argon2_password_hash (no spaces between!)
+    ///
+    /// Lines in the old username,argon2_password_hash format are accepted for compatibility
+    /// and given the student role. Workers must have the worker role set explicitly.
+    /// A '!' before the password hash marks the user as locked.
+    pub fn from_csv(file: impl io::Read) -> io::Result<Self> {
+        Ok(Self { users: RwLock::new(Self::parse_csv(file)?), ..Default::default() })
+    }
+
+    fn parse_csv(file: impl io::Read) -> io::Result<UserMap> {
         let mut fid = io::BufReader::new(file);
         let mut line = "".to_string();
         let mut idx = 0;
-        let mut db = Self::default();
+        let mut users = HashMap::new();
         while {
             line.clear();
             idx += 1;
             fid.read_line(&mut line)? > 0
         } {
             // line contains trailing \n, so ignore that.
-            let Some((username, hash)) = line.trim_end_matches('\n').split_once(",") else {
+            let line = line.trim_end_matches('\n');
+            if line.is_empty() { continue }
+            let Some((username, rest)) = line.split_once(",") else {
                 return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Missing ',' on line {idx}")));
             };
+            // Password hashes always start with '$' (or '!$' if locked), so anything else is a role.
+            let (role, hash) = if rest.starts_with('$') || rest.starts_with("!$") {
+                let role = Role::Student;
+                log::warn!("User \"{username}\" on line {idx} has no role. Assuming {role}. \
+                    Regenerate the users file with pytf-hash-users to remove this warning.");
+                (role, rest)
+            } else {
+                let Some((role, hash)) = rest.split_once(",") else {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Missing password hash on line {idx}")));
+                };
+                (role.parse()?, hash)
+            };
+            if role == Role::Guest {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Guest user \"{username}\" on line {idx}. Guests can't be stored in the users file.")));
+            }
+            let (hash, locked) = match hash.strip_prefix('!') {
+                Some(hash) => (hash, true),
+                None => (hash, false),
+            };
             if let Err(e) = PasswordHash::new(&hash) {
                 log::error!("Invalid password hash for user \"{username}\". Failed to parse with error: {e}");
                 continue
             }
-            if db.0.insert(username.into(), hash.into()).is_some() {
+            if users.insert(username.into(), UserEntry { hash: hash.into(), role, locked }).is_some() {
                 log::warn!("User already exists! ({})", username);
             }
         }
-        Ok(db)
+        Ok(users)
+    }
+
+    /// Write the database in the same format read by `from_csv()`, sorted by username.
+    pub fn to_csv(&self, file: impl io::Write) -> io::Result<()> {
+        Self::write_csv(&self.users.read().unwrap(), file)
+    }
+
+    fn write_csv(users: &UserMap, file: impl io::Write) -> io::Result<()> {
+        let mut names: Vec<&String> = users.keys().collect();
+        names.sort();
+        let mut fid = io::BufWriter::new(file);
+        for name in names {
+            let entry = &users[name];
+            let lock = if entry.locked { "!" } else { "" };
+            writeln!(fid, "{name},{},{lock}{}", entry.role, entry.hash)?;
+        }
+        fid.flush()
+    }
+
+    /// Write `users` to the file the database was loaded from.
+    fn save(&self, users: &UserMap) -> io::Result<()> {
+        let Some(fname) = &self.file else {
+            log::warn!("No users file to save changes to. Changes will be lost on restart.");
+            return Ok(())
+        };
+        write_atomic(fname, |fid| Self::write_csv(users, fid))?;
+        log::debug!("Saved users to {}", fname.to_string_lossy());
+        Ok(())
+    }
+
+    /// Apply `change` to a copy of the users, write the result to the users file,
+    /// then swap it in. The in-memory database is left untouched if anything fails.
+    /// Lookups aren't blocked while the file is written.
+    fn update<T>(&self, change: impl FnOnce(&mut UserMap) -> io::Result<T>) -> io::Result<T> {
+        let _writer = self.writer.lock().unwrap();
+        let mut new_users = self.users.read().unwrap().clone();
+        let out = change(&mut new_users)?;
+        self.save(&new_users)?;
+        *self.users.write().unwrap() = new_users;
+        Ok(out)
+    }
+
+    /// Re-read the users file, replacing the in-memory database.
+    /// Returns the names of any users who were active before reloading, but no longer are.
+    pub fn reload(&self) -> io::Result<Vec<String>> {
+        let Some(fname) = &self.file else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No users file to reload from"))
+        };
+        log::info!("Reloading users from {}", fname.to_string_lossy());
+        let _writer = self.writer.lock().unwrap();
+        let new_users = Self::parse_csv(fs::File::open(fname)?)?;
+        let mut users = self.users.write().unwrap();
+        let deactivated = users.iter()
+            .filter(|(name, entry)| !entry.locked && new_users.get(*name).map_or(true, |e| e.locked))
+            .map(|(name, _)| name.clone())
+            .collect();
+        *users = new_users;
+        log::info!("Loaded {} users", users.len());
+        Ok(deactivated)
+    }
+
+    /// Get the role of a user if they exist and are not locked
+    pub fn active_role(&self, username: &str) -> Option<Role> {
+        self.users.read().unwrap()
+            .get(username)
+            .and_then(|entry| if entry.locked { None } else { Some(entry.role) })
+    }
+
+    /// Get the role of a user who logged in through an external identity provider with the
+    /// role `claimed`. An entry for the user in the users file lets admins disable them or
+    /// lower their role, but never raise it. Returns `None` if the user shouldn't be allowed in.
+    pub fn external_role(&self, username: &str, claimed: Role) -> Option<Role> {
+        match self.users.read().unwrap().get(username) {
+            Some(entry) if entry.locked || entry.role == Role::Worker => None,
+            Some(entry) if claimed.permits(entry.role) => Some(entry.role),
+            _ => Some(claimed),
+        }
+    }
+
+    /// List all users, sorted by username
+    pub fn list_users(&self) -> Vec<UserSummary> {
+        let mut out: Vec<UserSummary> = self.users.read().unwrap()
+            .iter()
+            .map(|(name, entry)| UserSummary { username: name.clone(), role: entry.role, locked: entry.locked })
+            .collect();
+        out.sort_by(|a, b| a.username.cmp(&b.username));
+        out
+    }
+
+    /// Add a new user with the specified password and role, and save the change to the users file.
+    pub fn add_user(&self, username: &str, password: &str, role: Role) -> io::Result<()> {
+        validate_username(username)?;
+        if role == Role::Guest {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Guests can't be stored in the users file"))
+        }
+        let hash = hash_password(password)?;
+        self.update(|users| {
+            if users.contains_key(username) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("User \"{username}\" already exists")))
+            }
+            users.insert(username.into(), UserEntry { hash, role, locked: false });
+            Ok(())
+        })?;
+        log::info!("Added {role} user \"{username}\"");
+        Ok(())
+    }
+
+    /// Remove a user, and save the change to the users file.
+    pub fn remove_user(&self, username: &str) -> io::Result<()> {
+        self.update(|users| {
+            users.remove(username).map(|_| ()).ok_or_else(|| unknown_user(username))
+        })?;
+        log::info!("Removed user \"{username}\"");
+        Ok(())
+    }
+
+    /// Set a new password for a user, and save the change to the users file.
+    pub fn reset_password(&self, username: &str, password: &str) -> io::Result<()> {
+        let hash = hash_password(password)?;
+        self.update(|users| {
+            let entry = users.get_mut(username).ok_or_else(|| unknown_user(username))?;
+            entry.hash = hash;
+            Ok(())
+        })?;
+        log::info!("Reset password for user \"{username}\"");
+        Ok(())
+    }
+
+    /// Lock or unlock a user, and save the change to the users file. Locked users can't log in.
+    /// Returns the role of the user.
+    pub fn set_locked(&self, username: &str, locked: bool) -> io::Result<Role> {
+        let role = self.update(|users| {
+            let entry = users.get_mut(username).ok_or_else(|| unknown_user(username))?;
+            entry.locked = locked;
+            Ok(entry.role)
+        })?;
+        log::info!("{} user \"{username}\"", if locked { "Disabled" } else { "Enabled" });
+        Ok(role)
     }
 
-    /// Check the provided credentials against the database and return true if a match is found
-    /// and the password is correct.
-    pub fn validate_user(&self, user: &UserCredentials) -> bool {
-        let Some(hash) = self.0.get(&user.username) else {
+    /// Lock a worker's key so it can no longer log in, and save the change to the users file.
+    /// Returns an error if `name` is not a worker.
+    pub fn revoke_worker(&self, name: &str) -> io::Result<()> {
+        self.update(|users| {
+            let entry = users.get_mut(name).ok_or_else(|| unknown_user(name))?;
+            if entry.role != Role::Worker {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("User \"{name}\" is not a worker")))
+            }
+            entry.locked = true;
+            Ok(())
+        })?;
+        log::info!("Revoked key for worker \"{name}\"");
+        Ok(())
+    }
+
+    /// Check the provided credentials against the database and return the user's details if a
+    /// match is found and the password is correct.
+    pub fn validate_user(&self, user: &UserCredentials) -> Option<SessionUser> {
+        if user.username.contains(EXTERNAL_SEPARATOR) {
+            log::info!("Received password login request for external user \"{}\"", user.username);
+            return None
+        }
+        let Some(entry) = self.users.read().unwrap().get(&user.username).cloned() else {
             log::info!("Received login request for unknown user \"{}\"", user.username);
-            return false
+            return None
         };
-        let parsed_hash = match PasswordHash::new(&hash) {
+        if entry.locked {
+            log::info!("Received login request for locked user \"{}\"", user.username);
+            return None
+        }
+        let parsed_hash = match PasswordHash::new(&entry.hash) {
             Ok(h) => h,
             Err(e) => {
                 log::warn!("Failed to parse password hash for user \"{}\". This should never happen! Error was: {e}", user.username);
-                return false
+                return None
             }
         };
         Argon2::default()
             .verify_password(user.password.as_str().as_bytes(), &parsed_hash)
-            .is_ok()
+            .ok()
+            .map(|_| SessionUser { username: user.username.clone(), role: entry.role, external: false, session: None })
+    }
+}
+
+/// Write to `fname` via a temporary file which is then moved into place,
+/// so the file is never left partially written.
+pub(crate) fn write_atomic(fname: &Path, write: impl FnOnce(&fs::File) -> io::Result<()>) -> io::Result<()> {
+    let mut tmp_name = fname.to_owned().into_os_string();
+    tmp_name.push(".tmp");
+    let tmp_name = PathBuf::from(tmp_name);
+    let fid = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_name)?;
+    write(&fid)?;
+    fid.sync_all()?;
+    fs::rename(&tmp_name, fname)
+}
+
+fn unknown_user(username: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("Unknown user \"{username}\""))
+}
+
+/// Check that a username can be stored in the users file
+pub fn validate_username(username: &str) -> io::Result<()> {
+    if username.is_empty() || username.contains(|c: char| c == ',' || c.is_whitespace() || c.is_control()) {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Username must not be empty or contain commas or whitespace"))
+    } else { Ok(()) }
+}
+
+/// Hash a password with Argon2 using a random salt
+pub fn hash_password(password: &str) -> io::Result<String> {
+    if password.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Password must not be empty"))
+    }
+    let salt = SaltString::generate(OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Error hashing password: {e}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_role_permits() {
+        assert!(Role::Admin.permits(Role::Instructor));
+        assert!(Role::Admin.permits(Role::Student));
+        assert!(Role::Instructor.permits(Role::Student));
+        assert!(!Role::Instructor.permits(Role::Admin));
+        assert!(!Role::Student.permits(Role::Instructor));
+        assert!(!Role::Worker.permits(Role::Student));
+        assert!(!Role::Admin.permits(Role::Worker));
+        assert!(Role::Worker.permits(Role::Worker));
+        assert!(Role::Student.permits(Role::Guest));
+        assert!(!Role::Guest.permits(Role::Student));
+        assert!(!Role::Worker.permits(Role::Guest));
+    }
+
+    #[test]
+    fn test_users_csv_roles() {
+        let hash = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHQ$S0+LqOgB5ZqDUjUGLlkbCi6JgZpAcMr63r8I9XcTNZQ";
+        let csv = format!("Foo,admin,{hash}\nBar,{hash}\nworker,{hash}\n\nBaz,worker,!{hash}\nQux,worker,{hash}\n");
+        let db = UserDB::from_csv(csv.as_bytes()).unwrap();
+        assert_eq!(db.active_role("Foo"), Some(Role::Admin));
+        assert_eq!(db.active_role("Bar"), Some(Role::Student));
+        // Legacy rows never get the worker role from their name
+        assert_eq!(db.active_role("worker"), Some(Role::Student));
+        assert_eq!(db.active_role("Qux"), Some(Role::Worker));
+        assert_eq!(db.active_role("Baz"), None);
+        assert!(db.users.read().unwrap()["Baz"].locked);
+        assert!(UserDB::from_csv(format!("Foo,wizard,{hash}").as_bytes()).is_err());
+
+        // Round trip, including locked user
+        let mut out = Vec::new();
+        db.to_csv(&mut out).unwrap();
+        let db2 = UserDB::from_csv(out.as_slice()).unwrap();
+        assert_eq!(db2.active_role("Foo"), Some(Role::Admin));
+        assert_eq!(db2.active_role("Baz"), None);
+        db.revoke_worker("Qux").unwrap();
+        assert_eq!(db.active_role("Qux"), None);
+        assert!(db.revoke_worker("worker").is_err());
+        assert!(db.revoke_worker("Foo").is_err());
+    }
+
+    #[test]
+    fn test_user_management() {
+        let db = UserDB::default();
+        db.add_user("Foo", "asdf", Role::Instructor).unwrap();
+        assert_eq!(db.add_user("Foo", "fdsa", Role::Student).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(db.add_user("Bar,admin", "asdf", Role::Student).is_err());
+        let creds = UserCredentials { username: "Foo".into(), password: "asdf".into() };
+        assert_eq!(db.validate_user(&creds).map(|u| u.role), Some(Role::Instructor));
+
+        assert_eq!(db.external_role("Foo", Role::Admin), Some(Role::Instructor));
+        assert_eq!(db.external_role("Foo", Role::Student), Some(Role::Student));
+        assert_eq!(db.external_role("Baz", Role::Student), Some(Role::Student));
+
+        // Entries for external users only restrict them, and can't be used to log in
+        db.add_user("idp:Foo", "asdf", Role::Student).unwrap();
+        assert_eq!(db.external_role("idp:Foo", Role::Instructor), Some(Role::Student));
+        assert!(db.validate_user(&UserCredentials { username: "idp:Foo".into(), password: "asdf".into() }).is_none());
+        db.remove_user("idp:Foo").unwrap();
+
+        db.set_locked("Foo", true).unwrap();
+        assert!(db.validate_user(&creds).is_none());
+        assert_eq!(db.external_role("Foo", Role::Student), None);
+        db.set_locked("Foo", false).unwrap();
+        db.reset_password("Foo", "qwerty").unwrap();
+        assert!(db.validate_user(&creds).is_none());
+        assert!(db.validate_user(&UserCredentials { username: "Foo".into(), password: "qwerty".into() }).is_some());
+
+        db.remove_user("Foo").unwrap();
+        assert!(db.list_users().is_empty());
+        assert_eq!(db.remove_user("Foo").unwrap_err().kind(), io::ErrorKind::NotFound);
     }
 }
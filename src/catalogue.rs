@@ -0,0 +1,402 @@
+use std::{path::{Path, PathBuf}, sync::Mutex};
+
+use actix::Addr;
+use actix_web::{delete, get, post, web, HttpResponse};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use pytf_web::{
+    authentication::{Role, SessionUser},
+    guests::GUESTS,
+    input_config::ConfigSettings,
+    pdb2xyz::pdb2xyz,
+    pytf_config::{MixtureComponentDetailed, MoleculeResources, AVAILABLE_MOLECULES, RESOURCES_DIR},
+    topology::{check_pdb_residue, check_res_name, check_topology},
+    Reloadable,
+};
+
+use crate::{
+    job_queue::{CatalogueChanged, JobServer, MoleculeInUse},
+    server_config::config,
+};
+
+/// Held while the molecules are being changed, so that concurrent edits of the
+/// molecules file don't overwrite each other
+static EDIT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Result of reloading the molecules and input configuration
+#[derive(Debug, Serialize)]
+pub struct ReloadSummary {
+    pub molecules: usize,
+    /// Number of settings in the input configuration
+    pub settings: usize,
+    /// Connected clients told to fetch the molecules and settings again
+    pub clients_notified: usize,
+}
+
+/// Load and check the molecules file (parsing the .pdb file of every molecule) and the
+/// input configuration, without replacing the current ones
+pub fn load_resources(molecules_file: &Path, input_config_file: &Path) -> anyhow::Result<(MoleculeResources, ConfigSettings)> {
+    let molecules = MoleculeResources::load(molecules_file)
+        .map_err(|e| anyhow!("{}: {e}", molecules_file.to_string_lossy()))?;
+    if molecules.is_empty() {
+        return Err(anyhow!("{}: No molecules listed", molecules_file.to_string_lossy()))
+    }
+    let input_config = ConfigSettings::open(input_config_file)
+        .map_err(|e| anyhow!("{}: {e}", input_config_file.to_string_lossy()))?;
+    Ok((molecules, input_config))
+}
+
+/// Reload the molecules and input configuration from disk and notify connected clients.
+/// Nothing is replaced unless both files are valid.
+pub async fn reload(srv: &Addr<JobServer>, input_config: &Reloadable<ConfigSettings>) -> anyhow::Result<ReloadSummary> {
+    let paths = &config().paths;
+    let (molecules_file, input_config_file) = (paths.molecules_file(), paths.input_config_file());
+    let (n_molecules, new_config) = web::block(move || {
+        let _lock = EDIT_LOCK.lock().unwrap();
+        let (molecules, new_config) = load_resources(&molecules_file, &input_config_file)?;
+        let n_molecules = molecules.len();
+        AVAILABLE_MOLECULES.get().unwrap().replace(molecules);
+        anyhow::Ok((n_molecules, new_config))
+    }).await??;
+
+    let settings = new_config.settings.len();
+    // Names of the jobs guests may run depend on the input configuration
+    if let Some(guests) = GUESTS.get() {
+        guests.update_input_config(&new_config);
+    }
+    input_config.replace(new_config);
+
+    let clients_notified = srv.send(CatalogueChanged {}).await?;
+    log::info!("Reloaded {n_molecules} molecules and {settings} settings. Notified {clients_notified} client(s).");
+    Ok(ReloadSummary { molecules: n_molecules, settings, clients_notified })
+}
+
+/// Reload the molecules and input configuration
+#[post("/admin/reload")]
+async fn reload_resources(
+    user: SessionUser,
+    srv: web::Data<Addr<JobServer>>,
+    input_config: web::Data<Reloadable<ConfigSettings>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    Ok(match reload(&srv, &input_config).await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => {
+            log::error!("Failed to reload resources: {e}");
+            HttpResponse::UnprocessableEntity()
+                .body(format!("Failed to reload resources: {e}. Keeping the current molecules and settings."))
+        }
+    })
+}
+
+/// Request body for adding a molecule. The .pdb and .itp files are sent as text.
+#[derive(Debug, Deserialize)]
+pub struct MoleculeUpload {
+    pub res_name: String,
+    pub name: String,
+    pub formula: String,
+    pub smiles: String,
+    pub pdb: String,
+    pub itp: String,
+}
+
+/// Reasons a change to the molecules can fail
+#[derive(Debug)]
+pub enum EditError {
+    NotFound(String),
+    Conflict(String),
+    Invalid(String),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for EditError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Internal(e)
+    }
+}
+
+impl From<std::io::Error> for EditError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
+impl EditError {
+    fn response(self, action: &str, res_name: &str) -> HttpResponse {
+        match self {
+            Self::NotFound(msg) => HttpResponse::NotFound().body(msg),
+            Self::Conflict(msg) => HttpResponse::Conflict().body(msg),
+            Self::Invalid(msg) => HttpResponse::UnprocessableEntity().body(msg),
+            Self::Internal(e) => {
+                log::error!("Failed to {action} molecule {res_name}: {e}");
+                HttpResponse::InternalServerError().body(format!("Failed to {action} molecule."))
+            }
+        }
+    }
+}
+
+/// Where the molecules are stored
+pub struct MoleculePaths {
+    /// The molecules file
+    pub file: PathBuf,
+    /// Directory containing the .pdb and .itp file of each molecule
+    pub dir: PathBuf,
+}
+
+impl MoleculePaths {
+    pub fn from_config() -> Self {
+        Self {
+            file: config().paths.molecules_file(),
+            dir: RESOURCES_DIR.get().unwrap().join("molecules"),
+        }
+    }
+
+    fn molecule_file(&self, res_name: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{res_name}.{extension}"))
+    }
+
+    /// Apply `edit` to a copy of the current molecules, then save them to the molecules file
+    /// and make them available. Nothing changes if `edit` or saving fails.
+    fn edit(&self, edit: impl FnOnce(&mut MoleculeResources) -> Result<(), EditError>) -> Result<(), EditError> {
+        let _lock = EDIT_LOCK.lock().unwrap();
+        let available = AVAILABLE_MOLECULES.get().unwrap();
+        let mut molecules = (*available.get()).clone();
+        edit(&mut molecules)?;
+        molecules.save(&self.file)?;
+        available.replace(molecules);
+        Ok(())
+    }
+}
+
+/// Check an uploaded molecule, then store its files in the molecules directory and add it
+/// to the molecules file. The .pdb file must parse with `pdb2xyz`, and the .itp file must
+/// describe the same molecule (see `check_topology`).
+pub fn add_molecule(paths: &MoleculePaths, upload: MoleculeUpload) -> Result<(), EditError> {
+    let MoleculeUpload { res_name, name, formula, smiles, pdb, itp } = upload;
+    check_res_name(&res_name).map_err(|e| EditError::Invalid(e.to_string()))?;
+    for (field, value) in [("name", &name), ("formula", &formula), ("smiles", &smiles)] {
+        if value.trim().is_empty() || value.contains('\n') {
+            return Err(EditError::Invalid(format!("The molecule's {field} must be a single non-empty line")))
+        }
+    }
+    let (pdb_file, itp_file) = (paths.molecule_file(&res_name, "pdb"), paths.molecule_file(&res_name, "itp"));
+
+    // pdb2xyz reads from a file, so check the uploaded files before moving them into place
+    let pdb_tmp = pdb_file.with_extension("pdb.upload");
+    let itp_tmp = itp_file.with_extension("itp.upload");
+
+    let mut writing = false;
+    let result = paths.edit(|molecules| {
+        if molecules.get(&res_name).is_some() || pdb_file.exists() || itp_file.exists() {
+            return Err(EditError::Conflict(format!("Molecule {res_name} already exists.")))
+        }
+        writing = true;
+        std::fs::write(&pdb_tmp, &pdb)?;
+        std::fs::write(&itp_tmp, &itp)?;
+        let atoms = pdb2xyz(&pdb_tmp).map_err(|e| EditError::Invalid(format!("Invalid .pdb file: {e}")))?;
+        check_pdb_residue(&pdb, &res_name)
+            .and_then(|_| check_topology(&itp, &res_name, atoms.len()))
+            .map_err(|e| EditError::Invalid(e.to_string()))?;
+        std::fs::rename(&pdb_tmp, &pdb_file)?;
+        std::fs::rename(&itp_tmp, &itp_file)?;
+        molecules.add(MixtureComponentDetailed::new(res_name.clone(), name, formula, smiles, atoms))?;
+        Ok(())
+    });
+    // Don't leave files behind for a molecule which wasn't added. None of them existed
+    // before writing started, so they're all from this upload.
+    if result.is_err() && writing {
+        for file in [pdb_tmp, itp_tmp, pdb_file, itp_file] {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+    result
+}
+
+/// Remove a molecule from the molecules file and delete its .pdb and .itp files
+pub fn remove_molecule(paths: &MoleculePaths, res_name: &str) -> Result<(), EditError> {
+    paths.edit(|molecules| {
+        if molecules.remove(res_name).is_none() {
+            return Err(EditError::NotFound(format!("No molecule {res_name}.")))
+        }
+        if molecules.is_empty() {
+            return Err(EditError::Conflict("Can't remove the last molecule.".into()))
+        }
+        Ok(())
+    })?;
+    for file in [paths.molecule_file(res_name, "pdb"), paths.molecule_file(res_name, "itp")] {
+        if let Err(e) = std::fs::remove_file(&file) {
+            log::warn!("Failed to remove {}: {e}", file.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+/// Hide a molecule from users, or show it again
+pub fn set_hidden(paths: &MoleculePaths, res_name: &str, hidden: bool) -> Result<(), EditError> {
+    paths.edit(|molecules| {
+        if !molecules.set_hidden(res_name, hidden) {
+            return Err(EditError::NotFound(format!("No molecule {res_name}.")))
+        }
+        if hidden && molecules.visible().next().is_none() {
+            return Err(EditError::Conflict("Can't hide the last visible molecule.".into()))
+        }
+        Ok(())
+    })
+}
+
+/// List every molecule, including hidden ones
+#[get("/molecules/all")]
+async fn list_molecules(user: SessionUser) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Instructor)?;
+    Ok(HttpResponse::Ok().json(&*AVAILABLE_MOLECULES.get().unwrap().get()))
+}
+
+#[post("/molecules")]
+async fn upload_molecule(
+    user: SessionUser,
+    upload: web::Json<MoleculeUpload>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Instructor)?;
+    let upload = upload.into_inner();
+    let res_name = upload.res_name.clone();
+    if let Err(e) = web::block(move || add_molecule(&MoleculePaths::from_config(), upload)).await? {
+        return Ok(e.response("add", &res_name))
+    }
+    srv.do_send(CatalogueChanged {});
+    log::info!("Molecule {res_name} added by {}", user.username);
+    Ok(HttpResponse::Created().finish())
+}
+
+#[post("/molecules/{res_name}/hide")]
+async fn hide_molecule(
+    user: SessionUser,
+    res_name: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Instructor)?;
+    let res_name = res_name.into_inner();
+    let name = res_name.clone();
+    if let Err(e) = web::block(move || set_hidden(&MoleculePaths::from_config(), &name, true)).await? {
+        return Ok(e.response("hide", &res_name))
+    }
+    srv.do_send(CatalogueChanged {});
+    log::info!("Molecule {res_name} hidden by {}", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/molecules/{res_name}/show")]
+async fn show_molecule(
+    user: SessionUser,
+    res_name: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Instructor)?;
+    let res_name = res_name.into_inner();
+    let name = res_name.clone();
+    if let Err(e) = web::block(move || set_hidden(&MoleculePaths::from_config(), &name, false)).await? {
+        return Ok(e.response("show", &res_name))
+    }
+    srv.do_send(CatalogueChanged {});
+    log::info!("Molecule {res_name} shown by {}", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/molecules/{res_name}")]
+async fn delete_molecule(
+    user: SessionUser,
+    res_name: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Instructor)?;
+    let res_name = res_name.into_inner();
+    // Queued and archived jobs need the molecule's files to run or be resumed
+    let in_use = srv.send(MoleculeInUse { res_name: res_name.clone() }).await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if in_use {
+        return Ok(EditError::Conflict(format!("Molecule {res_name} is used by jobs which may still be resumed. Hide it instead."))
+            .response("remove", &res_name))
+    }
+    let name = res_name.clone();
+    if let Err(e) = web::block(move || remove_molecule(&MoleculePaths::from_config(), &name)).await? {
+        return Ok(e.response("remove", &res_name))
+    }
+    srv.do_send(CatalogueChanged {});
+    log::info!("Molecule {res_name} removed by {}", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Register the resource reload and molecule management endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(reload_resources)
+        .service(list_molecules)
+        .service(upload_molecule)
+        .service(hide_molecule)
+        .service(show_molecule)
+        .service(delete_molecule);
+}
+
+#[cfg(test)]
+mod test {
+    use actix::Actor;
+
+    use super::*;
+    use crate::job_queue::test_archive_dir;
+    use pytf_web::pytf_config::RESOURCES_DIR;
+
+    #[actix_web::test]
+    async fn test_reload() {
+        let _ = RESOURCES_DIR.set("resources".into());
+        test_archive_dir();
+        let input_file = Path::new("resources/input_config.yml");
+
+        // A molecule without a .pdb file is rejected
+        let bad_molecules = std::env::temp_dir().join(format!("pytf-molecules-{}.json", std::process::id()));
+        std::fs::write(&bad_molecules,
+            r#"{"molecules": [{"res_name": "NOPE", "name": "Missing", "formula": "X", "smiles": "X"}]}"#).unwrap();
+        assert!(load_resources(&bad_molecules, input_file).is_err());
+        std::fs::remove_file(&bad_molecules).unwrap();
+
+        let (molecules, _) = load_resources(Path::new("resources/molecules.json"), input_file).unwrap();
+        AVAILABLE_MOLECULES.get_or_init(|| Reloadable::new(molecules.clone()));
+        let input_config = Reloadable::new(ConfigSettings::default());
+        let srv = JobServer::new().start();
+        let summary = reload(&srv, &input_config).await.unwrap();
+        assert_eq!(summary.molecules, molecules.len());
+        assert_eq!(summary.clients_notified, 0);
+        assert_eq!(input_config.get().settings.len(), summary.settings);
+        assert!(summary.settings > 0);
+
+        // Add, hide and remove a molecule in a copy of the molecules
+        let dir = std::env::temp_dir().join(format!("pytf-catalogue-molecules-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = MoleculePaths { file: dir.join("molecules.json"), dir: dir.clone() };
+        let upload = |res_name: &str, itp: &str| MoleculeUpload {
+            res_name: res_name.into(),
+            name: "Ethyl acetate".into(),
+            formula: "CH3COOC2H5".into(),
+            smiles: "CCOC(C)=O".into(),
+            pdb: std::fs::read_to_string("resources/molecules/5GOY.pdb").unwrap(),
+            itp: itp.into(),
+        };
+        let itp = std::fs::read_to_string("resources/molecules/5GOY.itp").unwrap();
+        assert!(matches!(add_molecule(&paths, upload("5GOY", "")), Err(EditError::Invalid(_))));
+        assert!(matches!(add_molecule(&paths, upload("LCHU", &itp)), Err(EditError::Conflict(_))));
+        assert!(!dir.join("5GOY.pdb").exists());
+        add_molecule(&paths, upload("5GOY", &itp)).unwrap();
+        assert!(dir.join("5GOY.pdb").is_file() && dir.join("5GOY.itp").is_file());
+        let saved = std::fs::read_to_string(&paths.file).unwrap();
+        assert!(saved.contains("\"5GOY\"") && !saved.contains("atoms"));
+        assert!(AVAILABLE_MOLECULES.get().unwrap().get().get("5GOY").is_some());
+
+        set_hidden(&paths, "5GOY", true).unwrap();
+        assert!(AVAILABLE_MOLECULES.get().unwrap().get().visible().all(|mol| mol.res_name() != "5GOY"));
+        assert!(std::fs::read_to_string(&paths.file).unwrap().contains("\"hidden\": true"));
+        remove_molecule(&paths, "5GOY").unwrap();
+        assert!(!dir.join("5GOY.pdb").exists());
+        assert!(AVAILABLE_MOLECULES.get().unwrap().get().get("5GOY").is_none());
+        assert!(matches!(remove_molecule(&paths, "5GOY"), Err(EditError::NotFound(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
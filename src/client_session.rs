@@ -1,17 +1,20 @@
-use std::{time::{Duration, Instant}, sync::Arc};
+use std::{time::Instant, sync::Arc};
 
 use actix::prelude::*;
 use actix_web_actors::ws;
 use pytf_web::{
-    pytf_config::{PytfConfigMinimal, PytfConfig},
+    authentication::{Role, SessionUser},
+    guests::GUESTS,
+    pytf_config::{PytfConfigMinimal, PytfConfig, AVAILABLE_MOLECULES},
     input_config::ConfigSettings,
+    Reloadable,
 };
 
-use crate::job_queue::{Job, JobServer, ClientConnect, ClientDisconnect, ClientReqJob, AssignJobs, JobInner, RegisterJob, AcceptedJob};
-
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
-
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+use crate::{
+    job_queue::{Job, JobServer, ClientConnect, ClientDisconnect, ClientReqJob, AssignJobs, JobInner, JobStatus, RegisterJob, AcceptedJob, FindHistoryEntry},
+    metrics::{Metrics, METRICS},
+    server_config::config,
+};
 
 /** MESSAGES TO CLIENT
 *
@@ -24,7 +27,8 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
 /// Format is "{MSG_NEW_FRAMES}{{l:{latest_frame},f:{n_cycles}}}"
 const MSG_NEW_FRAMES: &str = "new_frames";
 
-/// text => Job has failed.
+/// text => Job has failed, with the name of the worker it failed on if known.
+/// Format is "{MSG_JOB_FAILED}{worker}"
 const MSG_JOB_FAILED: &str = "failed";
 
 /// text => Requested segment ID (sent back) is unavailable
@@ -34,6 +38,13 @@ const MSG_SEG_UNAVAILABLE: &str = "no_seg";
 /// text => Job has been queued
 const MSG_JOB_QUEUED: &str = "queued";
 
+/// text => Job was not allowed to run (e.g. guest limits or user quotas), with a reason to show the user
+/// Format is "{MSG_JOB_DENIED}{reason}"
+const MSG_JOB_DENIED: &str = "denied";
+
+/// text => Available molecules or protocol settings have changed, so should be fetched again
+const MSG_CATALOGUE_CHANGED: &str = "catalogue";
+
 
 
 /** MESSAGES FROM CLIENT
@@ -48,6 +59,10 @@ const MSG_JOB_QUEUED: &str = "queued";
 /// text => Cancel the current job
 const MSG_JOB_CANCEL: &str = "cancel";
 
+/// text => Re-attach to a job from the user's history (see `GET /jobs`), loading it from the archive if needed.
+/// Format is "{MSG_JOB_ATTACH}{job name}"
+const MSG_JOB_ATTACH: &str = "attach";
+
 
 
 
@@ -55,6 +70,12 @@ const MSG_JOB_CANCEL: &str = "cancel";
 pub struct ClientWsSession {
     pub id: Arc<String>,
 
+    /// Role of the user. Guests are limited to the jobs allowed in guest mode.
+    pub role: Role,
+
+    /// Login session the client connected with, so it can be closed if the session is revoked.
+    session: Option<String>,
+
     /// Set true when received a `ClientForceDisconnect` message from server to
     /// avoid sending `ClientDisconnect` message back to server when this Actor stops.
     force_disconnect: bool,
@@ -66,13 +87,15 @@ pub struct ClientWsSession {
     pub job_server: Addr<JobServer>,
 
     /// Config settings to be calculated/sanitized from user input + literals to be passed through.
-    input_config: Arc<ConfigSettings>,
+    input_config: Arc<Reloadable<ConfigSettings>>,
 }
 
 impl ClientWsSession {
-    pub fn new(id: String, job_server: Addr<JobServer>, input_config: Arc<ConfigSettings>) -> Self {
+    pub fn new(user: SessionUser, job_server: Addr<JobServer>, input_config: Arc<Reloadable<ConfigSettings>>) -> Self {
         Self {
-            id: Arc::new(id),
+            id: Arc::new(user.username),
+            role: user.role,
+            session: user.session,
             force_disconnect: false,
             heartbeat: Instant::now(),
             job: None,
@@ -82,8 +105,10 @@ impl ClientWsSession {
     }
 
     fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
+        let settings = &config().websocket;
+        let timeout = settings.client_timeout();
+        ctx.run_interval(settings.client_heartbeat(), move |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > timeout {
                 log::info!("Lost connection to client {}", act.id);
                 // act.job_server.do_send(ClientDisconnect { id: act.id.clone() });
                 ctx.stop();
@@ -92,6 +117,109 @@ impl ClientWsSession {
             ctx.ping(b"");
         });
     }
+
+    /// Give back the job slot reserved by `request_job` for guests
+    fn release_guest_job(&self) {
+        if self.role != Role::Guest { return }
+        if let Some(guests) = GUESTS.get() {
+            guests.release_job(&self.id);
+        }
+    }
+
+    /// Stop watching the current job (if any) to watch `job` instead
+    fn switch_job(&mut self, job: Option<Job>, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(old_job) = self.job.take() {
+            if !job.as_ref().is_some_and(|job| Arc::ptr_eq(job, &old_job)) {
+                let mut old_job = old_job.write().unwrap();
+                log::info!("Removing client {} from old job with name {}", self.id, old_job.config.name);
+                old_job.remove_client(&ctx.address());
+            }
+        }
+        self.job = job;
+    }
+
+    /// Attach to the job for `config`, starting it (or loading it from the archive) if the
+    /// server doesn't have it. `submitted` is the config as sent by the client.
+    fn request_job(&mut self, config: PytfConfig, submitted: PytfConfigMinimal, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.role == Role::Guest {
+            let allowed = GUESTS.get()
+                .ok_or_else(|| "Guest mode is disabled.".to_string())
+                .and_then(|guests| guests.reserve_job(&self.id, &config.name));
+            if let Err(reason) = allowed {
+                log::info!("Denied job {} for guest {}: {reason}", config.name, self.id);
+                ctx.text(format!("{MSG_JOB_DENIED}{reason}"));
+                return;
+            }
+        }
+        self.job_server.send(ClientReqJob {
+            config: config.clone(),
+            submitted: submitted.clone(),
+            client_id: self.id.clone(),
+            client_addr: ctx.address(),
+            client_prev_job: self.job.clone(),
+        })
+        .into_actor(self)
+        .then(|res, act, ctx| {
+            // Only new simulations count towards guests' limits, so give back the reserved slot otherwise.
+            // Jobs the server doesn't have may still turn out to be finished once loaded from the archive.
+            if !matches!(res, Ok(AcceptedJob::New)) {
+                act.release_guest_job();
+            }
+            match res {
+                Ok(AcceptedJob::Existing(job)) => {
+                    act.switch_job(Some(job), ctx);
+                    act.job_server.do_send(AssignJobs {});
+                    ctx.text(MSG_JOB_QUEUED);
+                },
+                Ok(AcceptedJob::Finished(job)) => {
+                    let ping = { job.read().unwrap().build_ping() };
+                    act.switch_job(Some(job), ctx);
+                    ctx.address().do_send(ping);
+                },
+                Ok(AcceptedJob::New) => {
+                    act.switch_job(None, ctx);
+                    // For new jobs, create on client thread
+                    // since could involve slow read from disk
+                    act.job_server.send(RegisterJob {
+                        job: JobInner::new(config),
+                        client: ctx.address(),
+                        client_id: act.id.clone(),
+                        submitted,
+                    }).into_actor(act).then(|res, act, ctx| {
+                        match res {
+                            Ok(Ok(job)) => {
+                                if job.read().unwrap().status == JobStatus::Finished {
+                                    act.release_guest_job();
+                                }
+                                act.job = Some(job);
+                                ctx.text(MSG_JOB_QUEUED);
+                            },
+                            Ok(Err(reason)) => {
+                                act.release_guest_job();
+                                ctx.text(format!("{MSG_JOB_DENIED}{reason}"));
+                            },
+                            Err(_) => {
+                                act.release_guest_job();
+                                ctx.text(MSG_JOB_FAILED);
+                            },
+                        }
+                        fut::ready(())
+                    }).wait(ctx);
+                }
+                Ok(AcceptedJob::Failed(worker)) => {
+                    act.switch_job(None, ctx);
+                    ctx.text(format!("{MSG_JOB_FAILED}{}", worker.as_deref().map_or("", String::as_str)));
+                },
+                Ok(AcceptedJob::Denied(reason)) => {
+                    // Keep watching the current job, as when denied for guest limits
+                    ctx.text(format!("{MSG_JOB_DENIED}{reason}"));
+                },
+                _ => ctx.stop(), // Something went wrong
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
+    }
 }
 
 
@@ -99,13 +227,16 @@ impl Actor for ClientWsSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        Metrics::connected(&METRICS.client_connections, &METRICS.client_connections_total);
         self.heartbeat(ctx);
 
         let addr = ctx.address();
         self.job_server
             .send(ClientConnect {
                 id: self.id.clone(),
+                role: self.role,
                 addr,
+                session: self.session.clone(),
             })
             .into_actor(self)
             .then(|res, _act, ctx| {
@@ -128,6 +259,10 @@ impl Actor for ClientWsSession {
         }
         Running::Stop
     }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        Metrics::dec(&METRICS.client_connections);
+    }
 }
 
 #[derive(Message)]
@@ -148,6 +283,37 @@ impl Handler<ClientForceDisconnect> for ClientWsSession {
     }
 }
 
+/// The user's account has been disabled or removed, or their login session has been revoked,
+/// so close the connection.
+/// Unlike `ClientForceDisconnect`, the session is cleaned up as normal.
+#[derive(Message)]
+#[rtype(result="()")]
+pub struct ClientEndSession {}
+
+impl Handler<ClientEndSession> for ClientWsSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ClientEndSession, ctx: &mut Self::Context) -> Self::Result {
+        log::info!("Closing session for deactivated or logged out client {}", self.id);
+        ctx.close(Some(ws::CloseCode::Policy.into()));
+        ctx.stop();
+    }
+}
+
+
+/// The molecules or input configuration have been reloaded
+#[derive(Message)]
+#[rtype(result="()")]
+pub struct ClientCatalogueChanged {}
+
+impl Handler<ClientCatalogueChanged> for ClientWsSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ClientCatalogueChanged, ctx: &mut Self::Context) -> Self::Result {
+        ctx.text(MSG_CATALOGUE_CHANGED);
+    }
+}
+
 
 // Incoming stream from client
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWsSession {
@@ -180,57 +346,29 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWsSession {
                         ctx.text(MSG_JOB_QUEUED); // Queued works as a null response
                     }
                     log::debug!("Done processing cancel for client {}", self.id);
-                } else if let Ok(config) = serde_json::from_str::<PytfConfigMinimal>(&text) {
-                    log::info!("Received job config from client {}: {config}", self.id);
-                    if let Some(old_job) = self.job.take() {
-                        let mut old_job = old_job.write().unwrap();
-                        log::info!("Removing client {} from old job with name {}", self.id, old_job.config.name);
-                        old_job.remove_client(&ctx.address());
-                    }
-                    let config: PytfConfig = config.build(&self.input_config);
-                    self.job_server.send(ClientReqJob {
-                        config: config.clone(),
-                        client_id: self.id.clone(),
-                        client_addr: ctx.address(),
-                        client_prev_job: self.job.clone(),
-                    })
-                    .into_actor(self)
-                    .then(|res, act, ctx| {
-                        match res {
-                            Ok(AcceptedJob::Existing(job)) => {
-                                act.job = Some(job);
-                                act.job_server.do_send(AssignJobs {});
-                                ctx.text(MSG_JOB_QUEUED);
-                            },
-                            Ok(AcceptedJob::Finished(job)) => {
-                                let ping = { job.read().unwrap().build_ping() };
-                                act.job = Some(job.clone());
-                                ctx.address().do_send(ping);
-                            },
-                            Ok(AcceptedJob::New) => {
-                                // For new jobs, create on client thread
-                                // since could involve slow read from disk
-                                act.job_server.send(RegisterJob {
-                                    job: JobInner::new(config),
-                                    client: ctx.address(),
-                                }).into_actor(act).then(|res, act, ctx| {
-                                    if let Ok(job) = res {
-                                        act.job = Some(job);
-                                    } else {
-                                        ctx.text(MSG_JOB_FAILED);
-                                    }
-                                    fut::ready(())
-                                }).wait(ctx);
-                                ctx.text(MSG_JOB_QUEUED);
+                } else if let Some(jobname) = text.strip_prefix(MSG_JOB_ATTACH) {
+                    log::info!("Received request from client {} to re-attach to job {jobname}", self.id);
+                    self.job_server.send(FindHistoryEntry { user: self.id.to_string(), jobname: jobname.to_owned() })
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            match res {
+                                Ok(Some(entry)) => act.request_job(entry.config, entry.submitted, ctx),
+                                Ok(None) => ctx.text(format!("{MSG_JOB_DENIED}Unknown job.")),
+                                Err(_) => ctx.stop(), // Something went wrong
                             }
-                            Ok(AcceptedJob::Failed) => {
-                                ctx.text(MSG_JOB_FAILED);
-                            },
-                            _ => ctx.stop(), // Something went wrong
-                        }
-                        fut::ready(())
-                    })
-                    .wait(ctx);
+                            fut::ready(())
+                        })
+                        .wait(ctx);
+                } else if let Ok(submitted) = serde_json::from_str::<PytfConfigMinimal>(&text) {
+                    log::info!("Received job config from client {}: {submitted}", self.id);
+                    // Hidden molecules are only left out of /molecules, so check that the client didn't ask for one anyway
+                    if let Err(reason) = AVAILABLE_MOLECULES.get().unwrap().get().check_visible(&submitted) {
+                        log::info!("Denied job config from client {}: {reason}", self.id);
+                        ctx.text(format!("{MSG_JOB_DENIED}{reason}"));
+                        return;
+                    }
+                    let config: PytfConfig = submitted.clone().build(&self.input_config.get());
+                    self.request_job(config, submitted, ctx);
                 } else if let Ok(segment_id) = text.parse::<usize>() {
                     log::debug!("Received request for segment {segment_id} from client {}: {text}", self.id);
                     if segment_id == 0 {
@@ -300,6 +438,8 @@ impl Handler<TrajectoryPing> for ClientWsSession {
 #[rtype(result="()")]
 pub struct JobFailed {
     pub jobname: String,
+    /// Name of the worker the job failed on
+    pub worker: Arc<String>,
 }
 
 impl Handler<JobFailed> for ClientWsSession {
@@ -310,9 +450,9 @@ impl Handler<JobFailed> for ClientWsSession {
         // to job as well.
         if let Some(job) = self.job.take() {
             if job.read().unwrap().config.name == msg.jobname {
-                log::warn!("Sending fail message to client {} for job {}",
-                    self.id, msg.jobname);
-                ctx.text(MSG_JOB_FAILED);
+                log::warn!("Sending fail message to client {} for job {} (failed on worker {})",
+                    self.id, msg.jobname, msg.worker);
+                ctx.text(format!("{MSG_JOB_FAILED}{}", msg.worker));
             }
         }
     }
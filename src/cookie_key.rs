@@ -0,0 +1,121 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use actix_web::{
+    cookie::{Cookie, CookieJar, Key},
+    dev::ServiceRequest,
+    http::header::{HeaderValue, COOKIE},
+};
+use pytf_web::to_hex;
+
+/// Name of the session cookie
+pub const SESSION_COOKIE: &str = "id";
+
+/// Keys are stored as hex, so they can be copied between servers if necessary
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 { return None }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i+2)?, 16).ok())
+        .collect()
+}
+
+/// Read a cookie key from the specified file
+pub fn load(fname: impl AsRef<Path>) -> io::Result<Key> {
+    let hex = fs::read_to_string(&fname)?;
+    let bytes = from_hex(hex.trim()).ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Cookie key file {} is not valid hex", fname.as_ref().to_string_lossy())
+    ))?;
+    Key::try_from(bytes.as_slice()).map_err(|e| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid cookie key in {}: {e}", fname.as_ref().to_string_lossy())
+    ))
+}
+
+/// Read a cookie key from the specified file, or generate a new
+/// key and save it to the file if it doesn't exist yet.
+pub fn load_or_create(fname: impl AsRef<Path>) -> io::Result<Key> {
+    let fname = fname.as_ref();
+    if fname.exists() {
+        log::info!("Using cookie key from {}", fname.to_string_lossy());
+        return load(fname)
+    }
+    log::info!("Generating new cookie key in {}", fname.to_string_lossy());
+    let key = Key::generate();
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        // Anyone who can read the key can forge sessions
+        opts.mode(0o600);
+    }
+    let mut fid = opts.open(fname)?;
+    writeln!(fid, "{}", to_hex(key.master()))?;
+    fid.sync_all()?;
+    Ok(key)
+}
+
+/// Re-encrypt a session cookie made with the previous key using the current key,
+/// so that sessions survive a key rotation. The session middleware then sends back
+/// a cookie encrypted with the current key the next time the session is updated.
+pub fn rotate_session_cookie(req: &mut ServiceRequest, current: &Key, previous: &Key) {
+    // Parse the header directly rather than using req.cookie(), which would cache
+    // the cookies before they are rewritten.
+    let mut cookies: Vec<Cookie<'static>> = req.headers()
+        .get_all(COOKIE)
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| Cookie::parse_encoded(c.trim().to_owned()).ok())
+        .collect();
+    let Some(session) = cookies.iter_mut().find(|c| c.name() == SESSION_COOKIE) else { return };
+
+    let mut jar = CookieJar::new();
+    if jar.private(current).decrypt(session.clone()).is_some() { return }
+    let Some(decrypted) = jar.private(previous).decrypt(session.clone()) else { return };
+    jar.private_mut(current).add(decrypted);
+    let Some(rotated) = jar.get(SESSION_COOKIE) else { return };
+    *session = rotated.clone();
+
+    let header = cookies.iter()
+        .map(|c| c.encoded().stripped().to_string())
+        .collect::<Vec<_>>()
+        .join("; ");
+    if let Ok(header) = HeaderValue::from_str(&header) {
+        log::debug!("Re-encrypted session cookie made with previous key");
+        let headers = req.headers_mut();
+        headers.remove(COOKIE);
+        headers.insert(COOKIE, header);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_rotate_session_cookie() {
+        let previous = Key::generate();
+        let current = Key::generate();
+        assert_eq!(from_hex(&to_hex(current.master())).unwrap(), current.master());
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&previous).add(Cookie::new(SESSION_COOKIE, "session"));
+        let old_cookie = jar.get(SESSION_COOKIE).unwrap().clone();
+        let mut req = TestRequest::default()
+            .cookie(Cookie::new("other", "value"))
+            .cookie(old_cookie)
+            .to_srv_request();
+        rotate_session_cookie(&mut req, &current, &previous);
+
+        let rotated = req.cookie(SESSION_COOKIE).unwrap();
+        let decrypted = CookieJar::new().private(&current).decrypt(rotated).unwrap();
+        assert_eq!(decrypted.value(), "session");
+        assert_eq!(req.cookie("other").unwrap().value(), "value");
+    }
+}
@@ -0,0 +1,123 @@
+use actix_cors::Cors;
+use actix_web::{
+    dev::RequestHead,
+    http::{self, header::HeaderValue, Method, Uri},
+};
+use anyhow::anyhow;
+
+use crate::server_config::CorsSection;
+
+/// Host (and port) a request was sent to. Behind a trusted reverse proxy this comes from the
+/// X-Forwarded-Host header, since the proxy may change the Host header.
+fn request_host(req: &RequestHead, trust_proxy: bool) -> Option<&str> {
+    let forwarded = trust_proxy.then(|| req.headers().get("x-forwarded-host")).flatten();
+    let host = forwarded.or_else(|| req.headers().get(http::header::HOST))?;
+    host.to_str().ok()?.split(',').next().map(str::trim)
+}
+
+/// Whether a request's Origin is the server itself. Only the host and port are compared,
+/// since a reverse proxy handling https makes requests look like http to the server.
+fn is_same_origin(origin: &HeaderValue, req: &RequestHead, trust_proxy: bool) -> bool {
+    let Some(origin_host) = origin.to_str().ok().and_then(|o| o.split_once("://")).map(|(_, host)| host) else {
+        return false
+    };
+    let host = request_host(req, trust_proxy).or_else(|| req.uri.authority().map(|a| a.as_str()));
+    host.is_some_and(|host| host.eq_ignore_ascii_case(origin_host))
+}
+
+/// Check the CORS settings, so mistakes are reported at startup rather than when the
+/// middleware is built
+pub fn validate(settings: &CorsSection) -> anyhow::Result<()> {
+    if settings.allow_any_origin && settings.supports_credentials {
+        return Err(anyhow!("cors.allow_any_origin can't be used with cors.supports_credentials, \
+            since any website could then make requests as a logged in user. List the allowed origins instead."))
+    }
+    for origin in &settings.allowed_origins {
+        let valid = origin.parse::<Uri>().is_ok_and(|uri| {
+            uri.scheme().is_some() && uri.authority().is_some() && origin.trim_end_matches('/') == origin
+        });
+        if !valid {
+            return Err(anyhow!("Invalid CORS origin \"{origin}\". Expected a scheme and host, \
+                e.g. \"https://example.com\", without a path or trailing slash."))
+        }
+    }
+    for method in &settings.allowed_methods {
+        Method::from_bytes(method.as_bytes())
+            .map_err(|_| anyhow!("Invalid HTTP method \"{method}\" in cors.allowed_methods"))?;
+    }
+    Ok(())
+}
+
+/// Build the CORS middleware. Requests from the server's own origin are always allowed,
+/// as are those from the configured origins. Requests from other origins are rejected and logged.
+pub fn cors(settings: &CorsSection, trust_proxy: bool) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(settings.allowed_methods.iter().map(|m| m.as_str()))
+        .allowed_headers(vec![
+            http::header::AUTHORIZATION,
+            http::header::ACCEPT,
+            http::header::CONTENT_TYPE,
+        ])
+        .max_age(settings.max_age_secs);
+    if settings.allow_any_origin {
+        return cors.allow_any_origin()
+    }
+    for origin in &settings.allowed_origins {
+        cors = cors.allowed_origin(origin);
+    }
+    if settings.supports_credentials {
+        cors = cors.supports_credentials();
+    }
+    // Only called for origins which aren't in the allowed list
+    cors.allowed_origin_fn(move |origin, req| {
+        let allowed = is_same_origin(origin, req, trust_proxy);
+        if !allowed {
+            log::warn!("Blocked {} {} from disallowed origin {origin:?}", req.method, req.uri.path());
+        }
+        allowed
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_cors() {
+        let settings = CorsSection {
+            allowed_origins: vec!["https://other.example.com".into()],
+            ..Default::default()
+        };
+        validate(&settings).unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&settings, false))
+                .route("/", web::post().to(HttpResponse::Ok))
+        ).await;
+        let request = |origin: Option<&str>| {
+            let req = test::TestRequest::post().uri("/").insert_header(("host", "pytf.example.com"));
+            match origin {
+                Some(origin) => req.insert_header(("origin", origin)).to_request(),
+                None => req.to_request(),
+            }
+        };
+
+        let res = test::call_service(&app, request(None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, request(Some("https://pytf.example.com"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, request(Some("https://other.example.com"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("access-control-allow-origin").unwrap(), "https://other.example.com");
+        assert!(res.headers().get("access-control-allow-credentials").is_none());
+        let res = test::call_service(&app, request(Some("https://evil.example.com"))).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        assert!(validate(&CorsSection { allow_any_origin: true, supports_credentials: true, ..Default::default() }).is_err());
+        assert!(validate(&CorsSection { allowed_origins: vec!["https://example.com/".into()], ..Default::default() }).is_err());
+        assert!(validate(&CorsSection { allowed_origins: vec!["example.com".into()], ..Default::default() }).is_err());
+        assert!(validate(&CorsSection { allowed_methods: vec!["GET POST".into()], ..Default::default() }).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Mutex, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+
+use crate::{
+    authentication::{Role, SessionUser},
+    input_config::ConfigSettings,
+    pytf_config::PytfConfigMinimal,
+    to_hex,
+};
+
+/// Guests in guest mode. Only set if guest mode is enabled.
+pub static GUESTS: OnceLock<GuestManager> = OnceLock::new();
+
+/// Prefix of guest usernames. Guest names can't clash with real users,
+/// since guests are never looked up in the users database.
+const GUEST_PREFIX: &str = "guest-";
+
+/// Guest mode settings, loaded from a yaml file
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuestSettings {
+    /// How long a guest session lasts, in minutes
+    #[serde(default = "GuestSettings::default_ttl")]
+    pub ttl_minutes: u64,
+    /// Maximum number of jobs each guest can submit
+    #[serde(default = "GuestSettings::default_max_jobs")]
+    pub max_jobs: usize,
+    /// Maximum number of guests at once
+    #[serde(default = "GuestSettings::default_max_guests")]
+    pub max_guests: usize,
+    /// Configurations guests may run. Anything else is rejected.
+    pub configs: Vec<PytfConfigMinimal>,
+}
+
+impl GuestSettings {
+    fn default_ttl() -> u64 { 60 }
+    fn default_max_jobs() -> usize { 3 }
+    fn default_max_guests() -> usize { 200 }
+
+    /// Load guest mode settings from a yaml file.
+    pub fn open(yml_file: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let settings: Self = serde_yaml::from_reader(
+            std::fs::OpenOptions::new().read(true).open(yml_file)?)?;
+        if settings.configs.is_empty() {
+            log::warn!("No configurations allowed for guests, so guests won't be able to run anything.");
+        }
+        Ok(settings)
+    }
+}
+
+#[derive(Debug)]
+struct GuestEntry {
+    expires: Instant,
+    jobs: usize,
+}
+
+/// Keeps track of active guests, which only exist in memory
+#[derive(Debug)]
+pub struct GuestManager {
+    ttl: Duration,
+    max_jobs: usize,
+    max_guests: usize,
+    /// Configurations guests are allowed to run
+    configs: Vec<PytfConfigMinimal>,
+    /// Names of the jobs the allowed configurations correspond to
+    allowed_jobs: RwLock<HashSet<String>>,
+    guests: Mutex<HashMap<String, GuestEntry>>,
+}
+
+impl GuestManager {
+    /// Set up guest mode. `input_config` is needed to resolve the allowed configurations
+    /// into the names of the jobs they correspond to.
+    pub fn new(settings: GuestSettings, input_config: &ConfigSettings) -> Self {
+        let manager = Self {
+            ttl: Duration::from_secs(settings.ttl_minutes * 60),
+            max_jobs: settings.max_jobs,
+            max_guests: settings.max_guests,
+            configs: settings.configs,
+            allowed_jobs: RwLock::new(HashSet::new()),
+            guests: Mutex::new(HashMap::new()),
+        };
+        manager.update_input_config(input_config);
+        manager
+    }
+
+    /// Resolve the allowed configurations again after the input configuration has changed,
+    /// since job names depend on it
+    pub fn update_input_config(&self, input_config: &ConfigSettings) {
+        *self.allowed_jobs.write().unwrap() = self.configs.iter()
+            .map(|config| config.clone().build(input_config).name)
+            .collect();
+    }
+
+    /// Create a new guest. Returns `None` if there are already too many guests.
+    pub fn create(&self) -> Option<SessionUser> {
+        let mut guests = self.guests.lock().unwrap();
+        let now = Instant::now();
+        guests.retain(|_, g| g.expires > now);
+        if guests.len() >= self.max_guests {
+            log::warn!("Refusing new guest: already at the limit of {} guests", self.max_guests);
+            return None
+        }
+        let mut bytes = [0u8; 6];
+        OsRng.fill_bytes(&mut bytes);
+        let username = format!("{GUEST_PREFIX}{}", to_hex(&bytes));
+        guests.insert(username.clone(), GuestEntry { expires: now + self.ttl, jobs: 0 });
+        log::info!("Created guest {username} ({} active guests)", guests.len());
+        Some(SessionUser { username, role: Role::Guest, external: false, session: None })
+    }
+
+    /// Check whether a guest exists and hasn't expired
+    pub fn is_active(&self, username: &str) -> bool {
+        self.guests.lock().unwrap()
+            .get(username)
+            .is_some_and(|g| g.expires > Instant::now())
+    }
+
+    /// Check whether a guest may run the job with the specified name, and if so count it
+    /// against their quota straight away, so that simultaneous requests can't both get the
+    /// last slot. Returns the reason if not allowed.
+    /// Call `release_job()` if the job doesn't go ahead after all.
+    pub fn reserve_job(&self, username: &str, jobname: &str) -> Result<(), String> {
+        if !self.allowed_jobs.read().unwrap().contains(jobname) {
+            return Err("Guests can only run the example configurations.".into())
+        }
+        let mut guests = self.guests.lock().unwrap();
+        let Some(guest) = guests.get_mut(username) else {
+            return Err("Guest session has expired.".into())
+        };
+        if guest.jobs >= self.max_jobs {
+            return Err(format!("Guests can run at most {} simulations.", self.max_jobs))
+        }
+        guest.jobs += 1;
+        Ok(())
+    }
+
+    /// Give back a job reserved with `reserve_job()` which didn't go ahead
+    pub fn release_job(&self, username: &str) {
+        if let Some(guest) = self.guests.lock().unwrap().get_mut(username) {
+            guest.jobs = guest.jobs.saturating_sub(1);
+        }
+    }
+
+    /// Remove a guest (e.g. on logout)
+    pub fn remove(&self, username: &str) {
+        self.guests.lock().unwrap().remove(username);
+    }
+
+    /// Remove expired guests, returning their names so their sessions can be closed
+    pub fn remove_expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.guests.lock().unwrap().retain(|name, g| {
+            if g.expires > now { return true }
+            expired.push(name.clone());
+            false
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_example_settings() {
+        let _ = crate::pytf_config::RESOURCES_DIR.set("resources".into());
+        let settings = GuestSettings::open("resources/guest_mode.yml").unwrap();
+        let input_config = ConfigSettings::open("resources/input_config.yml").unwrap();
+        let guests = GuestManager::new(settings, &input_config);
+        let allowed_jobs = guests.allowed_jobs.read().unwrap();
+        assert_eq!(allowed_jobs.len(), 2);
+        assert!(allowed_jobs.contains("LCHU-1__J0B-1_0.35"));
+    }
+
+    #[test]
+    fn test_guests() {
+        let guests = GuestManager {
+            ttl: Duration::from_secs(60),
+            max_jobs: 2,
+            max_guests: 2,
+            configs: Vec::new(),
+            allowed_jobs: RwLock::new(HashSet::from(["example".to_string()])),
+            guests: Mutex::new(HashMap::new()),
+        };
+        let guest = guests.create().unwrap();
+        assert_eq!(guest.role, Role::Guest);
+        assert!(guest.username.starts_with(GUEST_PREFIX));
+        assert!(guests.is_active(&guest.username));
+        assert!(!guests.is_active("guest-000000000000"));
+
+        assert!(guests.reserve_job(&guest.username, "other").is_err());
+        assert!(guests.reserve_job(&guest.username, "example").is_ok());
+        assert!(guests.reserve_job(&guest.username, "example").is_ok());
+        assert!(guests.reserve_job(&guest.username, "example").is_err());
+        guests.release_job(&guest.username);
+        assert!(guests.reserve_job(&guest.username, "example").is_ok());
+        assert!(guests.reserve_job(&guest.username, "example").is_err());
+
+        let other = guests.create().unwrap();
+        assert!(guests.create().is_none());
+        guests.remove(&other.username);
+        assert!(guests.create().is_some());
+
+        guests.guests.lock().unwrap().get_mut(&guest.username).unwrap().expires = Instant::now();
+        assert_eq!(guests.remove_expired(), vec![guest.username.clone()]);
+        assert!(!guests.is_active(&guest.username));
+    }
+}
@@ -1,10 +1,27 @@
-use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Error, ErrorKind, Result, Write},
+    path::Path,
+};
 
-use argon2::{Argon2, password_hash::{SaltString, rand_core::OsRng}, PasswordHasher};
+use argon2::{Argon2, password_hash::{SaltString, rand_core::{OsRng, RngCore}}, PasswordHasher};
+use pytf_web::authentication::{Role, UserCredentials, UserDB, validate_username};
+
+/// Characters used for generated student passwords. Excludes easily confused characters
+/// (0/O, 1/l/I, etc.) since passwords are handed out on paper.
+const PASSWORD_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PASSWORD_LEN: usize = 10;
 
 const HELP_MSG: &str =
 "
 USAGE: pytf-hash-users <file> [OPTIONS]
+       pytf-hash-users --roster <roster> -o <out_file> [OPTIONS]
+       pytf-hash-users <file> --verify <username> <pwd>
+
+<file> should contain lines of username,role,password where role is one of admin,
+instructor, student or worker. Lines of username,password are given the student role,
+so workers must have the worker role set explicitly.
 
 OPTIONS:
   -g/--generate     <num_students> <pwd>
@@ -16,12 +33,36 @@ OPTIONS:
   -w/--worker-pass  <pwd>       Specify password for \"worker\" user when the -g flag
                                 is passed. Default is to generate a password and print it.
 
+  -n/--num-workers  <num>       Generate <num> individually named workers (worker01, worker02,
+                                ...) with randomised passwords when the -g flag is passed,
+                                instead of a single shared \"worker\" user. Names and
+                                passwords are printed as output.
+
+  --roster          <roster>    Generate the users file from <roster>, which contains one
+                                student name or email address per line. Each student gets
+                                a username based on their name or email and a random password.
+                                Workers are generated as for -g. Plaintext passwords are written
+                                to the handout files (see --handout).
+
+  --handout         <base>      Base name for the handout files written with --roster.
+                                <base>.csv contains name,username,password for each student,
+                                and <base>.txt contains a printable slip for each student.
+                                Defaults to <out_file>.handout
+
+  --verify          <username> <pwd>
+                                Check <pwd> against the password hash for <username> in
+                                the hashed users <file>, then exit. The file is not modified.
+
   -o                <out_file>  File to output to. Default is in-place (same as <file>)
 
   -u/--user         <username> <pwd>
-                                Additional user/password combinations to add.
+                                Additional student user/password combinations to add.
                                 Can be specified multiple times.
 
+  -r/--role-user    <username> <role> <pwd>
+                                Additional user/password combinations with the specified
+                                role to add. Can be specified multiple times.
+
   -h                            Show this message.
 ";
 
@@ -32,7 +73,11 @@ fn main() -> Result<()>{
     let mut generate = 0usize;
     let mut student_pass: Option<String> = None;
     let mut worker_pass: Option<String> = None;
-    let mut other_users: Vec<(String, String)> = vec![];
+    let mut num_workers = 0usize;
+    let mut other_users: Vec<(String, Role, String)> = vec![];
+    let mut roster: Option<String> = None;
+    let mut handout: Option<String> = None;
+    let mut verify: Option<(String, String)> = None;
     while let Some(arg) = args.next() {
         match arg.as_str() {
             "-h" | "--help" => {
@@ -84,12 +129,58 @@ fn main() -> Result<()>{
                     );
                 }
             }
+            "-n" | "--num-workers" => {
+                let Some(num) = args.next() else {
+                    return Err(
+                        Error::new(ErrorKind::InvalidInput.into(),
+                        "ERROR: Missing argument with -n flag for number of workers to generate")
+                    );
+                };
+                num_workers = match num.parse() {
+                    Ok(n) => n,
+                    Err(e) => {
+                        return Err(
+                            Error::new(ErrorKind::InvalidInput.into(),
+                            format!("Error parsing number of workers to generate: {e}"))
+                        );
+                    }
+                };
+            }
+            "--roster" => {
+                roster = args.next();
+                if roster.is_none() {
+                    return Err(
+                        Error::new(ErrorKind::InvalidInput.into(),
+                        "ERROR: No roster file provided with --roster")
+                    );
+                }
+            }
+            "--handout" => {
+                handout = args.next();
+                if handout.is_none() {
+                    return Err(
+                        Error::new(ErrorKind::InvalidInput.into(),
+                        "ERROR: No handout file name provided with --handout")
+                    );
+                }
+            }
+            "--verify" => {
+                let username = args.next();
+                let pass = args.next();
+                match (username, pass) {
+                    (Some(username), Some(pass)) => verify = Some((username, pass)),
+                    _ => return Err(
+                        Error::new(ErrorKind::InvalidInput.into(),
+                        "ERROR: Missing username or password after --verify")
+                    )
+                };
+            }
             "-u" | "--user" => {
                 let username = args.next();
                 let pass = args.next();
                 match (username, pass) {
                     (Some(username), Some(pass)) => {
-                        other_users.push((username, pass));
+                        other_users.push((username, Role::Student, pass));
                     }
                     _ => return Err(
                         Error::new(ErrorKind::InvalidInput.into(),
@@ -97,6 +188,27 @@ fn main() -> Result<()>{
                     )
                 };
             }
+            "-r" | "--role-user" => {
+                let username = args.next();
+                let role = args.next();
+                let pass = args.next();
+                match (username, role, pass) {
+                    (Some(username), Some(role), Some(pass)) => {
+                        let role = role.parse()?;
+                        if role == Role::Guest {
+                            return Err(
+                                Error::new(ErrorKind::InvalidInput.into(),
+                                "ERROR: Guest users can't be stored in the users file")
+                            );
+                        }
+                        other_users.push((username, role, pass));
+                    }
+                    _ => return Err(
+                        Error::new(ErrorKind::InvalidInput.into(),
+                        "ERROR: Missing username, role or password after -r/--role-user")
+                    )
+                };
+            }
             arg => {
                 if in_file.is_some() {
                     return Err(
@@ -109,6 +221,22 @@ fn main() -> Result<()>{
         }
     }
 
+    if let Some((username, password)) = verify {
+        let Some(in_file) = in_file else {
+            return Err(
+                Error::new(ErrorKind::InvalidInput.into(),
+                "Missing hashed users file to verify against")
+            );
+        };
+        return verify_user(&in_file, username, password);
+    }
+    if generate > 0 && roster.is_some() {
+        return Err(
+            Error::new(ErrorKind::InvalidInput.into(),
+            "ERROR: -g and --roster can't be used together")
+        );
+    }
+
     let out_file = match out_file {
         Some(fname) => fname,
         None => match &in_file {
@@ -121,11 +249,11 @@ fn main() -> Result<()>{
     };
 
     let mut hasher = HashWriter::new(
-        std::fs::OpenOptions::new()
+        OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(out_file)?
+            .open(&out_file)?
     );
     if generate > 0 {
         let target_len = format!("{generate}").len();
@@ -133,17 +261,29 @@ fn main() -> Result<()>{
             (1..=generate).map(|i| {
                 let num = format!("{i}");
                 let prefix: String = vec!['0'; target_len - num.len()].iter().collect();
-                (format!("student{}{}", prefix, num), student_pass.as_ref().unwrap())
+                (format!("student{}{}", prefix, num), Role::Student, student_pass.as_ref().unwrap())
             })
         )?;
-        if let Some(worker_pass) = worker_pass {
-            hasher.write_user("worker", &worker_pass)?;
-        } else {
-            let worker_key = SaltString::generate(OsRng);
-            hasher.write_user("worker", &worker_key)?;
-            println!("{worker_key}");
-        }
+        write_workers(&mut hasher, num_workers, worker_pass)?;
         println!("Generated {generate} users.");
+    } else if let Some(roster) = roster {
+        let students = read_roster(&roster, &worker_names(num_workers))?;
+        let handout = handout.unwrap_or_else(|| format!("{out_file}.handout"));
+        let mut csv = BufWriter::new(create_private(format!("{handout}.csv"))?);
+        let mut slips = BufWriter::new(create_private(format!("{handout}.txt"))?);
+        writeln!(csv, "name,username,password")?;
+        for (name, username) in &students {
+            let password = generate_password();
+            hasher.write_user(username, Role::Student, &password)?;
+            // Quote names in case they contain commas
+            writeln!(csv, "\"{}\",{username},{password}", name.replace('"', "\"\""))?;
+            write_slip(&mut slips, name, username, &password)?;
+        }
+        csv.flush()?;
+        slips.flush()?;
+        write_workers(&mut hasher, num_workers, worker_pass)?;
+        println!("Generated {} users from {roster}. Passwords written to {handout}.csv and {handout}.txt",
+            students.len());
     } else if let Some(in_file) = in_file {
         let mut idx = 0;
         let in_data = std::fs::read_to_string(&in_file)?;
@@ -151,18 +291,30 @@ fn main() -> Result<()>{
             in_data.lines().filter_map(|line| {
                 idx += 1;
                 if line.len() == 0 { return None }
-                let Some(data) = line.split_once(",") else {
+                let Some((username, rest)) = line.split_once(",") else {
                     eprintln!("WARNING: Missing ',' on line {idx}! User skipped.");
                     return None;
                 };
-                Some(data)
+                // Role column is optional, so only treat it as a role if it parses as one.
+                // Rows without a role are students, so workers need the worker role set.
+                if let Some((role, pass)) = rest.split_once(",") {
+                    match role.parse::<Role>() {
+                        Ok(Role::Guest) => {
+                            eprintln!("WARNING: Guest user on line {idx}! Guests can't be stored in the users file. User skipped.");
+                            return None;
+                        }
+                        Ok(role) => return Some((username, role, pass)),
+                        Err(_) => (),
+                    }
+                }
+                Some((username, Role::Student, rest))
             })
         )?;
         println!("Hashed passwords for {idx} users from {in_file}.");
     };
     if other_users.len() > 0 {
-        for (user, pass) in &other_users {
-            hasher.write_user(&user, &pass)?;
+        for (user, role, pass) in &other_users {
+            hasher.write_user(&user, *role, &pass)?;
         }
         println!("Created {} additional users.", other_users.len());
     }
@@ -170,6 +322,132 @@ fn main() -> Result<()>{
     Ok(())
 }
 
+/// Names of the worker users in generated users files. Either `num_workers` individually
+/// named workers, or a single "worker" user.
+fn worker_names(num_workers: usize) -> Vec<String> {
+    if num_workers == 0 { return vec!["worker".into()] }
+    let target_len = format!("{num_workers}").len().max(2);
+    (1..=num_workers).map(|i| format!("worker{i:0target_len$}")).collect()
+}
+
+/// Write worker users for generated users files (see `worker_names()`), with individual
+/// random keys, or a single "worker" user with the specified or a random password.
+fn write_workers<W: Write>(hasher: &mut HashWriter<W>, num_workers: usize, worker_pass: Option<String>) -> Result<()> {
+    if num_workers > 0 {
+        for name in worker_names(num_workers) {
+            let worker_key = SaltString::generate(OsRng);
+            hasher.write_user(&name, Role::Worker, &worker_key)?;
+            println!("{name},{worker_key}");
+        }
+    } else if let Some(worker_pass) = worker_pass {
+        hasher.write_user("worker", Role::Worker, &worker_pass)?;
+    } else {
+        let worker_key = SaltString::generate(OsRng);
+        hasher.write_user("worker", Role::Worker, &worker_key)?;
+        println!("{worker_key}");
+    }
+    Ok(())
+}
+
+/// Read a roster of student names or email addresses, one per line. Blank lines
+/// and lines starting with '#' are skipped.
+/// Returns (name, username) pairs, with usernames made unique where necessary.
+/// Usernames in `reserved` (i.e. the workers) are never given to students.
+fn read_roster(fname: &str, reserved: &[String]) -> Result<Vec<(String, String)>> {
+    let data = std::fs::read_to_string(fname)?;
+    let mut taken: HashSet<String> = reserved.iter().cloned().collect();
+    let mut out = Vec::new();
+    for (idx, line) in data.lines().enumerate() {
+        let name = line.trim();
+        if name.is_empty() || name.starts_with('#') { continue }
+        let base = username_from_name(name);
+        if validate_username(&base).is_err() {
+            eprintln!("WARNING: Couldn't make a username for \"{name}\" on line {}! Student skipped.", idx + 1);
+            continue
+        }
+        if reserved.contains(&base) {
+            eprintln!("WARNING: Username \"{base}\" for \"{name}\" on line {} is used by a worker! \
+                Student given a different username.", idx + 1);
+        }
+        let mut username = base.clone();
+        let mut suffix = 2;
+        while !taken.insert(username.clone()) {
+            username = format!("{base}{suffix}");
+            suffix += 1;
+        }
+        out.push((name.to_owned(), username));
+    }
+    Ok(out)
+}
+
+/// Email addresses are used as-is (lower case). Names are converted to lower case,
+/// with spaces replaced by '.' and any other punctuation removed (e.g. "Jane O'Neil" => "jane.oneil")
+fn username_from_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    if name.contains('@') && !name.contains(|c: char| c.is_whitespace() || c == ',') {
+        return name
+    }
+    name.split_whitespace()
+        .map(|part| part.chars().filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_').collect::<String>())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Generate a random password from `PASSWORD_CHARS`
+fn generate_password() -> String {
+    // Reject values which would bias the choice towards the start of PASSWORD_CHARS
+    let limit = u32::MAX - u32::MAX % PASSWORD_CHARS.len() as u32;
+    let mut out = String::with_capacity(PASSWORD_LEN);
+    while out.len() < PASSWORD_LEN {
+        let x = OsRng.next_u32();
+        if x < limit {
+            out.push(PASSWORD_CHARS[(x % PASSWORD_CHARS.len() as u32) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Create a file which only the current user can read, since it will contain plaintext passwords
+fn create_private(fname: impl AsRef<Path>) -> Result<File> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(fname)
+}
+
+/// Write a slip with a student's login details, to be printed and cut out
+fn write_slip(out: &mut impl Write, name: &str, username: &str, password: &str) -> Result<()> {
+    const CUT_LINE: &str = "- - - - - - - - - - - - - - - - - - - - - - - -";
+    writeln!(out, "{CUT_LINE}")?;
+    writeln!(out)?;
+    writeln!(out, "  Name:      {name}")?;
+    writeln!(out, "  Username:  {username}")?;
+    writeln!(out, "  Password:  {password}")?;
+    writeln!(out)
+}
+
+/// Check `password` against the hash for `username` in the hashed users file
+fn verify_user(fname: &str, username: String, password: String) -> Result<()> {
+    let db = UserDB::load(fname)?;
+    let Some(user) = db.list_users().into_iter().find(|u| u.username == username) else {
+        return Err(Error::new(ErrorKind::NotFound, format!("No user \"{username}\" in {fname}")))
+    };
+    if db.validate_user(&UserCredentials { username: username.clone(), password }).is_some() {
+        println!("Password is correct for user \"{username}\" ({}).", user.role);
+        Ok(())
+    } else if user.locked {
+        Err(Error::new(ErrorKind::PermissionDenied,
+            format!("User \"{username}\" is disabled, so can't log in regardless of password.")))
+    } else {
+        Err(Error::new(ErrorKind::PermissionDenied, format!("Incorrect password for user \"{username}\".")))
+    }
+}
+
 struct HashWriter<'a, W: Write> {
     argon2: Argon2<'a>,
     fid: BufWriter<W>,
@@ -186,21 +464,21 @@ impl<'a, W: Write> HashWriter<'a, W> {
         }
     }
 
-    /// Write the specified `username` and `password` combination (password is hashed)
-    fn write_user(&mut self, username: impl AsRef<str>, password: impl AsRef<str>) -> Result<()> {
+    /// Write the specified `username`, `role` and `password` combination (password is hashed)
+    fn write_user(&mut self, username: impl AsRef<str>, role: Role, password: impl AsRef<str>) -> Result<()> {
         let username = username.as_ref();
         let password = password.as_ref();
         let salt = SaltString::generate(&mut self.rng);
         let Ok(hash) = self.argon2.hash_password(password.as_bytes(), &salt) else {
             return Err(Error::new(ErrorKind::Other, format!("Error hashing \"{password}\"")))
         };
-        writeln!(self.fid, "{username},{hash}")
+        writeln!(self.fid, "{username},{role},{hash}")
     }
 
-    /// Write all (username, password) combinations from the provided stream. Passwords are hashed.
-    fn hash_from_stream(&mut self, stream: impl Iterator<Item=(impl AsRef<str>, impl AsRef<str>)>) -> Result<()> {
-        for (username, password) in stream {
-            self.write_user(username, password)?;
+    /// Write all (username, role, password) combinations from the provided stream. Passwords are hashed.
+    fn hash_from_stream(&mut self, stream: impl Iterator<Item=(impl AsRef<str>, Role, impl AsRef<str>)>) -> Result<()> {
+        for (username, role, password) in stream {
+            self.write_user(username, role, password)?;
         }
         Ok(())
     }
@@ -0,0 +1,163 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use actix::Addr;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+use pytf_web::authentication::{Role, SessionUser};
+
+use crate::{
+    job_queue::{HealthCheck, JobServer, ARCHIVE_DIR},
+    session_store::AnySessionStore,
+};
+
+/// How long the job server has to answer before it is considered unresponsive
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// File written to check that the archive directory is writable
+const ARCHIVE_CHECK_FILE: &str = ".readyz";
+
+/// Result of one check
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn new(result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Self { ok: true, detail },
+            Err(detail) => Self { ok: false, detail },
+        }
+    }
+}
+
+/// Results of all checks. The status is "ok" if all checks passed, otherwise "fail".
+/// The individual checks are only included for admins, since their details describe the server.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checks: Option<BTreeMap<&'static str, CheckResult>>,
+}
+
+impl HealthReport {
+    fn response(checks: BTreeMap<&'static str, CheckResult>, user: Option<SessionUser>) -> HttpResponse {
+        let ok = checks.values().all(|check| check.ok);
+        if !ok {
+            log::warn!("Health check failed: {}", serde_json::to_string(&checks).unwrap_or_default());
+        }
+        let detailed = user.is_some_and(|user| user.role.permits(Role::Admin));
+        let report = Self { status: if ok { "ok" } else { "fail" }, checks: detailed.then_some(checks) };
+        if ok {
+            HttpResponse::Ok().json(report)
+        } else {
+            HttpResponse::ServiceUnavailable().json(report)
+        }
+    }
+}
+
+/// Ask the job server how many workers are connected
+async fn check_job_server(srv: &Addr<JobServer>) -> Result<usize, String> {
+    match srv.send(HealthCheck {}).timeout(HEALTH_CHECK_TIMEOUT).await {
+        Ok(workers) => Ok(workers),
+        Err(actix::MailboxError::Timeout) => Err(format!("No response within {}s", HEALTH_CHECK_TIMEOUT.as_secs())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Check that a file can be written to the archive directory
+async fn check_archive() -> Result<String, String> {
+    let dir = ARCHIVE_DIR.get().unwrap().clone();
+    web::block(move || {
+        let path = dir.join(ARCHIVE_CHECK_FILE);
+        std::fs::write(&path, b"")?;
+        std::fs::remove_file(&path)?;
+        Ok::<_, std::io::Error>(format!("{} is writable", dir.to_string_lossy()))
+    }).await
+        .map_err(|e| e.to_string())
+        .and_then(|res| res.map_err(|e| e.to_string()))
+}
+
+/// Liveness: the job server is answering messages
+#[get("/healthz")]
+async fn healthz(user: Option<SessionUser>, srv: web::Data<Addr<JobServer>>) -> HttpResponse {
+    let job_server = check_job_server(&srv).await.map(|_| "Responding".to_string());
+    HealthReport::response(BTreeMap::from([("job_server", CheckResult::new(job_server))]), user)
+}
+
+/// Readiness: the job server is answering, sessions can be stored, jobs can be archived,
+/// and there is a worker to run jobs
+#[get("/readyz")]
+async fn readyz(
+    user: Option<SessionUser>,
+    srv: web::Data<Addr<JobServer>>,
+    session_store: web::Data<AnySessionStore>,
+) -> HttpResponse {
+    let workers = check_job_server(&srv).await;
+    let job_server = workers.clone().map(|_| "Responding".to_string());
+    let workers = workers.and_then(|n| match n {
+        0 => Err("No workers connected".to_string()),
+        n => Ok(format!("{n} worker(s) connected")),
+    });
+    let session_store = session_store.check().await
+        .map(|_| "Reachable".to_string())
+        .map_err(|e| e.to_string());
+    HealthReport::response(BTreeMap::from([
+        ("job_server", CheckResult::new(job_server)),
+        ("session_store", CheckResult::new(session_store)),
+        ("archive", CheckResult::new(check_archive().await)),
+        ("workers", CheckResult::new(workers)),
+    ]), user)
+}
+
+/// Register the health check endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz)
+        .service(readyz);
+}
+
+#[cfg(test)]
+mod test {
+    use actix::Actor;
+    use actix_web::{http::StatusCode, test, App};
+
+    use super::*;
+    use crate::{job_queue::test_archive_dir, session_store::SessionBackend};
+
+    #[actix_web::test]
+    async fn test_health_checks() {
+        test_archive_dir();
+        let srv = JobServer::new().start();
+        let store = AnySessionStore::new(&SessionBackend::Memory, String::new()).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(srv))
+                .app_data(web::Data::new(store))
+                .configure(configure)
+        ).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Not ready without any workers
+        let res = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body, serde_json::json!({ "status": "fail" }));
+
+        // Admins see the individual checks
+        let admin = SessionUser { username: "admin".into(), role: Role::Admin, external: false, session: None };
+        let res = HealthReport::response(BTreeMap::from([
+            ("archive", CheckResult::new(check_archive().await)),
+            ("workers", CheckResult::new(Err("No workers connected".into()))),
+        ]), Some(admin));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "fail");
+        assert_eq!(body["checks"]["archive"]["ok"], true);
+        assert_eq!(body["checks"]["workers"]["ok"], false);
+    }
+}
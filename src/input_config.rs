@@ -6,6 +6,7 @@ use std::{
 use num::Num;
 use serde::{Deserialize, Serialize, ser::SerializeMap};
 use evalexpr::HashMapContext;
+use anyhow::anyhow;
 
 /// An input parameter which can be varied by the user in the protocol section.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
@@ -273,13 +274,45 @@ impl Serialize for ConfigSettings {
 }
 
 impl ConfigSettings {
-    /// Load input configuration from a yaml file.
+    /// Load input configuration from a yaml file, and check it with `validate()`.
     pub fn open(yml_file: impl AsRef<Path>) -> anyhow::Result<Self> {
         let config: ConfigSettings = serde_yaml::from_reader(
             std::fs::OpenOptions::new().read(true).open(yml_file)?)?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Check that range defaults are within their bounds, and that formulas can be
+    /// calculated from the default settings.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        fn check_range<T>(k: &str, range: &ValueRange<T>) -> anyhow::Result<()>
+        where T: Num + PartialOrd + Copy + Default + IsInteger + std::fmt::Display {
+            let below_min = range.min.is_some_and(|min| range.default < min);
+            let above_max = range.max.is_some_and(|max| range.default > max);
+            if below_min || above_max {
+                return Err(anyhow!("Default value {} of {k} is outside its min/max", range.default))
+            }
+            Ok(())
+        }
+        let mut defaults = HashMap::with_capacity(self.settings.len());
+        for (k, v) in &self.settings {
+            match v {
+                ConfigSettingsValue::IntRange(range) => check_range(k, range)?,
+                ConfigSettingsValue::FloatRange(range) => check_range(k, range)?,
+                ConfigSettingsValue::Formula { .. } => continue,
+                ConfigSettingsValue::Literal(_) => (),
+            }
+            defaults.insert(k.clone(), v.get(None)?);
+        }
+        let ctx = build_evalexpr_ctx_from_settings(&defaults);
+        for (k, v) in &self.settings {
+            if let ConfigSettingsValue::Formula { .. } = v {
+                v.get(Some(&ctx)).map_err(|e| anyhow!("Formula for {k} failed: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
     /// Apply sanitization to json values with names corresponding
     /// to keys in `self.settings`. Any missing fields are inserted,
     /// and formulas are calculated.
@@ -393,5 +426,19 @@ mod test {
         let vr: ValueRange<i64> = serde_json::from_str(&json).unwrap();
         assert_eq!(vr.dec_places, Some(0));
     }
+
+    #[test]
+    fn test_validate() {
+        ConfigSettings::open("resources/input_config.yml").unwrap();
+        let valid: ConfigSettings = serde_yaml::from_str(
+            "a:\n  default: 2\n  min: 1\nb: 3\nc:\n  formula: a * b\n").unwrap();
+        valid.validate().unwrap();
+        let out_of_range: ConfigSettings = serde_yaml::from_str(
+            "a:\n  default: 0.5\n  min: 1.0\n").unwrap();
+        assert!(out_of_range.validate().is_err());
+        let bad_formula: ConfigSettings = serde_yaml::from_str(
+            "a: 1\nc:\n  formula: a * missing\n").unwrap();
+        assert!(bad_formula.validate().is_err());
+    }
 }
 
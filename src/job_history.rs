@@ -0,0 +1,311 @@
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use pytf_web::{
+    pytf_config::{PytfConfig, PytfConfigMinimal},
+    to_hex,
+};
+
+/// Status of a job once the server stopped holding it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinalStatus {
+    Finished,
+    /// Stopped part way through. Re-attaching resumes it.
+    Paused,
+    Failed,
+    /// Abandoned before it started running
+    Cancelled,
+}
+
+impl Display for FinalStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Finished  => "Finished",
+            Self::Paused    => "Paused",
+            Self::Failed    => "Failed",
+            Self::Cancelled => "Cancelled",
+        })
+    }
+}
+
+/// A job in a user's history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Configuration as submitted by the user
+    pub submitted: PytfConfigMinimal,
+    /// Full configuration of the job, which names its archive
+    pub config: PytfConfig,
+    /// Unix time the job was first requested
+    pub created: u64,
+    /// Unix time the job was most recently requested
+    pub last_requested: u64,
+    /// Status when the job was last removed from the server, or `None` while it's held
+    pub status: Option<FinalStatus>,
+}
+
+impl HistoryEntry {
+    pub fn new(submitted: PytfConfigMinimal, config: PytfConfig) -> Self {
+        let now = unix_now();
+        Self { submitted, config, created: now, last_requested: now, status: None }
+    }
+
+    /// The job has been requested again, so is held by the server
+    pub fn requested_again(&mut self) {
+        self.last_requested = unix_now();
+        self.status = None;
+    }
+}
+
+/// Number of recently requested jobs to remember for each user
+const MAX_USER_JOBS: usize = 100;
+
+/// Add a request for a job to a history (oldest first), moving the job to the end if it's
+/// already there, and dropping the oldest job if there are more than `MAX_USER_JOBS`
+pub fn record_request(entries: &mut Vec<HistoryEntry>, config: &PytfConfig, submitted: &PytfConfigMinimal) {
+    let entry = match entries.iter().position(|e| e.config.name == config.name) {
+        Some(idx) => {
+            let mut entry = entries.remove(idx);
+            entry.requested_again();
+            entry
+        }
+        None => HistoryEntry::new(submitted.clone(), config.clone()),
+    };
+    if entries.len() >= MAX_USER_JOBS {
+        entries.remove(0);
+    }
+    entries.push(entry);
+}
+
+/// Set the status of jobs which the server no longer holds. Returns whether any were in the history.
+pub fn record_final_statuses(entries: &mut [HistoryEntry], statuses: &[(String, FinalStatus)]) -> bool {
+    let mut changed = false;
+    for entry in entries.iter_mut() {
+        if let Some((_, status)) = statuses.iter().find(|(name, _)| *name == entry.config.name) {
+            entry.status = Some(*status);
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Job histories of each user, stored as one JSON file per user.
+/// Runs as a sync actor, so that the job server doesn't wait on the disk,
+/// and changes are written in the order they were sent.
+#[derive(Debug, Clone)]
+pub struct JobHistory {
+    dir: PathBuf,
+}
+
+impl JobHistory {
+    pub fn new(dir: PathBuf) -> Self {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            log::warn!("Failed to create job history directory with error \"{e}\". Job histories will not be saved!");
+        }
+        Self { dir }
+    }
+
+    /// File for a user's history. Usernames are hex encoded so that any name is a valid file name.
+    fn file(&self, user: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", to_hex(user.as_bytes())))
+    }
+
+    /// Read a user's history, oldest first. Returns an empty history if the user has none.
+    pub fn load(&self, user: &str) -> Vec<HistoryEntry> {
+        let contents = match std::fs::read_to_string(self.file(user)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                log::warn!("Failed to read job history of \"{user}\": {e}");
+                return Vec::new()
+            }
+        };
+        serde_json::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!("Failed to parse job history of \"{user}\": {e}");
+            Vec::new()
+        })
+    }
+
+    /// Replace a user's history. The file is replaced in one step, so it is never left partially written.
+    pub fn save(&self, user: &str, entries: &[HistoryEntry]) {
+        let file = self.file(user);
+        let tmp = file.with_extension("json.tmp");
+        let result = serde_json::to_vec(entries)
+            .map_err(std::io::Error::from)
+            .and_then(|contents| std::fs::write(&tmp, contents))
+            .and_then(|_| std::fs::rename(&tmp, &file));
+        if let Err(e) = result {
+            log::warn!("Failed to save job history of \"{user}\": {e}");
+        }
+    }
+
+    /// Find an entry matching `f` in the saved histories of all users
+    pub fn find_entry(&self, f: impl Fn(&HistoryEntry) -> bool) -> Option<HistoryEntry> {
+        let files = match std::fs::read_dir(&self.dir) {
+            Ok(files) => files,
+            Err(e) => {
+                log::warn!("Failed to list job histories: {e}");
+                return None
+            }
+        };
+        files.filter_map(Result::ok)
+            .map(|file| file.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .find_map(|path| {
+                let entries: Vec<HistoryEntry> = std::fs::read_to_string(&path).ok()
+                    .and_then(|contents| serde_json::from_str(&contents).ok())
+                    .unwrap_or_default();
+                entries.into_iter().find(&f)
+            })
+    }
+
+    /// Delete a user's history
+    pub fn remove(&self, user: &str) {
+        match std::fs::remove_file(self.file(user)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => log::warn!("Failed to remove job history of \"{user}\": {e}"),
+            _ => (),
+        }
+    }
+}
+
+impl Actor for JobHistory {
+    type Context = SyncContext<Self>;
+}
+
+/// Read a user's history
+#[derive(Message)]
+#[rtype(result = "Vec<HistoryEntry>")]
+pub struct LoadHistory {
+    pub user: Arc<String>,
+}
+
+/// Record that a user requested a job, returning their updated history
+#[derive(Message)]
+#[rtype(result = "Vec<HistoryEntry>")]
+pub struct RecordRequest {
+    pub user: Arc<String>,
+    pub config: PytfConfig,
+    pub submitted: PytfConfigMinimal,
+}
+
+/// Record the status of jobs the server no longer holds in a user's history,
+/// returning their updated history
+#[derive(Message)]
+#[rtype(result = "Vec<HistoryEntry>")]
+pub struct RecordFinalStatuses {
+    pub user: Arc<String>,
+    pub statuses: Vec<(String, FinalStatus)>,
+}
+
+/// Delete a user's history
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RemoveHistory {
+    pub user: String,
+}
+
+/// Find an entry in the saved histories of all users
+#[derive(Message)]
+#[rtype(result = "Option<HistoryEntry>")]
+pub struct FindInHistories {
+    pub matches: Box<dyn Fn(&HistoryEntry) -> bool + Send>,
+}
+
+impl Handler<LoadHistory> for JobHistory {
+    type Result = MessageResult<LoadHistory>;
+
+    fn handle(&mut self, msg: LoadHistory, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.load(&msg.user))
+    }
+}
+
+impl Handler<RecordRequest> for JobHistory {
+    type Result = MessageResult<RecordRequest>;
+
+    fn handle(&mut self, msg: RecordRequest, _ctx: &mut Self::Context) -> Self::Result {
+        let mut entries = self.load(&msg.user);
+        record_request(&mut entries, &msg.config, &msg.submitted);
+        self.save(&msg.user, &entries);
+        MessageResult(entries)
+    }
+}
+
+impl Handler<RecordFinalStatuses> for JobHistory {
+    type Result = MessageResult<RecordFinalStatuses>;
+
+    fn handle(&mut self, msg: RecordFinalStatuses, _ctx: &mut Self::Context) -> Self::Result {
+        let mut entries = self.load(&msg.user);
+        if record_final_statuses(&mut entries, &msg.statuses) {
+            self.save(&msg.user, &entries);
+        }
+        MessageResult(entries)
+    }
+}
+
+impl Handler<RemoveHistory> for JobHistory {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveHistory, _ctx: &mut Self::Context) -> Self::Result {
+        self.remove(&msg.user);
+    }
+}
+
+impl Handler<FindInHistories> for JobHistory {
+    type Result = Option<HistoryEntry>;
+
+    fn handle(&mut self, msg: FindInHistories, _ctx: &mut Self::Context) -> Self::Result {
+        self.find_entry(msg.matches)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let dir = std::env::temp_dir().join(format!("pytf-history-{}", std::process::id()));
+        let history = JobHistory::new(dir.clone());
+        assert!(history.load("student").is_empty());
+
+        let mut entry = HistoryEntry::new(PytfConfigMinimal::default(), PytfConfig::default());
+        entry.status = Some(FinalStatus::Paused);
+        history.save("student", &[entry.clone()]);
+        history.save("../student", &[]);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        let loaded = history.load("student");
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].config.name, entry.config.name);
+        assert_eq!(loaded[0].created, entry.created);
+        assert_eq!(loaded[0].status, Some(FinalStatus::Paused));
+        assert!(history.load("../student").is_empty());
+        assert!(history.find_entry(|e| e.config.name == entry.config.name).is_some());
+        assert!(history.find_entry(|e| e.config.name == "other").is_none());
+
+        let mut entries = loaded;
+        let other = PytfConfig { name: "other".into(), ..PytfConfig::default() };
+        record_request(&mut entries, &other, &PytfConfigMinimal::default());
+        record_request(&mut entries, &entry.config, &PytfConfigMinimal::default());
+        assert_eq!(entries.iter().map(|e| e.config.name.as_str()).collect::<Vec<_>>(), ["other", entry.config.name.as_str()]);
+        assert_eq!(entries[1].status, None);
+        assert!(record_final_statuses(&mut entries, &[("other".into(), FinalStatus::Failed)]));
+        assert_eq!(entries[0].status, Some(FinalStatus::Failed));
+        assert!(!record_final_statuses(&mut entries, &[("missing".into(), FinalStatus::Failed)]));
+
+        history.remove("student");
+        assert!(history.load("student").is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,36 +1,52 @@
 use std::{
     sync::{Arc, RwLock, atomic::{AtomicBool, Ordering}, OnceLock},
     collections::HashMap,
-    time::{Duration, Instant},
+    time::Instant,
     io::{BufReader, BufWriter, Read, Write}, path::PathBuf, fmt::Display
 };
 use actix::prelude::*;
 use actix_web::web::Bytes;
+use serde::Serialize;
 use pytf_web::{
-    pytf_config::PytfConfig,
+    authentication::Role,
+    pytf_config::{PytfConfig, PytfConfigMinimal},
     pytf_frame::TrajectorySegment
 };
 
 use crate::{
-    client_session::{ClientWsSession, ClientForceDisconnect, TrajectoryPing},
-    worker_session::{WorkerWsSession, WorkerPause, WorkerIdle}
+    client_session::{ClientWsSession, ClientEndSession, ClientForceDisconnect, ClientCatalogueChanged, TrajectoryPing},
+    job_history::{
+        self, FinalStatus, FindInHistories, HistoryEntry, JobHistory, LoadHistory, RecordFinalStatuses,
+        RecordRequest, RemoveHistory,
+    },
+    metrics::{Metrics, METRICS},
+    quotas::{Quotas, UserLoad},
+    scheduler::{Candidate, Scheduler, Usage},
+    server_config::config,
+    worker_session::{WorkerWsSession, WorkerPause, WorkerIdle, WorkerForceDisconnect}
 };
 
-/// How frequently to check for jobs to archive.
-/// Doubles as age at which jobs are eligible for archiving.
-/// TODO: make this configurable
-const JOB_CLEANUP_INTERVAL: Duration = Duration::from_secs(150);
-const MAX_JOB_AGE: Duration = Duration::from_secs(300);
-
 /// Directory to store archived jobs.
 pub static ARCHIVE_DIR: OnceLock<PathBuf> = OnceLock::new();
 
+/// Set `ARCHIVE_DIR` for tests. Tests in the same binary share it, so they
+/// must use distinct job names and only remove the files they created.
+#[cfg(test)]
+pub fn test_archive_dir() -> &'static PathBuf {
+    let dir = ARCHIVE_DIR.get_or_init(|| std::env::temp_dir().join(format!("pytf-test-{}", std::process::id())));
+    std::fs::create_dir_all(dir).unwrap();
+    dir
+}
+
 // Client
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientConnect {
     pub id: Arc<String>,
+    pub role: Role,
     pub addr: Addr<ClientWsSession>,
+    /// Id of the login session the client connected with, if any
+    pub session: Option<String>,
 }
 
 #[derive(Message)]
@@ -43,6 +59,8 @@ pub struct ClientDisconnect {
 #[rtype(result = "AcceptedJob")]
 pub struct ClientReqJob {
     pub config: PytfConfig,
+    /// Config as submitted by the client, to keep in the user's history
+    pub submitted: PytfConfigMinimal,
     pub client_id: Arc<String>,
     pub client_addr: Addr<ClientWsSession>,
     pub client_prev_job: Option<Job>,
@@ -54,6 +72,7 @@ pub struct ClientReqJob {
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct WorkerConnect {
+    pub name: Arc<String>,
     pub addr: Addr<WorkerWsSession>,
 }
 
@@ -63,9 +82,97 @@ pub struct WorkerDisconnect {
     pub addr: Addr<WorkerWsSession>,
 }
 
+/// A user (or worker) has been disabled, removed or revoked, so disconnect any sessions it has open
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct UserDeactivated {
+    pub name: String,
+    /// The user won't be back (e.g. removed, or an expired guest), so delete their job history too
+    pub removed: bool,
+}
+
+/// The molecules or input configuration have been reloaded, so tell connected clients.
+/// Returns the number of clients notified.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct CatalogueChanged {}
+
+/// Some of a user's login sessions have been revoked, so disconnect
+/// the client if it connected with one of them
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct SessionsRevoked {
+    pub name: String,
+    pub sessions: Vec<String>,
+}
+
+/// List the jobs a user has requested, most recent first
+#[derive(Message)]
+#[rtype(result = "Vec<UserJob>")]
+pub struct ListUserJobs {
+    pub name: String,
+}
+
+/// Look up a job in a user's history, to re-attach to it
+#[derive(Message)]
+#[rtype(result = "Option<HistoryEntry>")]
+pub struct FindHistoryEntry {
+    pub user: String,
+    pub jobname: String,
+}
+
+/// Look up one of a user's jobs by name.
+/// With `any_user`, jobs requested by other users can be found too.
+#[derive(Message)]
+#[rtype(result = "Option<JobRecord>")]
+pub struct FindJob {
+    pub user: String,
+    pub jobname: String,
+    pub any_user: bool,
+}
+
+/// Check whether any job held by the server or in a user's history, which may still
+/// be resumed, uses a molecule
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct MoleculeInUse {
+    pub res_name: String,
+}
+
+/// Where to find a job requested by a user
+#[derive(Debug, Clone)]
+pub enum JobRecord {
+    /// Job is still held by the server
+    Active(Job),
+    /// Job has been removed from the server, and can be loaded from the archive if it was saved
+    Archived(PytfConfig),
+}
+
+/// A job in a user's history
+#[derive(Debug, Clone)]
+pub struct UserJob {
+    pub entry: HistoryEntry,
+    pub record: JobRecord,
+}
+
+/// Jobs requested by a user, oldest first
+#[derive(Debug, Default)]
+struct UserJobs {
+    entries: Vec<HistoryEntry>,
+    /// Whether to save the history to disk. Guests' histories are only kept in memory.
+    persist: bool,
+    /// Whether `entries` holds the whole history, rather than just requests made
+    /// while it was being read from disk
+    loaded: bool,
+    /// Number of the latest reply from the history actor applied to `entries`
+    seq: u64,
+}
+
 pub struct ClientDetails {
     addr: Addr<ClientWsSession>,
+    role: Role,
     job: Option<Job>,
+    session: Option<String>,
 }
 
 
@@ -83,12 +190,14 @@ pub struct AssignJobs {}
 
 #[derive(Debug, Clone)]
 struct WorkerHandle {
+    /// Username the worker logged in with
+    name: Arc<String>,
     addr: Addr<WorkerWsSession>,
     idle: Arc<AtomicBool>,
 }
 impl WorkerHandle {
-    pub fn new(addr: Addr<WorkerWsSession>) -> Self {
-        Self { addr, idle: Arc::new(AtomicBool::new(true)) }
+    pub fn new(name: Arc<String>, addr: Addr<WorkerWsSession>) -> Self {
+        Self { name, addr, idle: Arc::new(AtomicBool::new(true)) }
     }
 }
 
@@ -107,11 +216,31 @@ pub struct JobServer {
 
     /// List of unfinished jobs - candidates for work requests
     unfinished_jobs: Vec<Job>,
+
+    /// Histories of the jobs requested by connected users and guests, kept in step with `history`.
+    /// Other users' histories are read from disk when needed.
+    user_jobs: HashMap<Arc<String>, UserJobs>,
+
+    /// Users who requested each job held by the server, whose histories get its final status
+    requesters: HashMap<String, Vec<Arc<String>>>,
+
+    /// Persisted job histories
+    history: Addr<JobHistory>,
+
+    /// Number of the latest change sent to `history`
+    history_seq: u64,
+
+    /// Chooses which unfinished jobs run next
+    scheduler: Scheduler,
+
+    /// Limits on the jobs each user can start
+    quotas: Quotas,
 }
 
 impl JobServer {
     pub fn new() -> Self {
-        if let Err(e) = std::fs::create_dir_all(ARCHIVE_DIR.get().unwrap()) {
+        let archive_dir = ARCHIVE_DIR.get().unwrap();
+        if let Err(e) = std::fs::create_dir_all(archive_dir) {
             log::warn!("Failed to create archive directory with error \"{e}\". Old jobs will not be archived!");
         }
         let mut job_lookup = HashMap::with_capacity(128);
@@ -123,6 +252,138 @@ impl JobServer {
             worker_sessions: Vec::with_capacity(64),
             job_lookup,
             unfinished_jobs: Vec::with_capacity(64),
+            user_jobs: HashMap::with_capacity(64),
+            requesters: HashMap::with_capacity(64),
+            history: {
+                let history = JobHistory::new(archive_dir.join("history"));
+                SyncArbiter::start(1, move || history.clone())
+            },
+            history_seq: 0,
+            scheduler: Scheduler::new(config().jobs.scheduler, config().jobs.instructor_priority),
+            quotas: Quotas::new(config().quotas.clone()),
+        }
+    }
+
+    /// Send a change to `user`'s saved history, and keep their history in memory up to date
+    /// with the result while they're connected
+    fn update_history<M>(&mut self, user: Arc<String>, msg: M, ctx: &mut <Self as Actor>::Context)
+    where
+        M: Message<Result = Vec<HistoryEntry>> + Send + 'static,
+        JobHistory: Handler<M>,
+    {
+        self.history_seq += 1;
+        let seq = self.history_seq;
+        self.history.send(msg)
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let Ok(entries) = res else { return };
+                if !act.client_sessions.contains_key(&user) { return }
+                let jobs = act.user_jobs.entry(user).or_default();
+                // Replies can arrive out of order, and only the latest is up to date
+                if jobs.seq < seq {
+                    *jobs = UserJobs { entries, persist: true, loaded: true, seq };
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// History of `user`, from memory if it's held, or else read from disk without keeping it
+    fn user_history(&self, user: String) -> ResponseActFuture<Self, Vec<HistoryEntry>> {
+        if let Some(jobs) = self.user_jobs.get(&user).filter(|jobs| jobs.loaded) {
+            return Box::pin(fut::ready(jobs.entries.clone()))
+        }
+        Box::pin(self.history.send(LoadHistory { user: Arc::new(user) })
+            .into_actor(self)
+            .map(|res, _act, _ctx| res.unwrap_or_default()))
+    }
+
+    /// Remember that `user` requested a job, so they can look it up later
+    fn record_user_job(&mut self, user: &Arc<String>, config: &PytfConfig, submitted: &PytfConfigMinimal, ctx: &mut <Self as Actor>::Context) {
+        let requesters = self.requesters.entry(config.name.clone()).or_default();
+        if !requesters.contains(user) {
+            requesters.push(user.clone());
+        }
+        let persist = self.client_sessions.get(user).is_some_and(|c| c.role != Role::Guest);
+        if let Some(jobs) = self.user_jobs.get_mut(user) {
+            job_history::record_request(&mut jobs.entries, config, submitted);
+        }
+        if persist {
+            let msg = RecordRequest { user: user.clone(), config: config.clone(), submitted: submitted.clone() };
+            self.update_history(user.clone(), msg, ctx);
+        }
+    }
+
+    /// Record the status of jobs which the server no longer holds in the histories of
+    /// the users who requested them
+    fn record_final_statuses(&mut self, removed: Vec<(String, FinalStatus)>, ctx: &mut <Self as Actor>::Context) {
+        let mut statuses: HashMap<Arc<String>, Vec<(String, FinalStatus)>> = HashMap::new();
+        for (name, status) in removed {
+            for user in self.requesters.remove(&name).unwrap_or_default() {
+                statuses.entry(user).or_default().push((name.clone(), status));
+            }
+        }
+        // Jobs can also be removed without a final status
+        let job_lookup = &self.job_lookup;
+        self.requesters.retain(|name, _| job_lookup.contains_key(name));
+        for (user, statuses) in statuses {
+            let persist = match self.user_jobs.get_mut(&user) {
+                Some(jobs) => {
+                    job_history::record_final_statuses(&mut jobs.entries, &statuses);
+                    jobs.persist
+                }
+                // Guests' histories are always held, so this user's is on disk
+                None => true,
+            };
+            if persist {
+                self.update_history(user.clone(), RecordFinalStatuses { user, statuses }, ctx);
+            }
+        }
+    }
+
+    /// Check `user`'s quotas before they start or resume job `jobname` with `cycles` cycles
+    /// to run, counting the submission if it's allowed. Returns the reason if not allowed.
+    /// Check whether `user` may run `cycles` more cycles of `jobname`, counting it as a submission if so.
+    /// Only the user's jobs which are on a worker or still watched by someone other than the
+    /// requesting `client` count as active.
+    fn check_quotas(&mut self, user: &Arc<String>, client: &Addr<ClientWsSession>, jobname: &str, cycles: usize) -> Result<(), String> {
+        if self.client_sessions.get(user).is_some_and(|c| c.role.permits(Role::Instructor)) {
+            return Ok(())
+        }
+        let mut load = UserLoad::default();
+        for job in self.requesters.iter()
+            .filter(|(name, users)| *name != jobname && users.contains(user))
+            .filter_map(|(name, _)| self.job_lookup.get(name))
+        {
+            let job = job.read().unwrap();
+            let on_worker = matches!(job.status, JobStatus::Running(_) | JobStatus::Stealing(..));
+            let watched = job.clients.iter().any(|c| c != client)
+                && !matches!(job.status, JobStatus::Finished | JobStatus::Failed | JobStatus::Archived);
+            if on_worker || watched {
+                load.active_jobs += 1;
+                load.queued_cycles += job.segments.len().saturating_sub(job.latest_segment);
+            }
+        }
+        let now = Instant::now();
+        self.quotas.check(user, load, cycles, now)?;
+        self.quotas.record(user, now);
+        Ok(())
+    }
+
+    /// Check the quotas of `user` for a request which would run `cycles` cycles of `jobname`.
+    /// Requests which don't start (or resume) running the job pass `None` and are always allowed.
+    fn check_request(&mut self, user: &Arc<String>, client: &Addr<ClientWsSession>, jobname: &str, cycles: Option<usize>) -> Result<(), String> {
+        let Some(cycles) = cycles else { return Ok(()) };
+        self.check_quotas(user, client, jobname, cycles).map_err(|reason| {
+            log::info!("Denied job {jobname} for {user}: {reason}");
+            Metrics::inc(&METRICS.jobs_denied);
+            reason
+        })
+    }
+
+    fn job_record(&self, config: &PytfConfig) -> JobRecord {
+        match self.job_lookup.get(&config.name) {
+            Some(job) => JobRecord::Active(job.clone()),
+            None => JobRecord::Archived(config.clone()),
         }
     }
 
@@ -135,22 +396,23 @@ impl JobServer {
 
     fn send_job_to_worker(&self, job: JobAssignment, worker: &WorkerHandle, ctx: &mut <Self as Actor>::Context) {
         let idle = worker.idle.clone();
+        let name = worker.name.clone();
         let job_handle = job.job.clone();
         worker.addr.send(job)
             .into_actor(self)
             .then(move | res, _act, _ctx| {
                 match res {
                     Ok(true) => {
-                        log::debug!("Sent new job to worker session");
+                        log::debug!("Sent new job to worker session {name}");
                         let job = job_handle.read().unwrap();
                         job.notify_clients_no_timestamp();
                     }
                     Ok(false) => {
-                        log::warn!("Worker failed to take job");
+                        log::warn!("Worker {name} failed to take job");
                         idle.store(true, Ordering::Release);
                     }
                     Err(e) => {
-                        log::error!("Error while sending job assignment: {e}.");
+                        log::error!("Error while sending job assignment to worker {name}: {e}.");
                         idle.store(true, Ordering::Release); // There's a problem with the worker
                     }
                 }
@@ -160,39 +422,82 @@ impl JobServer {
     }
 
     fn start_cleanup_timer(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(JOB_CLEANUP_INTERVAL, |act, _ctx| {
-            act.cleanup_jobs(Instant::now());
+        ctx.run_interval(config().jobs.cleanup_interval(), |act, ctx| {
+            act.cleanup_jobs(Instant::now(), ctx);
         });
     }
 
-    fn cleanup_jobs(&mut self, now: Instant) {
+    fn cleanup_jobs(&mut self, now: Instant, ctx: &mut <Self as Actor>::Context) {
+        let mut removed = Vec::new();
         self.unfinished_jobs.retain(|job| {
             if let Ok(mut job_lock) = job.try_write() {
+                let status = final_status(&job_lock.status);
                 let retain = job_lock.archive_if_ready(&now);
-                if !retain {
-                    self.job_lookup.remove(&job_lock.config.name);
+                if !retain && self.job_lookup.remove(&job_lock.config.name).is_some() {
+                    removed.extend(status.map(|s| (job_lock.config.name.clone(), s)));
                 }
                 retain
             } else { true }
         });
         self.job_lookup.retain(|_jobname, job| {
             if let Ok(mut job_lock) = job.try_write() {
-                job_lock.archive_if_ready(&now)
+                let status = final_status(&job_lock.status);
+                let retain = job_lock.archive_if_ready(&now);
+                if !retain {
+                    removed.extend(status.map(|s| (job_lock.config.name.clone(), s)));
+                }
+                retain
             } else { true }
-        })
+        });
+        self.record_final_statuses(removed, ctx);
+    }
+
+    /// Choose up to `n` runnable jobs to assign to workers, in the order they should be assigned
+    fn next_jobs(&self, n: usize) -> Vec<Job> {
+        if n == 0 { return Vec::new() }
+        let mut usage = Usage::new();
+        let mut jobs = Vec::new();
+        let mut candidates = Vec::new();
+        for job in self.unfinished_jobs.iter() {
+            let Ok(job_lock) = job.try_read() else { continue };
+            let job_users = job_lock.clients.iter().filter_map(|addr| {
+                self.client_sessions.iter().find(|(_, client)| client.addr == *addr)
+            });
+            match job_lock.status {
+                JobStatus::Running(_) | JobStatus::Stealing(..) => {
+                    for (user, _) in job_users {
+                        *usage.entry(user.clone()).or_default() += 1;
+                    }
+                }
+                _ if job_lock.is_runnable() => {
+                    candidates.push(Candidate {
+                        created: job_lock.created,
+                        clients: job_lock.clients.len(),
+                        users: job_users.clone().map(|(user, _)| user.clone()).collect(),
+                        instructor: job_users.clone().any(|(_, client)| client.role.permits(Role::Instructor)),
+                    });
+                    jobs.push(job.clone());
+                }
+                _ => (),
+            }
+        }
+        self.scheduler.choose(&candidates, &mut usage, n, Instant::now())
+            .into_iter()
+            .map(|idx| jobs[idx].clone())
+            .collect()
     }
 
     fn assign_jobs(&mut self, ctx: &mut <Self as Actor>::Context) {
         log::debug!("Assigning unallocated jobs");
-        let unassigned_jobs = self.unfinished_jobs.iter().filter(|job| is_job_runnable(job));
+        let idle_workers: Vec<&WorkerHandle> = self.worker_sessions.iter()
+            .filter(|w| w.idle.load(Ordering::Acquire))
+            .collect();
+        let jobs = self.next_jobs(idle_workers.len());
 
-        let mut count = 0;
-        for (job, worker) in unassigned_jobs.zip(
-            self.worker_sessions.iter().filter(|w| w.idle.load(Ordering::Acquire))
-        ) {
+        let count = jobs.len();
+        for (job, worker) in jobs.into_iter().zip(idle_workers) {
             worker.idle.store(false, Ordering::Release);
-            self.send_job_to_worker(JobAssignment { job: job.clone(), }, worker, ctx);
-            count += 1;
+            self.send_job_to_worker(JobAssignment { job }, worker, ctx);
         }
         log::info!("Assigned {count} jobs");
     }
@@ -206,20 +511,27 @@ impl Actor for JobServer {
         self.start_cleanup_timer(ctx);
     }
 
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
+    fn stopped(&mut self, ctx: &mut Self::Context) {
         log::debug!("Triggering final cleanup.");
-        self.cleanup_jobs(Instant::now() + 2*MAX_JOB_AGE);
+        // Changes to histories are still sent, though the replies won't be handled
+        self.cleanup_jobs(Instant::now() + 2*config().jobs.max_age(), ctx);
     }
 }
 
 impl Handler<ClientConnect> for JobServer {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientConnect, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ClientConnect, ctx: &mut Self::Context) -> Self::Result {
         log::info!("Client {} connected", msg.id);
+        // Hold the user's history while they're connected. Guests' histories are only kept in memory.
+        if msg.role == Role::Guest {
+            self.user_jobs.entry(msg.id.clone()).or_insert_with(|| UserJobs { loaded: true, ..Default::default() });
+        } else if !self.user_jobs.contains_key(&msg.id) {
+            self.update_history(msg.id.clone(), LoadHistory { user: msg.id.clone() }, ctx);
+        }
 
         if let Some(old_session) = self.client_sessions.insert(
-            msg.id.clone(), ClientDetails { addr: msg.addr, job: None })
+            msg.id.clone(), ClientDetails { addr: msg.addr, role: msg.role, job: None, session: msg.session })
         {
             // Client started a new session before a previous one was closed
             // Remove interest from any previous job
@@ -236,7 +548,11 @@ impl Handler<ClientDisconnect> for JobServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientDisconnect, _ctx: &mut Self::Context) -> Self::Result {
-        self.client_sessions.remove(&msg.id);
+        if let Some(client) = self.client_sessions.remove(&msg.id) {
+            if client.role != Role::Guest {
+                self.user_jobs.remove(&msg.id);
+            }
+        }
     }
 }
 
@@ -247,9 +563,9 @@ impl Handler<WorkerIdle> for JobServer {
     fn handle(&mut self, msg: WorkerIdle, ctx: &mut Self::Context) -> Self::Result {
         for w in self.worker_sessions.iter() {
             if w.addr == msg.addr {
-                if let Some(job) = self.unfinished_jobs.iter().filter(|job| is_job_runnable(job)).next() {
-                    log::info!("Assigning new job to finished worker");
-                    self.send_job_to_worker(JobAssignment { job: job.clone(), }, w, ctx);
+                if let Some(job) = self.next_jobs(1).pop() {
+                    log::info!("Assigning new job to finished worker {}", w.name);
+                    self.send_job_to_worker(JobAssignment { job }, w, ctx);
                 } else {
                     w.idle.store(true, Ordering::Release);
                 }
@@ -259,13 +575,20 @@ impl Handler<WorkerIdle> for JobServer {
     }
 }
 
-fn is_job_runnable(job: &Job) -> bool {
-    if let Ok(job) = job.try_read() {
-        return (job.status == JobStatus::Waiting
-        || matches!(job.status, JobStatus::Steal(_))
-    ) && !job.clients.is_empty()
+/// Status to record in users' histories when a job is removed from the server
+fn final_status(status: &JobStatus) -> Option<FinalStatus> {
+    match status {
+        JobStatus::Finished => Some(FinalStatus::Finished),
+        JobStatus::Steal(_) | JobStatus::Stealing(..) | JobStatus::Paused(_) | JobStatus::Running(_)
+            => Some(FinalStatus::Paused),
+        JobStatus::Failed => Some(FinalStatus::Failed),
+        JobStatus::Waiting => Some(FinalStatus::Cancelled),
+        JobStatus::Archived => None,
     }
-    false
+}
+
+fn is_job_runnable(job: &Job) -> bool {
+    job.try_read().is_ok_and(|job| job.is_runnable())
 }
 
 impl Handler<AssignJobs> for JobServer {
@@ -277,29 +600,42 @@ impl Handler<AssignJobs> for JobServer {
 }
 
 pub enum AcceptedJob {
-    /// Creating a new job
+    /// Server doesn't have the job, so the client should create (or load) it and send `RegisterJob`
     New,
     /// Attaching to an existing job
     Existing(Job),
     /// Attaching to a finished job
     Finished(Job),
-    /// Job exists, but has failed
-    Failed,
+    /// Job exists, but has failed on the specified worker
+    Failed(Option<Arc<String>>),
+    /// Job would take the user over one of their quotas, for the specified reason
+    Denied(String),
 }
 
 impl Handler<ClientReqJob> for JobServer {
     type Result = MessageResult<ClientReqJob>;
 
-    fn handle(&mut self, msg: ClientReqJob, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ClientReqJob, ctx: &mut Self::Context) -> Self::Result {
         // Check whether job already exists.
         // Keep job_lookup locked while we work with it to avoid races
         // (i.e. we can only add one new job at a time)
         let jobname = msg.config.name.clone();
         let existing = self.job_lookup.get(&jobname).and_then(|j| Some(j.clone()));
+
+        // Jobs the server doesn't have may be finished in the archive, so their
+        // quotas are checked by RegisterJob once they've been loaded
+        if let Some(job) = &existing {
+            let cycles = job.read().unwrap().requested_cycles();
+            if let Err(reason) = self.check_request(&msg.client_id, &msg.client_addr, &jobname, cycles) {
+                return MessageResult(AcceptedJob::Denied(reason))
+            }
+        }
+
         if let Some(job) = existing {
+            self.record_user_job(&msg.client_id, &msg.config, &msg.submitted, ctx);
             // Attach client to job.
             let mut job_lock = job.write().unwrap();
-            log::info!("Job with name {} already exists.", job_lock.config.name);
+            log::info!("Job with name {} already exists. Status: {}", job_lock.config.name, job_lock.describe_status());
 
             // If client wasn't already attached to that job, remove them from their old job
             if !job_lock.clients.contains(&msg.client_addr) {
@@ -310,7 +646,7 @@ impl Handler<ClientReqJob> for JobServer {
                     drop(job_lock);
                     MessageResult(AcceptedJob::Finished(job))
                 },
-                JobStatus::Failed => MessageResult(AcceptedJob::Failed),
+                JobStatus::Failed => MessageResult(AcceptedJob::Failed(job_lock.worker.clone())),
                 _ => {
                     drop(job_lock);
                     MessageResult(AcceptedJob::Existing(job))
@@ -322,20 +658,34 @@ impl Handler<ClientReqJob> for JobServer {
     }
 }
 
+/// Register a job created (or loaded from the archive) by a client session after
+/// `ClientReqJob` returned `AcceptedJob::New`. Returns the reason if the job would
+/// take the user over one of their quotas.
 #[derive(Message)]
-#[rtype(result="Job")]
+#[rtype(result="Result<Job, String>")]
 pub struct RegisterJob {
     pub client: Addr<ClientWsSession>,
+    pub client_id: Arc<String>,
+    /// Config as sent by the client
+    pub submitted: PytfConfigMinimal,
     pub job: JobInner,
 }
 impl Handler<RegisterJob> for JobServer {
-    type Result = Job;
+    type Result = Result<Job, String>;
     fn handle(&mut self, msg: RegisterJob, ctx: &mut Self::Context) -> Self::Result {
         let jobname = msg.job.config.name.clone();
-        if let Some(job) = self.job_lookup.get(&jobname) {
-            // Job may have been registered by another client while loading
+        // Job may have been registered by another client while loading
+        let existing = self.job_lookup.get(&jobname).cloned();
+        let cycles = match &existing {
+            Some(job) => job.read().unwrap().requested_cycles(),
+            None => msg.job.requested_cycles(),
+        };
+        self.check_request(&msg.client_id, &msg.client, &jobname, cycles)?;
+
+        self.record_user_job(&msg.client_id, &msg.job.config, &msg.submitted, ctx);
+        if let Some(job) = existing {
             job.write().unwrap().clients.push(msg.client);
-            job.clone()
+            Ok(job)
         } else {
             let mut job = msg.job;
             let finished = job.status == JobStatus::Finished;
@@ -345,7 +695,7 @@ impl Handler<RegisterJob> for JobServer {
             self.job_lookup.insert(jobname, job.clone());
             if !finished { self.unfinished_jobs.push(job.clone()); }
             self.assign_jobs(ctx);
-            job
+            Ok(job)
         }
     }
 }
@@ -355,15 +705,16 @@ impl Handler<WorkerConnect> for JobServer {
     type Result = ();
 
     fn handle(&mut self, msg: WorkerConnect, ctx: &mut Self::Context) -> Self::Result {
-        log::info!("New worker connected");
-        self.worker_sessions.push(WorkerHandle::new(msg.addr.clone()));
-        if let Some(job) = self.unfinished_jobs.iter().filter(|job| is_job_runnable(job)).next() {
-            log::info!("Assigning job to new worker");
-            self.send_job_to_worker(
-                JobAssignment { job: job.clone(), },
-                self.worker_sessions.last().unwrap(),
-                ctx
-            );
+        log::info!("New worker connected: {}", msg.name);
+        if self.worker_sessions.iter().any(|w| w.name == msg.name) {
+            log::warn!("Worker {} already has a session open. Is the same key in use by multiple workers?", msg.name);
+        }
+        self.worker_sessions.push(WorkerHandle::new(msg.name, msg.addr.clone()));
+        if let Some(job) = self.next_jobs(1).pop() {
+            let worker = self.worker_sessions.last().unwrap();
+            log::info!("Assigning job to new worker {}", worker.name);
+            worker.idle.store(false, Ordering::Release);
+            self.send_job_to_worker(JobAssignment { job }, worker, ctx);
         }
         log::debug!("Currently have {} workers, {} of which are idle.",
             self.worker_sessions.len(),
@@ -380,8 +731,8 @@ impl Handler<WorkerDisconnect> for JobServer {
             log::warn!("Disconnect message received for unknown worker");
             return
         };
-        let _ = self.worker_sessions.swap_remove(idx);
-        log::info!("Removed disconnected worker.");
+        let worker = self.worker_sessions.swap_remove(idx);
+        log::info!("Removed disconnected worker {}.", worker.name);
         log::debug!("Currently have {} workers, of which {} are idle.",
             self.worker_sessions.len(),
             self.count_idle_workers()
@@ -389,6 +740,267 @@ impl Handler<WorkerDisconnect> for JobServer {
     }
 }
 
+impl Handler<UserDeactivated> for JobServer {
+    type Result = usize;
+
+    /// Disconnect all sessions of the deactivated user. Any job a worker was running is
+    /// re-queued when its session stops. Returns the number of sessions disconnected.
+    fn handle(&mut self, msg: UserDeactivated, _ctx: &mut Self::Context) -> Self::Result {
+        let mut count = 0;
+        for w in self.worker_sessions.iter().filter(|w| *w.name == msg.name) {
+            w.addr.do_send(WorkerForceDisconnect {});
+            count += 1;
+        }
+        if let Some(client) = self.client_sessions.get(&msg.name) {
+            client.addr.do_send(ClientEndSession {});
+            count += 1;
+        }
+        // Forget the user's jobs, since the user may not come back. They're loaded from disk again if they do.
+        self.user_jobs.remove(&msg.name);
+        if msg.removed {
+            self.history.do_send(RemoveHistory { user: msg.name.clone() });
+        }
+        log::info!("Disconnecting {count} session(s) of deactivated user {}", msg.name);
+        count
+    }
+}
+
+impl Handler<CatalogueChanged> for JobServer {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: CatalogueChanged, _ctx: &mut Self::Context) -> Self::Result {
+        for client in self.client_sessions.values() {
+            client.addr.do_send(ClientCatalogueChanged {});
+        }
+        self.client_sessions.len()
+    }
+}
+
+impl Handler<ListUserJobs> for JobServer {
+    type Result = ResponseActFuture<Self, Vec<UserJob>>;
+
+    fn handle(&mut self, msg: ListUserJobs, _ctx: &mut Self::Context) -> Self::Result {
+        Box::pin(self.user_history(msg.name).map(|entries, act, _ctx| {
+            entries.into_iter().rev().map(|entry| UserJob {
+                record: act.job_record(&entry.config),
+                entry,
+            }).collect()
+        }))
+    }
+}
+
+impl Handler<FindJob> for JobServer {
+    type Result = ResponseActFuture<Self, Option<JobRecord>>;
+
+    fn handle(&mut self, msg: FindJob, _ctx: &mut Self::Context) -> Self::Result {
+        Box::pin(self.user_history(msg.user.clone())
+            .map(move |entries, act, _ctx| {
+                if let Some(entry) = entries.iter().find(|e| e.config.name == msg.jobname) {
+                    return Ok(Some(act.job_record(&entry.config)))
+                }
+                if !msg.any_user { return Ok(None) }
+                if let Some(job) = act.job_lookup.get(&msg.jobname) {
+                    return Ok(Some(JobRecord::Active(job.clone())))
+                }
+                if let Some(entry) = act.user_jobs.values()
+                    .flat_map(|jobs| jobs.entries.iter())
+                    .find(|e| e.config.name == msg.jobname)
+                {
+                    return Ok(Some(JobRecord::Archived(entry.config.clone())))
+                }
+                Err(msg.jobname)
+            })
+            .then(|found, act, _ctx| {
+                // Only search every saved history once nothing closer to hand has the job
+                let search = found.map_err(|jobname| act.history.send(FindInHistories {
+                    matches: Box::new(move |e| e.config.name == jobname),
+                }));
+                async move {
+                    match search {
+                        Ok(found) => found,
+                        Err(search) => search.await.ok().flatten().map(|entry| JobRecord::Archived(entry.config)),
+                    }
+                }.into_actor(act)
+            }))
+    }
+}
+
+impl Handler<MoleculeInUse> for JobServer {
+    type Result = ResponseFuture<bool>;
+
+    fn handle(&mut self, msg: MoleculeInUse, _ctx: &mut Self::Context) -> Self::Result {
+        let uses = |config: &PytfConfig| config.config.res_names().any(|name| name == msg.res_name);
+        let in_memory = self.job_lookup.values().any(|job| uses(&job.read().unwrap().config))
+            || self.user_jobs.values().flat_map(|jobs| jobs.entries.iter()).any(|entry| uses(&entry.config));
+        if in_memory {
+            return Box::pin(async { true })
+        }
+        let search = self.history.send(FindInHistories {
+            matches: Box::new(move |entry| entry.config.config.res_names().any(|name| name == msg.res_name)),
+        });
+        Box::pin(async move { search.await.ok().flatten().is_some() })
+    }
+}
+
+impl Handler<FindHistoryEntry> for JobServer {
+    type Result = ResponseActFuture<Self, Option<HistoryEntry>>;
+
+    fn handle(&mut self, msg: FindHistoryEntry, _ctx: &mut Self::Context) -> Self::Result {
+        Box::pin(self.user_history(msg.user.clone()).map(move |entries, _act, _ctx| {
+            entries.into_iter().find(|e| e.config.name == msg.jobname)
+        }))
+    }
+}
+
+impl Handler<SessionsRevoked> for JobServer {
+    type Result = usize;
+
+    /// Disconnect the user's client if it belongs to one of the revoked sessions.
+    /// Returns the number of clients disconnected.
+    fn handle(&mut self, msg: SessionsRevoked, _ctx: &mut Self::Context) -> Self::Result {
+        match self.client_sessions.get(&msg.name) {
+            Some(ClientDetails { addr, session: Some(session), .. }) if msg.sessions.contains(session) => {
+                log::info!("Disconnecting client {} after its session was revoked", msg.name);
+                addr.do_send(ClientEndSession {});
+                1
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Check that the job server is responsive. Returns the number of connected workers.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct HealthCheck {}
+
+impl Handler<HealthCheck> for JobServer {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: HealthCheck, _ctx: &mut Self::Context) -> Self::Result {
+        self.worker_sessions.len()
+    }
+}
+
+/// Update the job server's gauges in `METRICS`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RefreshMetrics {}
+
+impl Handler<RefreshMetrics> for JobServer {
+    type Result = ();
+
+    fn handle(&mut self, _msg: RefreshMetrics, _ctx: &mut Self::Context) -> Self::Result {
+        Metrics::set(&METRICS.queue_depth, self.unfinished_jobs.iter().filter(|job| is_job_runnable(job)).count());
+        Metrics::set(&METRICS.workers, self.worker_sessions.len());
+        Metrics::set(&METRICS.idle_workers, self.count_idle_workers());
+        Metrics::set(&METRICS.jobs_in_memory, self.job_lookup.len());
+        let segment_bytes: usize = self.job_lookup.values()
+            .map(|job| job.read().unwrap().segments.iter().flatten().map(|seg| seg.data.len()).sum::<usize>())
+            .sum();
+        Metrics::set(&METRICS.segment_bytes, segment_bytes);
+    }
+}
+
+/// Snapshot of the workers, jobs and clients of the server, for admins
+#[derive(Message)]
+#[rtype(result = "ServerStatus")]
+pub struct GetServerStatus {}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub idle: bool,
+    /// Job the worker is running (or stealing), if any
+    pub job: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStatusSummary {
+    pub name: String,
+    pub status: String,
+    /// Worker most recently assigned to the job
+    pub worker: Option<String>,
+    /// Number of clients attached to the job
+    pub clients: usize,
+    pub latest_segment: usize,
+    pub n_cycles: usize,
+    /// Seconds since the job was created or loaded from the archive
+    pub age_secs: u64,
+    /// Seconds since the job was last updated
+    pub idle_secs: u64,
+    /// Whether the job is in the queue of unfinished jobs
+    pub queued: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientStatus {
+    pub name: String,
+    /// Job the client is attached to, if any
+    pub job: Option<String>,
+}
+
+#[derive(Debug, Serialize, MessageResponse)]
+pub struct ServerStatus {
+    pub workers: Vec<WorkerStatus>,
+    pub jobs: Vec<JobStatusSummary>,
+    pub clients: Vec<ClientStatus>,
+}
+
+impl Handler<GetServerStatus> for JobServer {
+    type Result = ServerStatus;
+
+    fn handle(&mut self, _msg: GetServerStatus, _ctx: &mut Self::Context) -> Self::Result {
+        let now = Instant::now();
+        let mut jobs: Vec<JobStatusSummary> = self.job_lookup.values()
+            .map(|job| {
+                let queued = self.unfinished_jobs.iter().any(|j| Arc::ptr_eq(j, job));
+                let job = job.read().unwrap();
+                JobStatusSummary {
+                    name: job.config.name.clone(),
+                    status: job.status.to_string(),
+                    worker: job.worker.as_ref().map(|w| w.to_string()),
+                    clients: job.clients.len(),
+                    latest_segment: job.latest_segment,
+                    n_cycles: job.segments.len(),
+                    age_secs: now.duration_since(job.created).as_secs(),
+                    idle_secs: now.duration_since(job.timestamp).as_secs(),
+                    queued,
+                }
+            })
+            .collect();
+        jobs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let workers = self.worker_sessions.iter()
+            .map(|w| WorkerStatus {
+                name: w.name.to_string(),
+                idle: w.idle.load(Ordering::Acquire),
+                job: self.job_lookup.values().find_map(|job| {
+                    let job = job.read().unwrap();
+                    match &job.status {
+                        JobStatus::Running(addr) | JobStatus::Stealing(_, addr) if *addr == w.addr
+                            => Some(job.config.name.clone()),
+                        _ => None,
+                    }
+                }),
+            })
+            .collect();
+
+        let mut clients: Vec<ClientStatus> = self.client_sessions.iter()
+            .map(|(name, client)| ClientStatus {
+                name: name.to_string(),
+                job: self.job_lookup.values().find_map(|job| {
+                    let job = job.read().unwrap();
+                    job.clients.contains(&client.addr).then(|| job.config.name.clone())
+                }),
+            })
+            .collect();
+        clients.sort_by(|a, b| a.name.cmp(&b.name));
+
+        ServerStatus { workers, jobs, clients }
+    }
+}
+
 #[derive(Debug, Message, PartialEq, Eq)]
 #[rtype(result="()")]
 pub struct UnhandledTrajectorySegment {
@@ -416,10 +1028,15 @@ impl Handler<UnhandledTrajectorySegment> for JobServer {
 pub struct JobInner {
     pub config: PytfConfig,
     pub status: JobStatus,
+    /// Name of the worker most recently assigned to this job
+    pub worker: Option<Arc<String>>,
     pub clients: Vec<Addr<ClientWsSession>>,
     pub segments: Vec<Option<TrajectorySegment>>,
     pub latest_segment: usize,
+    /// Time the job was last updated
     pub timestamp: Instant,
+    /// Time the job was created or loaded from the archive
+    pub created: Instant,
 }
 pub type Job = Arc<RwLock<JobInner>>;
 
@@ -496,8 +1113,10 @@ impl JobInner {
             latest_segment: 0,
             config,
             status: JobStatus::Waiting,
+            worker: None,
             clients: Vec::with_capacity(32),
             timestamp: Instant::now(),
+            created: Instant::now(),
         }
     }
 
@@ -505,6 +1124,21 @@ impl JobInner {
         Arc::new(RwLock::new(self))
     }
 
+    /// Number of cycles a new request for the job would start (or resume) running.
+    /// `None` if the request wouldn't run anything, as the job has ended or is already watched.
+    pub fn requested_cycles(&self) -> Option<usize> {
+        match self.status {
+            JobStatus::Finished | JobStatus::Failed => None,
+            _ if !self.clients.is_empty() => None,
+            _ => Some(self.segments.len().saturating_sub(self.latest_segment)),
+        }
+    }
+
+    /// Whether the job is waiting for a worker and still has clients to run it for
+    pub fn is_runnable(&self) -> bool {
+        matches!(self.status, JobStatus::Waiting | JobStatus::Steal(_)) && !self.clients.is_empty()
+    }
+
     pub fn remove_client(&mut self, client: &Addr<ClientWsSession>) {
         let Some(client_idx) = self.clients.iter().position(|c| c == client) else {
             log::warn!("Tried to remove client that wasn't attached");
@@ -555,6 +1189,17 @@ impl JobInner {
         else { AddSegmentResult::Ok }
     }
 
+    /// Status of the job, including the name of the worker involved if there is one
+    pub fn describe_status(&self) -> String {
+        match (&self.status, &self.worker) {
+            (JobStatus::Running(_) | JobStatus::Paused(_) | JobStatus::Stealing(..), Some(worker))
+                => format!("{} ({worker})", self.status),
+            (JobStatus::Finished | JobStatus::Failed, Some(worker))
+                => format!("{} (last run on {worker})", self.status),
+            _ => self.status.to_string(),
+        }
+    }
+
     pub fn build_ping(&self) -> TrajectoryPing {
         TrajectoryPing {
             latest_segment: self.latest_segment,
@@ -579,7 +1224,7 @@ impl JobInner {
 
     pub fn archive_if_ready(&mut self, now: &Instant) -> bool {
         // If job was recently touched or has attached clients, don't archive it
-        if now.duration_since(self.timestamp) < MAX_JOB_AGE || self.clients.len() > 0 {
+        if now.duration_since(self.timestamp) < config().jobs.max_age() || self.clients.len() > 0 {
             return true;
         }
         match self.status {
@@ -634,6 +1279,7 @@ impl JobInner {
             }
         }
         fid.flush()?;
+        Metrics::inc(&METRICS.archive_writes);
         log::debug!("Archived job {}", self.config.name);
         self.status = JobStatus::Archived;
         Ok(())
@@ -669,18 +1315,96 @@ impl JobInner {
                 segments[i] = Some(TrajectorySegment { data: Bytes::from(seg_data) });
             }
         }
+        Metrics::inc(&METRICS.archive_reads);
         log::debug!("Loaded job {} from archive", config.name);
         Ok(Self {
             config,
             status,
+            worker: None,
             clients: Vec::new(), // Not setting capacity since this could be overwritten
             segments,
             latest_segment,
             timestamp: Instant::now(),
+            created: Instant::now(),
         })
     }
 }
 
+/// Open an archived job and skip to its segments.
+/// Returns the file, the status the job was archived with, and its latest segment.
+fn open_archived_segments(config: &PytfConfig) -> std::io::Result<(BufReader<std::fs::File>, FinalStatus, usize)> {
+    let fid = std::fs::File::open(ARCHIVE_DIR.get().unwrap().join(config.archive_name()))?;
+    let mut fid = BufReader::new(fid);
+    let pause_bytes = read_le_usize(&mut fid)?;
+    // Skip the resume data of paused jobs, or the status of finished jobs
+    let (status, skip) = if pause_bytes > 0 {
+        (FinalStatus::Paused, pause_bytes)
+    } else {
+        (FinalStatus::Finished, std::mem::size_of::<usize>())
+    };
+    fid.seek_relative(skip as i64)?;
+    let latest_segment = read_le_usize(&mut fid)?;
+    Ok((fid, status, latest_segment))
+}
+
+/// Read the status and latest segment of an archived job, without loading the segments
+pub fn archived_progress(config: &PytfConfig) -> std::io::Result<(FinalStatus, usize)> {
+    open_archived_segments(config).map(|(_, status, latest_segment)| (status, latest_segment))
+}
+
+/// Read one segment of an archived job, skipping over the others.
+/// Returns `Ok(None)` if the job didn't get as far as that segment.
+pub fn archived_segment(config: &PytfConfig, segment_id: usize) -> std::io::Result<Option<TrajectorySegment>> {
+    let (mut fid, _, latest_segment) = open_archived_segments(config)?;
+    if segment_id == 0 || segment_id > latest_segment {
+        return Ok(None)
+    }
+    for _ in 1..segment_id {
+        let bytes = read_le_usize(&mut fid)?;
+        fid.seek_relative(bytes as i64)?;
+    }
+    read_archived_segment(&mut fid)
+}
+
+/// Segments of an archived job, read from the archive one at a time
+pub struct ArchivedSegments {
+    fid: BufReader<std::fs::File>,
+    /// Segments still to read
+    remaining: usize,
+}
+
+impl Iterator for ArchivedSegments {
+    /// Segments which were never received are `None`
+    type Item = std::io::Result<Option<TrajectorySegment>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 { return None }
+        self.remaining -= 1;
+        let segment = read_archived_segment(&mut self.fid);
+        // Stop at the first error, since the rest of the file can't be found
+        if segment.is_err() { self.remaining = 0 }
+        Some(segment)
+    }
+}
+
+/// Open the segments of an archived job, to be read as they're needed
+pub fn archived_segments(config: &PytfConfig) -> std::io::Result<ArchivedSegments> {
+    let (fid, _, latest_segment) = open_archived_segments(config)?;
+    Ok(ArchivedSegments { fid, remaining: latest_segment })
+}
+
+/// Read the segment at the current position of an archive
+fn read_archived_segment(fid: &mut BufReader<std::fs::File>) -> std::io::Result<Option<TrajectorySegment>> {
+    let bytes = read_le_usize(fid)?;
+    if bytes == 0 {
+        return Ok(None)
+    }
+    let mut seg_data: Vec<u8> = vec![0u8; bytes];
+    fid.read_exact(&mut seg_data)?;
+    Metrics::inc(&METRICS.archive_reads);
+    Ok(Some(TrajectorySegment { data: Bytes::from(seg_data) }))
+}
+
 fn read_le_usize(fid: &mut BufReader<impl Read>) -> std::io::Result<usize> {
     let mut out = [0u8; 8];
     fid.read_exact(&mut out)?;
@@ -720,3 +1444,237 @@ pub struct JobAssignment {
 }
 
 
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::time::Duration;
+
+    use actix_codec::{Decoder, Encoder};
+    use actix_web::{error::PayloadError, web::BytesMut};
+    use actix_web_actors::ws;
+    use futures::{channel::mpsc, StreamExt};
+    use pytf_web::{
+        authentication::SessionUser,
+        input_config::ConfigSettings,
+        worker_client::{DONE_HEADER, JOB_HEADER},
+        Reloadable,
+    };
+
+    use super::*;
+    use crate::{scheduler::Policy, server_config::QuotasSection};
+
+    type Input = mpsc::UnboundedSender<Result<Bytes, PayloadError>>;
+
+    /// Run a websocket session actor over in-memory streams. Returns its address, a sender for
+    /// raw frames to the actor and a receiver for the raw frames it sends.
+    fn run_session<A>(actor: A) -> (Addr<A>, Input, mpsc::UnboundedReceiver<Bytes>)
+    where A: Actor<Context = ws::WebsocketContext<A>> + StreamHandler<Result<ws::Message, ws::ProtocolError>>
+    {
+        let (input, input_rx) = mpsc::unbounded();
+        let (addr, output) = ws::WebsocketContext::create_with_addr(actor, input_rx);
+        let (output_tx, output_rx) = mpsc::unbounded();
+        actix_rt::spawn(async move {
+            let mut output = Box::pin(output);
+            while let Some(Ok(bytes)) = output.next().await {
+                if output_tx.unbounded_send(bytes).is_err() { break }
+            }
+        });
+        (addr, input, output_rx)
+    }
+
+    /// Worker session with the worker on the other end played by the test
+    pub(crate) struct TestWorker {
+        input: Input,
+        output: mpsc::UnboundedReceiver<Bytes>,
+        buf: BytesMut,
+        codec: awc::ws::Codec,
+    }
+
+    impl TestWorker {
+        pub(crate) fn connect(name: &str, srv: &Addr<JobServer>) -> Self {
+            let (_, input, output) = run_session(WorkerWsSession::new(name.into(), srv.clone()));
+            Self { input, output, buf: BytesMut::new(), codec: awc::ws::Codec::new().client_mode() }
+        }
+
+        /// Wait for the next job sent to the worker and return its name
+        pub(crate) async fn next_job(&mut self) -> String {
+            loop {
+                while let Some(frame) = self.codec.decode(&mut self.buf).unwrap() {
+                    if let ws::Frame::Binary(bytes) = frame {
+                        if let Some(config) = bytes.strip_prefix(JOB_HEADER) {
+                            return serde_json::from_slice::<PytfConfig>(config).unwrap().name
+                        }
+                    }
+                }
+                let bytes = actix_rt::time::timeout(Duration::from_secs(1), self.output.next())
+                    .await
+                    .expect("No job sent to worker")
+                    .unwrap();
+                self.buf.extend_from_slice(&bytes);
+            }
+        }
+
+        /// Report a job as finished, so the worker is given its next job
+        pub(crate) fn finish(&mut self, jobname: &str) {
+            let mut frame = BytesMut::new();
+            self.codec.encode(ws::Message::Binary([DONE_HEADER, jobname.as_bytes()].concat().into()), &mut frame).unwrap();
+            self.input.unbounded_send(Ok(frame.freeze())).unwrap();
+        }
+    }
+
+    /// Client session which is kept open until dropped
+    pub(crate) struct TestClient {
+        name: Arc<String>,
+        addr: Addr<ClientWsSession>,
+        _input: Input,
+        _output: mpsc::UnboundedReceiver<Bytes>,
+    }
+
+    impl TestClient {
+        pub(crate) async fn connect(name: &str, role: Role, srv: &Addr<JobServer>) -> Self {
+            let user = SessionUser { username: name.into(), role, external: false, session: None };
+            let input_config = Arc::new(Reloadable::new(ConfigSettings::default()));
+            let (addr, _input, _output) = run_session(ClientWsSession::new(user, srv.clone(), input_config));
+            while !srv.send(GetServerStatus {}).await.unwrap().clients.iter().any(|c| c.name == name) {
+                actix_rt::time::sleep(Duration::from_millis(1)).await;
+            }
+            Self { name: Arc::new(name.into()), addr, _input, _output }
+        }
+
+        pub(crate) async fn submit(&self, jobname: &str, srv: &Addr<JobServer>) -> Result<Job, String> {
+            self.submit_created(jobname, Instant::now(), srv).await
+        }
+
+        /// Request a job as the client session does, creating it as if at `created` if the server
+        /// doesn't have it. Returns the reason if the request is denied.
+        pub(crate) async fn submit_created(&self, jobname: &str, created: Instant, srv: &Addr<JobServer>) -> Result<Job, String> {
+            let config = PytfConfig { name: jobname.into(), ..Default::default() };
+            let accepted = srv.send(ClientReqJob {
+                config: config.clone(),
+                submitted: PytfConfigMinimal::default(),
+                client_id: self.name.clone(),
+                client_addr: self.addr.clone(),
+                client_prev_job: None,
+            }).await.unwrap();
+            match accepted {
+                AcceptedJob::New => {
+                    let job = JobInner { created, ..JobInner::new(config) };
+                    srv.send(RegisterJob {
+                        client: self.addr.clone(),
+                        client_id: self.name.clone(),
+                        submitted: PytfConfigMinimal::default(),
+                        job,
+                    }).await.unwrap()
+                },
+                AcceptedJob::Existing(job) | AcceptedJob::Finished(job) => Ok(job),
+                AcceptedJob::Failed(_) => panic!("Job {jobname} failed"),
+                AcceptedJob::Denied(reason) => Err(reason),
+            }
+        }
+    }
+
+    fn start_server(policy: Policy, instructor_priority: bool) -> Addr<JobServer> {
+        test_archive_dir();
+        JobServer { scheduler: Scheduler::new(policy, instructor_priority), ..JobServer::new() }.start()
+    }
+
+    /// Alice submits two jobs before Bob submits one, then two workers connect
+    async fn burst(policy: Policy) -> Vec<String> {
+        let srv = start_server(policy, false);
+        let alice = TestClient::connect("alice", Role::Student, &srv).await;
+        let bob = TestClient::connect("bob", Role::Student, &srv).await;
+        alice.submit("alice-1", &srv).await.unwrap();
+        alice.submit("alice-2", &srv).await.unwrap();
+        bob.submit("bob-1", &srv).await.unwrap();
+
+        let mut w1 = TestWorker::connect("w1", &srv);
+        let mut order = vec![w1.next_job().await];
+        let mut w2 = TestWorker::connect("w2", &srv);
+        order.push(w2.next_job().await);
+        w1.finish(&order[0]);
+        order.push(w1.next_job().await);
+        order
+    }
+
+    #[actix_web::test]
+    async fn test_scheduling() {
+        assert_eq!(burst(Policy::Fifo).await, ["alice-1", "alice-2", "bob-1"]);
+        assert_eq!(burst(Policy::FairShare).await, ["alice-1", "bob-1", "alice-2"]);
+
+        // Jobs with more clients waiting go first. Jobs are given the same creation time,
+        // so they'd otherwise go in the order they were submitted.
+        let srv = start_server(Policy::Clients, false);
+        let created = Instant::now();
+        let alice = TestClient::connect("alice", Role::Student, &srv).await;
+        alice.submit_created("solo", created, &srv).await.unwrap();
+        let mut others = Vec::new();
+        for name in ["bob", "carol", "dave"] {
+            let client = TestClient::connect(name, Role::Student, &srv).await;
+            client.submit_created("shared", created, &srv).await.unwrap();
+            others.push(client);
+        }
+        let mut worker = TestWorker::connect("w1", &srv);
+        assert_eq!(worker.next_job().await, "shared");
+        worker.finish("shared");
+        assert_eq!(worker.next_job().await, "solo");
+
+        // Between two jobs which have never run, the one with more clients goes first
+        let srv = start_server(Policy::Clients, false);
+        let created = Instant::now();
+        let mut clients = Vec::new();
+        for (name, jobname) in [("alice", "pair"), ("bob", "pair"), ("carol", "trio"), ("dave", "trio"), ("erin", "trio")] {
+            let client = TestClient::connect(name, Role::Student, &srv).await;
+            client.submit_created(jobname, created, &srv).await.unwrap();
+            clients.push(client);
+        }
+        let mut worker = TestWorker::connect("w1", &srv);
+        assert_eq!(worker.next_job().await, "trio");
+        worker.finish("trio");
+        assert_eq!(worker.next_job().await, "pair");
+
+        // Instructors' jobs go before any others
+        let srv = start_server(Policy::Fifo, true);
+        let alice = TestClient::connect("alice", Role::Student, &srv).await;
+        let teacher = TestClient::connect("teacher", Role::Instructor, &srv).await;
+        alice.submit("student-job", &srv).await.unwrap();
+        teacher.submit("demo-job", &srv).await.unwrap();
+        let mut worker = TestWorker::connect("w1", &srv);
+        assert_eq!(worker.next_job().await, "demo-job");
+        worker.finish("demo-job");
+        assert_eq!(worker.next_job().await, "student-job");
+    }
+
+    #[actix_web::test]
+    async fn test_quotas() {
+        // A finished run in the archive, which the server doesn't hold
+        let dir = test_archive_dir();
+        let config = PytfConfig { name: "quota-finished".into(), ..Default::default() };
+        let mut finished = JobInner::new(config.clone());
+        finished.status = JobStatus::Finished;
+        finished.archive().unwrap();
+
+        let quotas = QuotasSection { max_active_jobs: 1, max_submissions_per_minute: 2, max_queued_cycles: 0 };
+        let srv = JobServer { quotas: Quotas::new(quotas), ..JobServer::new() }.start();
+        let alice = TestClient::connect("alice", Role::Student, &srv).await;
+        let bob = TestClient::connect("bob", Role::Student, &srv).await;
+        let carol = TestClient::connect("carol", Role::Student, &srv).await;
+
+        // Jobs only watched by the requesting client don't count as active
+        alice.submit("quota-1", &srv).await.unwrap();
+        alice.submit("quota-2", &srv).await.unwrap();
+        let reason = alice.submit("quota-3", &srv).await.unwrap_err();
+        assert!(reason.contains("per minute"), "{reason}");
+
+        // Reopening a finished run doesn't start anything, so is allowed once the quota is used up
+        let job = alice.submit("quota-finished", &srv).await.unwrap();
+        assert_eq!(job.read().unwrap().status, JobStatus::Finished);
+
+        // Jobs watched by other clients do count
+        carol.submit("quota-4", &srv).await.unwrap();
+        bob.submit("quota-4", &srv).await.unwrap();
+        let reason = carol.submit("quota-5", &srv).await.unwrap_err();
+        assert!(reason.contains("unfinished simulations"), "{reason}");
+
+        std::fs::remove_file(dir.join(config.archive_name())).unwrap();
+    }
+}
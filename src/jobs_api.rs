@@ -0,0 +1,338 @@
+use std::io::ErrorKind;
+
+use actix::Addr;
+use actix_web::{get, http, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use pytf_web::{
+    authentication::{Role, SessionUser},
+    pytf_config::PytfConfigMinimal,
+    pytf_frame::TrajectorySegment,
+    repro_bundle::write_bundle,
+    trajectory_export::{TrajectoryExport, TrajectoryFormat},
+};
+
+use crate::job_queue::{
+    archived_progress, archived_segment, archived_segments, FindJob, JobRecord, JobServer, JobStatus, ListUserJobs, UserJob,
+};
+
+/// Status and progress of a job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobSummary {
+    pub name: String,
+    pub status: String,
+    /// Id of the most recent segment produced (segment ids start at 1)
+    pub latest_segment: usize,
+    /// Number of segments the job will produce once finished
+    pub n_cycles: usize,
+}
+
+/// Job in a user's history
+#[derive(Debug, Serialize)]
+pub struct HistorySummary {
+    #[serde(flatten)]
+    pub summary: JobSummary,
+    /// Configuration as submitted, which can be shown to the user or submitted again
+    pub submitted: PytfConfigMinimal,
+    /// Unix time the job was first requested
+    pub created: u64,
+    /// Unix time the job was most recently requested
+    pub last_requested: u64,
+}
+
+/// Summarise a job in a user's history. Jobs which are no longer held by the server
+/// have the status they had when they were removed, if it's known.
+async fn summarize_history(job: UserJob) -> Result<HistorySummary, actix_web::Error> {
+    let UserJob { entry, record } = job;
+    let archived = matches!(record, JobRecord::Archived(_));
+    let mut summary = summarize(record).await?.unwrap_or_else(|| JobSummary {
+        name: entry.config.name.clone(),
+        status: JobStatus::Archived.to_string(),
+        latest_segment: 0,
+        n_cycles: entry.config.n_cycles,
+    });
+    // Jobs which failed or were cancelled aren't archived, so only the history knows how they ended
+    if let (true, Some(status)) = (archived, entry.status) {
+        summary.status = status.to_string();
+    }
+    Ok(HistorySummary {
+        summary,
+        submitted: entry.submitted,
+        created: entry.created,
+        last_requested: entry.last_requested,
+    })
+}
+
+/// Summarise a job, reading the progress of archived jobs from disk.
+/// Returns `Ok(None)` if the job was dropped without being archived.
+async fn summarize(record: JobRecord) -> Result<Option<JobSummary>, actix_web::Error> {
+    match record {
+        JobRecord::Active(job) => {
+            let job = job.read().unwrap();
+            Ok(Some(JobSummary {
+                name: job.config.name.clone(),
+                status: job.describe_status(),
+                latest_segment: job.latest_segment,
+                n_cycles: job.segments.len(),
+            }))
+        }
+        JobRecord::Archived(config) => {
+            let (name, n_cycles) = (config.name.clone(), config.n_cycles);
+            match web::block(move || archived_progress(&config)).await? {
+                Ok((status, latest_segment)) => Ok(Some(JobSummary {
+                    name,
+                    status: status.to_string(),
+                    latest_segment,
+                    n_cycles,
+                })),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => {
+                    log::warn!("Failed to read archived job \"{name}\": {e}");
+                    Err(actix_web::error::ErrorInternalServerError("Failed to read archived job."))
+                }
+            }
+        }
+    }
+}
+
+/// Query string for trajectory downloads
+#[derive(Debug, Deserialize)]
+pub struct TrajectoryQuery {
+    /// One of xyz (the default), pdb or gro
+    pub format: Option<String>,
+}
+
+/// Segments a job has received, in order
+type Segments = Box<dyn Iterator<Item = TrajectorySegment> + Send>;
+
+/// Segments of a job. Archived jobs' segments are read from the archive as they're needed,
+/// so the iterator should be run off the executor.
+/// Returns `Ok(None)` if the job was dropped without being archived.
+async fn job_segments(record: JobRecord) -> Result<Option<Segments>, actix_web::Error> {
+    match record {
+        JobRecord::Active(job) => {
+            let segments = job.read().unwrap().segments.clone();
+            Ok(Some(Box::new(segments.into_iter().flatten())))
+        },
+        JobRecord::Archived(config) => {
+            let name = config.name.clone();
+            match web::block(move || archived_segments(&config)).await? {
+                Ok(segments) => Ok(Some(Box::new(segments
+                    .map_while(move |segment| segment
+                        .map_err(|e| log::warn!("Failed to read segment of archived job \"{name}\": {e}"))
+                        .ok())
+                    .flatten()))),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => {
+                    log::warn!("Failed to load archived job \"{name}\": {e}");
+                    Err(actix_web::error::ErrorInternalServerError("Failed to read archived job."))
+                }
+            }
+        }
+    }
+}
+
+/// Find one of the user's jobs. Instructors and admins can find anyone's jobs.
+async fn find_job(user: &SessionUser, jobname: String, srv: &Addr<JobServer>) -> Result<Option<JobRecord>, actix_web::Error> {
+    srv.send(FindJob {
+        user: user.username.clone(),
+        jobname,
+        any_user: user.role.permits(Role::Instructor),
+    }).await.map_err(actix_web::error::ErrorInternalServerError)
+}
+
+/// List the jobs you have requested, most recent first
+#[get("/jobs")]
+async fn list_jobs(
+    user: SessionUser,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Guest)?;
+    let history = srv.send(ListUserJobs { name: user.username.clone() }).await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let mut jobs = Vec::with_capacity(history.len());
+    for job in history {
+        jobs.push(summarize_history(job).await?);
+    }
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+/// Status and progress of one of your jobs
+#[get("/jobs/{name}")]
+async fn job_status(
+    user: SessionUser,
+    jobname: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Guest)?;
+    let Some(record) = find_job(&user, jobname.into_inner(), &srv).await? else {
+        return Ok(HttpResponse::NotFound().body("Unknown job."))
+    };
+    match summarize(record).await? {
+        Some(summary) => Ok(HttpResponse::Ok().json(summary)),
+        None => Ok(HttpResponse::NotFound().body("Job is no longer available.")),
+    }
+}
+
+/// Data of one segment of one of your jobs, in the same binary format as sent over `/socket`
+#[get("/jobs/{name}/segments/{id}")]
+async fn job_segment(
+    user: SessionUser,
+    path: web::Path<(String, usize)>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Guest)?;
+    let (jobname, segment_id) = path.into_inner();
+    let Some(record) = find_job(&user, jobname, &srv).await? else {
+        return Ok(HttpResponse::NotFound().body("Unknown job."))
+    };
+    if segment_id == 0 {
+        return Ok(HttpResponse::BadRequest().body("Segment ids start at 1."))
+    }
+    let segment = match record {
+        JobRecord::Active(job) => job.read().unwrap().segments.get(segment_id - 1).cloned().flatten(),
+        JobRecord::Archived(config) => {
+            let name = config.name.clone();
+            match web::block(move || archived_segment(&config, segment_id)).await? {
+                Ok(segment) => segment,
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => {
+                    log::warn!("Failed to read segment {segment_id} of archived job \"{name}\": {e}");
+                    return Err(actix_web::error::ErrorInternalServerError("Failed to read archived job."))
+                }
+            }
+        }
+    };
+    match segment {
+        Some(segment) => Ok(HttpResponse::Ok().content_type("application/octet-stream").body(segment.data())),
+        None => Ok(HttpResponse::NotFound().body(format!("Segment {segment_id} is not available."))),
+    }
+}
+
+/// Download the frames produced so far by one of your jobs as a multi-frame xyz, pdb or gro file.
+/// The file is converted while it is sent, one frame at a time.
+#[get("/jobs/{name}/trajectory")]
+async fn job_trajectory(
+    user: SessionUser,
+    jobname: web::Path<String>,
+    query: web::Query<TrajectoryQuery>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Guest)?;
+    let format = match query.format.as_deref().map(str::parse).unwrap_or(Ok(TrajectoryFormat::Xyz)) {
+        Ok(format) => format,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+    let jobname = jobname.into_inner();
+    let Some(record) = find_job(&user, jobname.clone(), &srv).await? else {
+        return Ok(HttpResponse::NotFound().body("Unknown job."))
+    };
+    let Some(segments) = job_segments(record).await? else {
+        return Ok(HttpResponse::NotFound().body("Job is no longer available."))
+    };
+    log::info!("Sending {} trajectory of job {jobname} to {}", format.extension(), user.username);
+    let filename = format!("{jobname}.{}", format.extension());
+    // Each frame is converted off the executor, since it may need a segment read from disk
+    let export = TrajectoryExport::new(format, jobname, segments);
+    let body = futures::stream::unfold(Some(export), |export| async move {
+        let mut export = export?;
+        match web::block(move || (export.next(), export)).await {
+            Ok((Some(chunk), export)) => Some((Ok(chunk), Some(export))),
+            Ok((None, _)) => None,
+            Err(e) => Some((Err(actix_web::Error::from(e)), None)),
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .insert_header(http::header::ContentDisposition::attachment(filename))
+        .streaming(body))
+}
+
+/// Download everything needed to rerun one of your jobs with PyThinFilm outside pytf-web,
+/// as a .tar.gz holding its expanded config.yml, molecule files and a manifest of tool versions
+#[get("/jobs/{name}/bundle")]
+async fn job_bundle(
+    user: SessionUser,
+    jobname: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Guest)?;
+    let Some(record) = find_job(&user, jobname.into_inner(), &srv).await? else {
+        return Ok(HttpResponse::NotFound().body("Unknown job."))
+    };
+    let (config, worker) = match record {
+        JobRecord::Active(job) => {
+            let job = job.read().unwrap();
+            (job.config.clone(), job.worker.as_ref().map(|w| w.to_string()))
+        }
+        JobRecord::Archived(config) => (config, None),
+    };
+    let filename = format!("{}.tar.gz", config.name);
+    let name = config.name.clone();
+    let bundle = web::block(move || {
+        let mut out = Vec::new();
+        write_bundle(&config, worker, &mut out).map(|_| out)
+    }).await?;
+    match bundle {
+        Ok(bundle) => {
+            log::info!("Sending reproduction bundle of job {name} to {}", user.username);
+            Ok(HttpResponse::Ok()
+                .content_type("application/gzip")
+                .insert_header(http::header::ContentDisposition::attachment(filename))
+                .body(bundle))
+        }
+        Err(e) => {
+            log::error!("Failed to create reproduction bundle for job {name}: {e}");
+            Ok(HttpResponse::InternalServerError().body("Failed to create reproduction bundle."))
+        }
+    }
+}
+
+/// Register the job status endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_jobs)
+        .service(job_status)
+        .service(job_segment)
+        .service(job_trajectory)
+        .service(job_bundle);
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::web::Bytes;
+    use pytf_web::pytf_config::PytfConfig;
+
+    use super::*;
+    use crate::job_queue::{test_archive_dir, JobInner};
+
+    #[actix_web::test]
+    async fn test_summarize() {
+        let dir = test_archive_dir();
+
+        let mut config = PytfConfig::default();
+        config.name = "jobs-api-test".into();
+        config.n_cycles = 3;
+        let mut job = JobInner::new(config.clone());
+        job.add_segment(&config.name, 1, TrajectorySegment::new(Bytes::from_static(b"one")));
+        job.add_segment(&config.name, 2, TrajectorySegment::new(Bytes::from_static(b"two")));
+        let expected = JobSummary {
+            name: config.name.clone(),
+            status: "Waiting".into(),
+            latest_segment: 2,
+            n_cycles: 3,
+        };
+        assert_eq!(summarize(JobRecord::Active(job.clone().wrap())).await.unwrap(), Some(expected.clone()));
+
+        job.status = JobStatus::Finished;
+        job.archive().unwrap();
+        let archived = summarize(JobRecord::Archived(config.clone())).await.unwrap();
+        assert_eq!(archived, Some(JobSummary { status: "Finished".into(), ..expected }));
+        assert_eq!(archived_segment(&config, 2).unwrap().map(|s| s.data()), Some(Bytes::from_static(b"two")));
+        assert!(archived_segment(&config, 3).unwrap().is_none());
+        let segments: Vec<_> = archived_segments(&config).unwrap().map(|s| s.unwrap().map(|s| s.data())).collect();
+        assert_eq!(segments, [Some(Bytes::from_static(b"one")), Some(Bytes::from_static(b"two"))]);
+
+        std::fs::remove_file(dir.join(config.archive_name())).unwrap();
+        assert_eq!(summarize(JobRecord::Archived(config)).await.unwrap(), None);
+    }
+}
@@ -1,12 +1,22 @@
+pub mod api_tokens;
 pub mod authentication;
+pub mod guests;
 pub mod input_config;
+pub mod oidc;
 pub mod pytf;
 pub mod pytf_config;
 pub mod pytf_runner;
 pub mod pytf_frame;
+pub mod repro_bundle;
+pub mod tls;
+pub mod topology;
+pub mod trajectory_export;
+pub mod user_sessions;
 pub mod worker_client;
 pub mod pdb2xyz;
 
+use std::sync::{Arc, RwLock};
+
 use anyhow::anyhow;
 
 /// Split off a null-terminated utf8 string form a byte array, ignoring the null terminator
@@ -17,3 +27,32 @@ pub fn split_nullterm_utf8_str(bytes: &mut actix_web::web::Bytes) -> anyhow::Res
     let _ = bytes.split_to(1);
     Ok(substr)
 }
+
+/// Format bytes as a lowercase hex string
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Shared value which can be replaced at runtime (e.g. when a resource file is reloaded).
+/// Readers get an `Arc` to the current value, so replacing it doesn't affect anything
+/// already using the old value.
+#[derive(Debug)]
+pub struct Reloadable<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self { current: RwLock::new(Arc::new(value)) }
+    }
+
+    /// Get the current value
+    pub fn get(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Swap in a new value
+    pub fn replace(&self, value: T) {
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+}
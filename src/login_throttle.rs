@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How often stale entries are cleared out of the attempt tables
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits on failed login attempts before a username or IP address is locked out
+#[derive(Clone, Debug)]
+pub struct ThrottleSettings {
+    /// Failed attempts allowed for a single username before it is locked out
+    pub max_user_attempts: u32,
+    /// Failed attempts allowed from a single IP address before it is locked out
+    pub max_ip_attempts: u32,
+    /// Length of the first lockout. Doubles with each further lockout.
+    pub lockout: Duration,
+    /// Longest possible lockout. Failures are also forgotten after this long with no attempts.
+    pub max_lockout: Duration,
+    /// Use the X-Forwarded-For header to get the client's IP address. Only safe
+    /// behind a reverse proxy which sets the header.
+    pub trust_proxy: bool,
+}
+
+impl Default for ThrottleSettings {
+    fn default() -> Self {
+        Self {
+            max_user_attempts: 5,
+            max_ip_attempts: 20,
+            lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(60 * 60),
+            trust_proxy: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Attempts {
+    /// Failed attempts since the last lockout
+    failures: u32,
+    /// Number of times locked out so far, to calculate the backoff
+    lockouts: u32,
+    locked_until: Option<Instant>,
+    last_attempt: Instant,
+}
+
+impl Attempts {
+    fn new(now: Instant) -> Self {
+        Self { failures: 0, lockouts: 0, locked_until: None, last_attempt: now }
+    }
+
+    fn remaining_lockout(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Count a failed attempt. Returns the lockout duration if this attempt triggered one.
+    fn fail(&mut self, max_attempts: u32, settings: &ThrottleSettings, now: Instant) -> Option<Duration> {
+        self.last_attempt = now;
+        self.failures += 1;
+        if self.failures < max_attempts { return None }
+        self.failures = 0;
+        let lockout = settings.lockout
+            .saturating_mul(2u32.saturating_pow(self.lockouts))
+            .min(settings.max_lockout);
+        self.lockouts += 1;
+        self.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    fn is_stale(&self, max_lockout: Duration, now: Instant) -> bool {
+        self.remaining_lockout(now).is_none() && now.duration_since(self.last_attempt) > max_lockout
+    }
+}
+
+#[derive(Debug)]
+struct Tables {
+    users: HashMap<String, Attempts>,
+    ips: HashMap<IpAddr, Attempts>,
+    last_prune: Instant,
+}
+
+/// Tracks failed login attempts per username and per IP address
+#[derive(Debug)]
+pub struct LoginThrottle {
+    pub settings: ThrottleSettings,
+    tables: Mutex<Tables>,
+}
+
+impl LoginThrottle {
+    pub fn new(settings: ThrottleSettings) -> Self {
+        Self {
+            settings,
+            tables: Mutex::new(Tables {
+                users: HashMap::new(),
+                ips: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    /// Check whether a login attempt is allowed. Returns the time remaining
+    /// until the attempt would be allowed if either the username or IP address is locked out.
+    pub fn check(&self, username: &str, ip: Option<IpAddr>) -> Result<(), Duration> {
+        self.check_at(username, ip, Instant::now())
+    }
+
+    fn check_at(&self, username: &str, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let mut tables = self.tables.lock().unwrap();
+        if now.duration_since(tables.last_prune) > PRUNE_INTERVAL {
+            let max_lockout = self.settings.max_lockout;
+            tables.users.retain(|_, a| !a.is_stale(max_lockout, now));
+            tables.ips.retain(|_, a| !a.is_stale(max_lockout, now));
+            tables.last_prune = now;
+        }
+        let user_lockout = tables.users.get(username).and_then(|a| a.remaining_lockout(now));
+        let ip_lockout = ip.and_then(|ip| tables.ips.get(&ip)).and_then(|a| a.remaining_lockout(now));
+        match user_lockout.max(ip_lockout) {
+            Some(remaining) => Err(remaining),
+            None => Ok(()),
+        }
+    }
+
+    /// Record a failed login attempt, locking out the username and/or IP address
+    /// if they have reached their limit.
+    pub fn record_failure(&self, username: &str, ip: Option<IpAddr>) {
+        self.record_failure_at(username, ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, username: &str, ip: Option<IpAddr>, now: Instant) {
+        let settings = &self.settings;
+        let mut tables = self.tables.lock().unwrap();
+        let attempts = tables.users.entry(username.to_owned()).or_insert_with(|| Attempts::new(now));
+        if attempts.is_stale(settings.max_lockout, now) { *attempts = Attempts::new(now); }
+        if let Some(lockout) = attempts.fail(settings.max_user_attempts, settings, now) {
+            log::warn!("Locked out username \"{username}\" for {}s after {} failed login attempts",
+                lockout.as_secs(), settings.max_user_attempts);
+        }
+        let Some(ip) = ip else { return };
+        let attempts = tables.ips.entry(ip).or_insert_with(|| Attempts::new(now));
+        if attempts.is_stale(settings.max_lockout, now) { *attempts = Attempts::new(now); }
+        if let Some(lockout) = attempts.fail(settings.max_ip_attempts, settings, now) {
+            log::warn!("Locked out IP address {ip} for {}s after {} failed login attempts",
+                lockout.as_secs(), settings.max_ip_attempts);
+        }
+    }
+
+    /// Clear failed attempts for a username after a successful login.
+    /// Failures from the IP address are kept, so that one valid account
+    /// can't be used to reset the limit while guessing others.
+    pub fn record_success(&self, username: &str) {
+        self.tables.lock().unwrap().users.remove(username);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lockout_backoff() {
+        let throttle = LoginThrottle::new(ThrottleSettings {
+            max_user_attempts: 2,
+            max_ip_attempts: 3,
+            lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(25),
+            trust_proxy: false,
+        });
+        let ip = Some(IpAddr::from([127, 0, 0, 1]));
+        let start = Instant::now();
+
+        throttle.record_failure_at("Foo", ip, start);
+        assert!(throttle.check_at("Foo", ip, start).is_ok());
+        throttle.record_failure_at("Foo", ip, start);
+        assert_eq!(throttle.check_at("Foo", None, start), Err(Duration::from_secs(10)));
+        assert!(throttle.check_at("Bar", None, start).is_ok());
+
+        // IP is locked out after its third failure, regardless of username
+        throttle.record_failure_at("Bar", ip, start);
+        assert_eq!(throttle.check_at("Baz", ip, start), Err(Duration::from_secs(10)));
+
+        // Second lockout of the same username is twice as long, then capped
+        let t = start + Duration::from_secs(11);
+        assert!(throttle.check_at("Foo", None, t).is_ok());
+        throttle.record_failure_at("Foo", None, t);
+        throttle.record_failure_at("Foo", None, t);
+        assert_eq!(throttle.check_at("Foo", None, t), Err(Duration::from_secs(20)));
+        let t = t + Duration::from_secs(21);
+        throttle.record_failure_at("Foo", None, t);
+        throttle.record_failure_at("Foo", None, t);
+        assert_eq!(throttle.check_at("Foo", None, t), Err(Duration::from_secs(25)));
+
+        throttle.record_success("Bar");
+        assert!(throttle.check_at("Bar", None, t).is_ok());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use actix::Addr;
+use actix_web::{get, web, HttpResponse};
+
+use pytf_web::authentication::{Role, SessionUser};
+
+use crate::job_queue::{JobServer, RefreshMetrics};
+
+/// Server metrics, served on `/metrics` in Prometheus text format
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    // Gauges refreshed by the job server when metrics are requested
+    /// Jobs waiting for a worker
+    pub queue_depth: AtomicU64,
+    pub workers: AtomicU64,
+    pub idle_workers: AtomicU64,
+    /// Jobs held in server memory
+    pub jobs_in_memory: AtomicU64,
+    /// Trajectory segment data held in server memory
+    pub segment_bytes: AtomicU64,
+
+    // Counters
+    pub jobs_finished: AtomicU64,
+    pub jobs_failed: AtomicU64,
+    /// Job requests rejected for going over a user's quotas
+    pub jobs_denied: AtomicU64,
+    pub archive_reads: AtomicU64,
+    pub archive_writes: AtomicU64,
+
+    // Web socket connections, updated as sessions start and stop
+    pub client_connections: AtomicU64,
+    pub client_connections_total: AtomicU64,
+    pub worker_connections: AtomicU64,
+    pub worker_connections_total: AtomicU64,
+}
+
+enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            queue_depth: AtomicU64::new(0),
+            workers: AtomicU64::new(0),
+            idle_workers: AtomicU64::new(0),
+            jobs_in_memory: AtomicU64::new(0),
+            segment_bytes: AtomicU64::new(0),
+            jobs_finished: AtomicU64::new(0),
+            jobs_failed: AtomicU64::new(0),
+            jobs_denied: AtomicU64::new(0),
+            archive_reads: AtomicU64::new(0),
+            archive_writes: AtomicU64::new(0),
+            client_connections: AtomicU64::new(0),
+            client_connections_total: AtomicU64::new(0),
+            worker_connections: AtomicU64::new(0),
+            worker_connections_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Increment a counter or gauge
+    pub fn inc(metric: &AtomicU64) {
+        metric.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrement a gauge
+    pub fn dec(metric: &AtomicU64) {
+        let _ = metric.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+    }
+
+    /// Set a gauge
+    pub fn set(metric: &AtomicU64, value: usize) {
+        metric.store(value as u64, Ordering::Relaxed);
+    }
+
+    /// Record a new web socket connection
+    pub fn connected(gauge: &AtomicU64, total: &AtomicU64) {
+        Self::inc(gauge);
+        Self::inc(total);
+    }
+
+    /// Format all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        use MetricType::*;
+        let metrics = [
+            ("pytf_queue_depth", "Jobs waiting for a worker", Gauge, &self.queue_depth),
+            ("pytf_workers", "Connected workers", Gauge, &self.workers),
+            ("pytf_idle_workers", "Connected workers without a job", Gauge, &self.idle_workers),
+            ("pytf_jobs_in_memory", "Jobs held in server memory", Gauge, &self.jobs_in_memory),
+            ("pytf_segment_bytes", "Bytes of trajectory segments held in server memory", Gauge, &self.segment_bytes),
+            ("pytf_jobs_finished_total", "Jobs finished by workers", Counter, &self.jobs_finished),
+            ("pytf_jobs_failed_total", "Jobs reported as failed by workers", Counter, &self.jobs_failed),
+            ("pytf_jobs_denied_total", "Job requests denied by user quotas", Counter, &self.jobs_denied),
+            ("pytf_archive_reads_total", "Jobs loaded from the archive", Counter, &self.archive_reads),
+            ("pytf_archive_writes_total", "Jobs written to the archive", Counter, &self.archive_writes),
+            ("pytf_client_connections", "Open client web sockets", Gauge, &self.client_connections),
+            ("pytf_client_connections_opened_total", "Client web sockets opened", Counter, &self.client_connections_total),
+            ("pytf_worker_connections", "Open worker web sockets", Gauge, &self.worker_connections),
+            ("pytf_worker_connections_opened_total", "Worker web sockets opened", Counter, &self.worker_connections_total),
+        ];
+        let mut out = String::with_capacity(2048);
+        for (name, help, kind, value) in metrics {
+            let kind = match kind {
+                Counter => "counter",
+                Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}",
+                value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Server metrics in Prometheus text format
+#[get("/metrics")]
+async fn get_metrics(
+    user: SessionUser,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    if let Err(e) = srv.send(RefreshMetrics {}).await {
+        log::warn!("Failed to refresh job server metrics: {e}");
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render()))
+}
+
+/// Register the metrics endpoint
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_metrics);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        Metrics::connected(&metrics.client_connections, &metrics.client_connections_total);
+        Metrics::connected(&metrics.client_connections, &metrics.client_connections_total);
+        Metrics::dec(&metrics.client_connections);
+        Metrics::dec(&metrics.worker_connections);
+        Metrics::set(&metrics.segment_bytes, 1024);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE pytf_client_connections gauge\npytf_client_connections 1\n"));
+        assert!(text.contains("# TYPE pytf_client_connections_opened_total counter\npytf_client_connections_opened_total 2\n"));
+        assert!(text.contains("\npytf_worker_connections 0\n"));
+        assert!(text.contains("\npytf_segment_bytes 1024\n"));
+        // Every metric has help, type and a value
+        assert_eq!(text.lines().count(), 14 * 3);
+    }
+}
@@ -0,0 +1,91 @@
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{get, http, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+
+use pytf_web::{
+    authentication::USER_DB,
+    oidc::{CallbackParams, PendingLogin, OIDC},
+};
+
+use crate::{account, login_throttle::LoginThrottle};
+
+/// Session key for the details of a login in progress
+const PENDING_LOGIN: &str = "oidc_login";
+
+/// Send the user back to the login page with an error code, which the login page shows a
+/// message for. Details of the error are only logged, not passed on to the user.
+fn login_error(code: &str) -> HttpResponse {
+    let query = serde_urlencoded::to_string([("login_error", code)]).unwrap_or_default();
+    HttpResponse::SeeOther()
+        .insert_header((http::header::LOCATION, format!("/?{query}")))
+        .finish()
+}
+
+/// Whether OIDC login is enabled, and the name of the provider, so the login page can offer it
+#[get("/oidc")]
+async fn oidc_info() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "enabled": OIDC.get().is_some(),
+        "name": OIDC.get().map(|oidc| oidc.settings.name.as_str()),
+    }))
+}
+
+/// Start logging in by redirecting the user to the identity provider
+#[get("/oidc/login")]
+async fn oidc_login(session: Session) -> Result<HttpResponse, actix_web::Error> {
+    let Some(oidc) = OIDC.get() else {
+        return Ok(HttpResponse::NotFound().body("OIDC login is not enabled."))
+    };
+    let (url, pending) = oidc.start_login();
+    session.insert(PENDING_LOGIN, pending)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((http::header::LOCATION, url))
+        .finish())
+}
+
+/// The identity provider redirects users back here once they have logged in
+#[get("/oidc/callback")]
+async fn oidc_callback(
+    request: HttpRequest,
+    session: Session,
+    params: web::Query<CallbackParams>,
+    throttle: web::Data<LoginThrottle>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(oidc) = OIDC.get() else {
+        return Ok(HttpResponse::NotFound().body("OIDC login is not enabled."))
+    };
+    let Some(pending) = session.get::<PendingLogin>(PENDING_LOGIN)? else {
+        log::warn!("OIDC callback without a login in progress");
+        return Ok(login_error("expired"))
+    };
+    session.remove(PENDING_LOGIN);
+
+    let mut user = match oidc.finish_login(&oidc.client(), pending, params.into_inner()).await {
+        Ok(user) => user,
+        Err(e) => {
+            log::warn!("OIDC login failed: {e}");
+            return Ok(login_error("failed"))
+        }
+    };
+    // Users in the users file can be disabled, or given a lower role, by an admin
+    let Some(role) = USER_DB.get().and_then(|db| db.external_role(&user.username, user.role)) else {
+        log::info!("Rejected OIDC login for disabled user \"{}\"", user.username);
+        return Ok(login_error("disabled"))
+    };
+    user.role = role;
+    account::start_session(&request, &mut user, throttle.settings.trust_proxy).await?;
+
+    Identity::login(&request.extensions(), user.to_id())
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    log::info!("Logged in via OIDC ({}, {})", user.username, user.role);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((http::header::LOCATION, "/"))
+        .finish())
+}
+
+/// Register the OIDC login endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(oidc_info)
+        .service(oidc_login)
+        .service(oidc_callback);
+}
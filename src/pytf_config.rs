@@ -1,17 +1,19 @@
 use std::{
+    collections::HashSet,
     hash::{Hash, Hasher},
     sync::OnceLock,
     path::{Path, PathBuf},
     collections::HashMap,
     fmt::Display,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use num::integer::Integer;
 
 use crate::{
     pdb2xyz::pdb2xyz,
     input_config::{ValueRange, ConfigSettings, ConfigSettingsValue},
+    Reloadable,
 };
 
 
@@ -29,29 +31,141 @@ pub struct MixtureComponentDetailed {
     name: String,
     formula: String,
     smiles: String,
+    /// Hidden molecules aren't offered to users, but are kept for existing jobs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    hidden: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     atoms: Option<Vec<Atom>>,
 }
 
+impl MixtureComponentDetailed {
+    pub fn new(res_name: String, name: String, formula: String, smiles: String, atoms: Vec<Atom>) -> Self {
+        Self { res_name, name, formula, smiles, hidden: false, atoms: Some(atoms) }
+    }
+
+    pub fn res_name(&self) -> &str {
+        &self.res_name
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.hidden
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MoleculeResources {
     molecules: Vec<MixtureComponentDetailed>
 }
 
+/// Entry in the molecules file, without the structure read from the .pdb file
+#[derive(Serialize)]
+struct MoleculeEntry<'a> {
+    res_name: &'a str,
+    name: &'a str,
+    formula: &'a str,
+    smiles: &'a str,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    hidden: bool,
+}
+
 impl MoleculeResources {
+    /// Load the molecules from a JSON file, reading each molecule's structure from
+    /// `molecules/{res_name}.pdb` in the resources directory.
+    /// Fails if any molecule is duplicated or its .pdb file can't be parsed.
     pub fn load(file: impl AsRef<Path>) -> anyhow::Result<Self> {
         let mut molecules: MoleculeResources =
             serde_json::from_str(&std::fs::read_to_string(&file)?)?;
         log::debug!("Beginning parsing .pdb files");
         let path = RESOURCES_DIR.get().unwrap().join("molecules");
+        let mut res_names = HashSet::with_capacity(molecules.molecules.len());
         for mol in molecules.molecules.iter_mut() {
+            if !res_names.insert(mol.res_name.clone()) {
+                return Err(anyhow!("Molecule {} is listed more than once", mol.res_name))
+            }
             mol.atoms = Some({
                 pdb2xyz(path.join(format!("{}.pdb", mol.res_name)))
-                    .expect(&format!("Failed to parse pdb file for {}", mol.res_name))
+                    .map_err(|e| anyhow!("Failed to parse pdb file for {}: {e}", mol.res_name))?
             });
+            if !path.join(format!("{}.itp", mol.res_name)).is_file() {
+                log::warn!("No .itp file for molecule {}. Workers will need one to use it.", mol.res_name);
+            }
         }
         log::debug!("Done parsing .pdb files");
         Ok(molecules)
     }
+
+    /// Write the molecules back to a JSON file in the same format as `load()` reads.
+    /// The file is replaced in one step, so it is never left partially written.
+    pub fn save(&self, file: impl AsRef<Path>) -> anyhow::Result<()> {
+        let entries: Vec<_> = self.molecules.iter().map(|mol| MoleculeEntry {
+            res_name: &mol.res_name,
+            name: &mol.name,
+            formula: &mol.formula,
+            smiles: &mol.smiles,
+            hidden: mol.hidden,
+        }).collect();
+        let mut contents = serde_json::to_string_pretty(&serde_json::json!({ "molecules": entries }))?;
+        contents.push('\n');
+        let file = file.as_ref();
+        let tmp = file.with_extension("json.tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, file)?;
+        Ok(())
+    }
+
+    pub fn get(&self, res_name: &str) -> Option<&MixtureComponentDetailed> {
+        self.molecules.iter().find(|mol| mol.res_name == res_name)
+    }
+
+    /// Molecules which should be offered to users
+    pub fn visible(&self) -> impl Iterator<Item = &MixtureComponentDetailed> {
+        self.molecules.iter().filter(|mol| !mol.hidden)
+    }
+
+    /// Check that every molecule in a submitted mixture is offered to users.
+    /// Returns the reason to show the user if not.
+    pub fn check_visible(&self, config: &PytfConfigMinimal) -> Result<(), String> {
+        for res_name in config.res_names() {
+            if !self.visible().any(|mol| mol.res_name == res_name) {
+                return Err(format!("Molecule {res_name} is not available."))
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a new molecule. Fails if a molecule with the same residue name already exists.
+    pub fn add(&mut self, molecule: MixtureComponentDetailed) -> anyhow::Result<()> {
+        if self.get(&molecule.res_name).is_some() {
+            return Err(anyhow!("Molecule {} already exists", molecule.res_name))
+        }
+        self.molecules.push(molecule);
+        Ok(())
+    }
+
+    /// Remove a molecule, returning it if it existed
+    pub fn remove(&mut self, res_name: &str) -> Option<MixtureComponentDetailed> {
+        let idx = self.molecules.iter().position(|mol| mol.res_name == res_name)?;
+        Some(self.molecules.remove(idx))
+    }
+
+    /// Hide or show a molecule. Returns false if the molecule doesn't exist.
+    pub fn set_hidden(&mut self, res_name: &str, hidden: bool) -> bool {
+        match self.molecules.iter_mut().find(|mol| mol.res_name == res_name) {
+            Some(mol) => {
+                mol.hidden = hidden;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.molecules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.molecules.is_empty()
+    }
 }
 
 /// Working directory to store PyThinFilm data
@@ -60,7 +174,8 @@ pub static WORK_DIR: OnceLock<PathBuf> = OnceLock::new();
 pub static RESOURCES_DIR: OnceLock<PathBuf> = OnceLock::new();
 /// Molecules available for deposition.
 /// Parsed from JSON and filled with molecule 3D structure from .pdb file.
-pub static AVAILABLE_MOLECULES: OnceLock<MoleculeResources> = OnceLock::new();
+/// Replaced when the molecules file is reloaded.
+pub static AVAILABLE_MOLECULES: OnceLock<Reloadable<MoleculeResources>> = OnceLock::new();
 
 /// Default number of deposition cycles if not specified
 pub const DEFAULT_N_CYCLES:   usize = 36;
@@ -83,6 +198,19 @@ impl PytfConfig {
         format!("{}.archive", self.name)
     }
 
+    /// Contents of the PyThinFilm config file for this job:
+    /// `base_config.yml` with this config appended.
+    ///
+    /// # Panics
+    /// If `RESOURCES_DIR` has not been set.
+    pub fn config_yml(&self) -> Result<String> {
+        let mut out = std::fs::read_to_string(RESOURCES_DIR.get().unwrap().join("base_config.yml"))?;
+        out.push('\n');
+        out.push_str(&serde_yaml::to_string(self)?);
+        out.push('\n');
+        Ok(out)
+    }
+
     /// Set the working directory to be a sub-directory with the
     /// same name as the job's name under the global `WORK_DIR` directory.
     /// If successful, returns `Some(self)` with the modified `work_directory` member.
@@ -109,6 +237,11 @@ pub struct PytfConfigMinimal {
 }
 
 impl PytfConfigMinimal {
+    /// Residue names of the molecules in the mixture
+    pub fn res_names(&self) -> impl Iterator<Item = &str> {
+        self.mixture.iter().filter(|mol| mol.ratio > 0).map(|mol| mol.res_name.as_str())
+    }
+
     /// Sanitize settings using `base_config` to generate a full configuration
     pub fn build(mut self, base_config: &ConfigSettings) -> PytfConfig {
         // Sort by res_name for consistent ordering
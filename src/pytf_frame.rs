@@ -35,6 +35,19 @@ pub struct TrajectorySegment {
     pub data: Bytes
 }
 
+/// Upper case element symbols. The position of each element is the atom type used in `TrajectorySegment`.
+pub const ELEMENTS: [&str; 118] = [
+    "H", "HE", "LI", "BE", "B", "C", "N", "O", "F", "NE", "NA", "MG", "AL", "SI", "P", "S",
+    "CL", "AR", "K", "CA", "SC", "TI", "V", "CR", "MN", "FE", "CO", "NI", "CU", "ZN", "GA",
+    "GE", "AS", "SE", "BR", "KR", "RB", "SR", "Y", "ZR", "NB", "MO", "TC", "RU", "RH",
+    "PD", "AG", "CD", "IN", "SN", "SB", "TE", "I", "XE", "CS", "BA", "LA", "CE", "PR",
+    "ND", "PM", "SM", "EU", "GD", "TB", "DY", "HO", "ER", "TM", "YB", "LU", "HF", "TA",
+    "W", "RE", "OS", "IR", "PT", "AU", "HG", "TL", "PB", "BI", "PO", "AT", "RN", "FR",
+    "RA", "AC", "TH", "PA", "U", "NP", "PU", "AM", "CM", "BK", "CF", "ES", "FM", "MD",
+    "NO", "LR", "RF", "DB", "SG", "BH", "HS", "MT", "DS", "RG", "CN", "NH", "FL", "MC",
+    "LV", "TS", "OG"
+];
+
 pub struct AtomNameMap {
     pub map: HashMap<&'static str, u8>,
 }
@@ -43,17 +56,7 @@ pub struct AtomNameMap {
 pub static ATOM_NAME_MAP: OnceLock<AtomNameMap> = OnceLock::new();
 impl AtomNameMap {
     pub fn create() -> Self {
-        Self { map: HashMap::from_iter([
-            "H", "HE", "LI", "BE", "B", "C", "N", "O", "F", "NE", "NA", "MG", "AL", "SI", "P", "S",
-            "CL", "AR", "K", "CA", "SC", "TI", "V", "CR", "MN", "FE", "CO", "NI", "CU", "ZN", "GA",
-            "GE", "AS", "SE", "BR", "KR", "RB", "SR", "Y", "ZR", "NB", "MO", "TC", "RU", "RH",
-            "PD", "AG", "CD", "IN", "SN", "SB", "TE", "I", "XE", "CS", "BA", "LA", "CE", "PR",
-            "ND", "PM", "SM", "EU", "GD", "TB", "DY", "HO", "ER", "TM", "YB", "LU", "HF", "TA",
-            "W", "RE", "OS", "IR", "PT", "AU", "HG", "TL", "PB", "BI", "PO", "AT", "RN", "FR",
-            "RA", "AC", "TH", "PA", "U", "NP", "PU", "AM", "CM", "BK", "CF", "ES", "FM", "MD",
-            "NO", "LR", "RF", "DB", "SG", "BH", "HS", "MT", "DS", "RG", "CN", "NH", "FL", "MC",
-            "LV", "TS", "OG"
-        ].iter().enumerate().map(|(idx, atom)| (*atom, idx as u8)))}
+        Self { map: HashMap::from_iter(ELEMENTS.iter().enumerate().map(|(idx, atom)| (*atom, idx as u8))) }
     }
 }
 
@@ -6,7 +6,7 @@ use bincode;
 
 use crate::{
     pytf::*,
-    pytf_config::{PytfConfig, RESOURCES_DIR},
+    pytf_config::PytfConfig,
     worker_client::{
         PytfWorker, WsMessage,
         PAUSE_HEADER, FAILED_HEADER, DONE_HEADER
@@ -208,9 +208,6 @@ impl PytfRunner {
         segment_proc: Addr<SegmentProcessor>,
         resuming: bool
     ) -> anyhow::Result<Self> {
-        // Get yaml string to append to config
-        let yml = serde_yaml::to_string(&config)?;
-
         // Create working directory if it doesn't already exist
         // Skip if resuming, since unpacking pause data will create it
         let mut config_yml = PathBuf::from(&config.work_directory);
@@ -226,16 +223,7 @@ impl PytfRunner {
         // Create config.yml in working directory if it doesn't already exist
         config_yml.push("config.yml");
         if !config_yml.is_file() {
-            std::fs::copy(RESOURCES_DIR.get().unwrap().join("base_config.yml"), &config_yml)?;
-
-            // Write config.yml to jobname directory
-            let mut config_file = std::fs::OpenOptions::new()
-                .write(true)
-                .append(true)
-                .open(&config_yml)?;
-
-            // Fill in config file
-            writeln!(config_file, "\n{}", yml)?;
+            std::fs::write(&config_yml, config.config_yml()?)?;
         }
 
         Ok(Self {
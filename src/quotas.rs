@@ -0,0 +1,112 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::server_config::QuotasSection;
+
+/// Period over which submissions count towards `max_submissions_per_minute`
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Work a user already has held by the server, not counting the job they're requesting
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserLoad {
+    /// Unfinished jobs
+    pub active_jobs: usize,
+    /// Cycles still to run across the unfinished jobs
+    pub queued_cycles: usize,
+}
+
+/// Keeps track of submissions to enforce the limits in `QuotasSection`
+#[derive(Debug)]
+pub struct Quotas {
+    settings: QuotasSection,
+    /// Times of each user's submissions within the last `RATE_WINDOW`, oldest first
+    submissions: HashMap<Arc<String>, VecDeque<Instant>>,
+}
+
+impl Quotas {
+    pub fn new(settings: QuotasSection) -> Self {
+        Self { settings, submissions: HashMap::new() }
+    }
+
+    /// Check whether `user` may start a job with `cycles` cycles to run, given the work
+    /// they already have. Returns the reason to show the user if not.
+    pub fn check(&mut self, user: &Arc<String>, load: UserLoad, cycles: usize, now: Instant) -> Result<(), String> {
+        let settings = &self.settings;
+        if settings.max_submissions_per_minute > 0 {
+            if let Some(recent) = self.submissions.get_mut(user) {
+                while recent.front().is_some_and(|&t| now.duration_since(t) >= RATE_WINDOW) {
+                    recent.pop_front();
+                }
+                if recent.len() >= settings.max_submissions_per_minute {
+                    let wait = RATE_WINDOW - now.duration_since(recent[0]);
+                    return Err(format!(
+                        "You can start at most {} simulations per minute. Please try again in {} seconds.",
+                        settings.max_submissions_per_minute, wait.as_secs_f64().ceil()
+                    ))
+                }
+            }
+        }
+        if settings.max_active_jobs > 0 && load.active_jobs >= settings.max_active_jobs {
+            return Err(format!(
+                "You already have {} unfinished simulations, which is the limit. \
+                Please go back to one of them, or try again once they've been cleared up in a few minutes.",
+                load.active_jobs
+            ))
+        }
+        if settings.max_queued_cycles > 0 && load.queued_cycles + cycles > settings.max_queued_cycles {
+            return Err(format!(
+                "This simulation would bring your unfinished simulations to {} cycles, over the limit of {}. \
+                Please try fewer cycles, or try again once your other simulations have finished.",
+                load.queued_cycles + cycles, settings.max_queued_cycles
+            ))
+        }
+        Ok(())
+    }
+
+    /// Count a submission by `user` towards the rate limit
+    pub fn record(&mut self, user: &Arc<String>, now: Instant) {
+        if self.settings.max_submissions_per_minute == 0 { return }
+        // Forget users without any recent submissions
+        self.submissions.retain(|_, recent| recent.back().is_some_and(|&t| now.duration_since(t) < RATE_WINDOW));
+        self.submissions.entry(user.clone()).or_default().push_back(now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let mut quotas = Quotas::new(QuotasSection { max_active_jobs: 2, max_submissions_per_minute: 3, max_queued_cycles: 100 });
+        let user = Arc::new("student".to_string());
+        let other = Arc::new("other".to_string());
+        let start = Instant::now();
+        let load = UserLoad { active_jobs: 1, queued_cycles: 36 };
+
+        for i in 0..3 {
+            assert!(quotas.check(&user, load, 36, start + Duration::from_secs(i)).is_ok());
+            quotas.record(&user, start + Duration::from_secs(i));
+        }
+        let reason = quotas.check(&user, load, 36, start + Duration::from_secs(10)).unwrap_err();
+        assert!(reason.contains("try again in 50 seconds"), "{reason}");
+        assert!(quotas.check(&other, load, 36, start + Duration::from_secs(10)).is_ok());
+        // The oldest submission is no longer counted after a minute
+        assert!(quotas.check(&user, load, 36, start + RATE_WINDOW).is_ok());
+
+        let later = start + 2 * RATE_WINDOW;
+        assert!(quotas.check(&user, UserLoad { active_jobs: 2, queued_cycles: 36 }, 36, later).is_err());
+        assert!(quotas.check(&user, load, 65, later).is_err());
+        assert!(quotas.check(&user, load, 64, later).is_ok());
+
+        let mut unlimited = Quotas::new(QuotasSection { max_active_jobs: 0, max_submissions_per_minute: 0, max_queued_cycles: 0 });
+        for _ in 0..20 {
+            assert!(unlimited.check(&user, UserLoad { active_jobs: 20, queued_cycles: 1000 }, 36, start).is_ok());
+            unlimited.record(&user, start);
+        }
+        assert!(unlimited.submissions.is_empty());
+    }
+}
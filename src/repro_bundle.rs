@@ -0,0 +1,209 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use serde_yaml::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    pytf_config::{PytfConfig, RESOURCES_DIR},
+    to_hex,
+};
+
+/// PyThinFilm revision installed by `setup_pyenv.sh`
+pub const PYTHINFILM_VERSION: &str = "git+https://github.com/ATB-UQ/PyThinFilm@c77ad51";
+
+/// GROMACS version from the installation instructions
+pub const GROMACS_VERSION: &str = "2023.2";
+
+/// Directory within the bundle holding molecule structure and topology files
+const MOLECULES_DIR: &str = "molecules";
+
+/// Script to run the simulation the same way a pytf-web worker does
+const RUN_SCRIPT: &str = r#"#!/usr/bin/env python3
+# Runs the deposition in the same way as a pytf-web worker.
+# Usage: python3 run.py (from the directory containing config.yml)
+from PyThinFilm.deposition import Deposition
+
+deposition = Deposition("config.yml", False)
+while deposition.run_ID < deposition.last_run_ID:
+    # pytf-web doesn't deposit anything in the final cycle, so that molecules which
+    # evaporated in the previous cycle can be removed
+    if deposition.run_ID == deposition.last_run_ID - 1:
+        deposition.insertions_per_run = 0
+    if not deposition.cycle():
+        raise SystemExit(f"Cycle {deposition.run_ID} failed")
+"#;
+
+/// Versions of the software used to run the job
+#[derive(Debug, Serialize)]
+pub struct ToolVersions {
+    #[serde(rename = "pytf-web")]
+    pub pytf_web: &'static str,
+    #[serde(rename = "PyThinFilm")]
+    pub pythinfilm: &'static str,
+    #[serde(rename = "GROMACS")]
+    pub gromacs: &'static str,
+}
+
+impl Default for ToolVersions {
+    fn default() -> Self {
+        Self {
+            pytf_web: env!("CARGO_PKG_VERSION"),
+            pythinfilm: PYTHINFILM_VERSION,
+            gromacs: GROMACS_VERSION,
+        }
+    }
+}
+
+/// Description of a reproduction bundle, stored in it as `manifest.json`
+#[derive(Debug, Serialize)]
+pub struct BundleManifest {
+    pub job: String,
+    pub n_cycles: usize,
+    /// Worker which last ran the job, if known
+    pub worker: Option<String>,
+    /// Time the bundle was made, in seconds since the unix epoch
+    pub created: u64,
+    pub tools: ToolVersions,
+    /// SHA-256 hash of each file in the bundle (apart from the manifest)
+    pub files: BTreeMap<String, String>,
+}
+
+/// Find a molecule file referenced by the config. Relative paths in `base_config.yml` are
+/// relative to the worker's working directory, so fall back to the resources directory.
+fn find_molecule_file(path: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    if path.is_file() {
+        return Ok(path.to_owned())
+    }
+    let Some(fname) = path.file_name() else { return Err(anyhow!("Invalid molecule file path {path:?}")) };
+    let fallback = RESOURCES_DIR.get().unwrap().join("molecules").join(fname);
+    if fallback.is_file() {
+        Ok(fallback)
+    } else {
+        Err(anyhow!("Molecule file {path:?} not found"))
+    }
+}
+
+/// Point the `pdb_file` and `itp_file` of a molecule at the bundle's molecules directory,
+/// adding the original files to `files`
+fn relocate_molecule(molecule: &mut Value, files: &mut BTreeMap<String, PathBuf>) -> anyhow::Result<()> {
+    for key in ["pdb_file", "itp_file"] {
+        let Some(Value::String(path)) = molecule.get_mut(key) else { continue };
+        let source = find_molecule_file(path)?;
+        let bundle_path = format!("{MOLECULES_DIR}/{}", source.file_name().unwrap().to_string_lossy());
+        files.insert(bundle_path.clone(), source);
+        *path = bundle_path;
+    }
+    Ok(())
+}
+
+/// Expand the job's config file as `PytfRunner` would write it, with molecule files relative
+/// to the bundle. Returns the config and the files it refers to, keyed by their path in the bundle.
+pub fn bundle_config(config: &PytfConfig) -> anyhow::Result<(String, BTreeMap<String, PathBuf>)> {
+    let mut yml: Value = serde_yaml::from_str(&config.config_yml()?)?;
+    let mut files = BTreeMap::new();
+    if let Some(substrate) = yml.get_mut("substrate") {
+        relocate_molecule(substrate, &mut files)?;
+    }
+    if let Some(Value::Sequence(mixture)) = yml.get_mut("mixture") {
+        for molecule in mixture {
+            relocate_molecule(molecule, &mut files)?;
+        }
+    }
+    // PyThinFilm creates the working directory relative to where it's run
+    yml["work_directory"] = Value::String("work".into());
+    Ok((serde_yaml::to_string(&yml)?, files))
+}
+
+fn append_file(tar: &mut tar::Builder<impl Write>, path: String, data: &[u8], mode: u32, mtime: u64) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    tar.append_data(&mut header, path, data)
+}
+
+/// Write a .tar.gz holding everything needed to rerun a job with PyThinFilm outside pytf-web:
+/// the expanded `config.yml`, the molecule files it uses, a script to run it, and a manifest
+/// of tool versions. Files are placed in a directory named after the job.
+pub fn write_bundle(config: &PytfConfig, worker: Option<String>, out: impl Write) -> anyhow::Result<()> {
+    let (config_yml, molecule_files) = bundle_config(config)?;
+    let mut contents: Vec<(String, Vec<u8>, u32)> = vec![
+        ("config.yml".into(), config_yml.into_bytes(), 0o644),
+        ("run.py".into(), RUN_SCRIPT.as_bytes().to_vec(), 0o755),
+    ];
+    for (bundle_path, source) in molecule_files {
+        contents.push((bundle_path, std::fs::read(source)?, 0o644));
+    }
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let manifest = BundleManifest {
+        job: config.name.clone(),
+        n_cycles: config.n_cycles,
+        worker,
+        created,
+        tools: ToolVersions::default(),
+        files: contents.iter().map(|(path, data, _)| (path.clone(), to_hex(&Sha256::digest(data)))).collect(),
+    };
+    contents.push(("manifest.json".into(), serde_json::to_vec_pretty(&manifest)?, 0o644));
+
+    let mut tar = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    for (path, data, mode) in contents {
+        append_file(&mut tar, format!("{}/{path}", config.name), &data, mode, created)?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+    use crate::pytf_config::PytfConfigMinimal;
+
+    #[test]
+    fn test_bundle() {
+        let _ = RESOURCES_DIR.set("resources".into());
+        let config: PytfConfigMinimal = serde_json::from_str(
+            r#"{"mixture": [{"res_name": "LCHU", "ratio": 1}], "deposition_velocity": 0.35}"#
+        ).unwrap();
+        let config = config.build(&Default::default());
+
+        let (yml, files) = bundle_config(&config).unwrap();
+        let yml: Value = serde_yaml::from_str(&yml).unwrap();
+        assert_eq!(yml["substrate"]["pdb_file"], Value::String("molecules/GRM_4nm.pdb".into()));
+        assert_eq!(yml["mixture"][0]["itp_file"], Value::String("molecules/LCHU.itp".into()));
+        assert_eq!(yml["name"], Value::String(config.name.clone()));
+        assert_eq!(yml["work_directory"], Value::String("work".into()));
+        assert_eq!(files.len(), 4);
+
+        let mut bundle = Vec::new();
+        write_bundle(&config, Some("worker".into()), &mut bundle).unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(bundle.as_slice()));
+        let mut names = Vec::new();
+        let mut manifest = String::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            if path.ends_with("manifest.json") {
+                entry.read_to_string(&mut manifest).unwrap();
+            }
+            names.push(path);
+        }
+        assert_eq!(names.len(), 7);
+        assert!(names.contains(&format!("{}/molecules/LCHU.pdb", config.name)));
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["tools"]["PyThinFilm"], PYTHINFILM_VERSION);
+        assert_eq!(manifest["files"].as_object().unwrap().len(), 6);
+    }
+}
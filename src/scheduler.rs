@@ -0,0 +1,118 @@
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// A job that's ready to run, as seen by the scheduler
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    /// Time the job was created or loaded from the archive
+    pub created: Instant,
+    /// Number of clients attached to the job
+    pub clients: usize,
+    /// Users whose clients are attached to the job
+    pub users: Vec<Arc<String>>,
+    /// Whether an instructor (or admin) is attached to the job
+    pub instructor: bool,
+}
+
+/// Number of jobs running for each user
+pub type Usage = HashMap<Arc<String>, usize>;
+
+/// Strategy for choosing which waiting job runs next
+pub trait SchedulingPolicy {
+    /// Rank of a job given the jobs each user already has running.
+    /// Jobs with lower ranks run first, and ties go to the oldest job.
+    fn rank(&self, job: &Candidate, usage: &Usage, now: Instant) -> f64;
+}
+
+/// Oldest job first
+pub struct Fifo;
+
+impl SchedulingPolicy for Fifo {
+    fn rank(&self, _job: &Candidate, _usage: &Usage, _now: Instant) -> f64 {
+        0.
+    }
+}
+
+/// Jobs which the most clients are waiting on first, weighted by how long they've been waiting
+/// so that jobs with few clients still run eventually
+pub struct ClientWeighted;
+
+impl SchedulingPolicy for ClientWeighted {
+    fn rank(&self, job: &Candidate, _usage: &Usage, now: Instant) -> f64 {
+        -now.duration_since(job.created).as_secs_f64() * job.clients as f64
+    }
+}
+
+/// Jobs of the users with the fewest running jobs first, so that a burst of jobs
+/// from one user can't hold up everyone else
+pub struct FairShare;
+
+impl SchedulingPolicy for FairShare {
+    fn rank(&self, job: &Candidate, usage: &Usage, _now: Instant) -> f64 {
+        job.users.iter()
+            .map(|user| usage.get(user).copied().unwrap_or(0))
+            .min()
+            .unwrap_or(0) as f64
+    }
+}
+
+/// Scheduling policies which can be selected in the server config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    Fifo,
+    Clients,
+    #[default]
+    FairShare,
+}
+
+impl Policy {
+    pub fn build(self) -> Box<dyn SchedulingPolicy> {
+        match self {
+            Self::Fifo      => Box::new(Fifo),
+            Self::Clients   => Box::new(ClientWeighted),
+            Self::FairShare => Box::new(FairShare),
+        }
+    }
+}
+
+/// Chooses jobs for idle workers
+pub struct Scheduler {
+    policy: Box<dyn SchedulingPolicy>,
+    /// Run jobs which instructors are attached to before any others
+    instructor_priority: bool,
+}
+
+impl Scheduler {
+    pub fn new(policy: Policy, instructor_priority: bool) -> Self {
+        Self { policy: policy.build(), instructor_priority }
+    }
+
+    /// Choose up to `n` of `candidates` to run, in the order they should be assigned.
+    /// Returns indices into `candidates`. `usage` is updated as jobs are chosen.
+    pub fn choose(&self, candidates: &[Candidate], usage: &mut Usage, n: usize, now: Instant) -> Vec<usize> {
+        let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+        let mut chosen = Vec::with_capacity(n.min(candidates.len()));
+        while chosen.len() < n {
+            let priority = self.instructor_priority && remaining.iter().any(|&i| candidates[i].instructor);
+            let Some(pos) = remaining.iter()
+                .enumerate()
+                .filter(|(_, &i)| !priority || candidates[i].instructor)
+                .min_by(|(_, &a), (_, &b)| {
+                    let (a, b) = (&candidates[a], &candidates[b]);
+                    self.policy.rank(a, usage, now)
+                        .total_cmp(&self.policy.rank(b, usage, now))
+                        .then(a.created.cmp(&b.created))
+                })
+                .map(|(pos, _)| pos)
+            else { break };
+            let idx = remaining.swap_remove(pos);
+            for user in &candidates[idx].users {
+                *usage.entry(user.clone()).or_default() += 1;
+            }
+            chosen.push(idx);
+        }
+        chosen
+    }
+}
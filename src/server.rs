@@ -1,16 +1,19 @@
-use std::{io::{Error, ErrorKind}, sync::Arc};
+use std::{io::{Error, ErrorKind}, net::{IpAddr, SocketAddr}};
 
 use actix::{Addr, Actor};
-use actix_cors::Cors;
 use actix_files::{Files, NamedFile};
+use actix_rt::signal::unix::{signal, SignalKind};
 use actix_identity::{Identity, IdentityMiddleware};
-use actix_session::{storage::RedisSessionStore, SessionMiddleware};
+use actix_session::SessionMiddleware;
 use actix_web::{
-    cookie::Key, http, get, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+    dev::Service, http, get, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer,
     Responder
 };
 
 mod job_queue;
+mod job_history;
+mod scheduler;
+mod quotas;
 use actix_web_actors::ws;
 use job_queue::*;
 
@@ -21,18 +24,46 @@ use worker_session::WorkerWsSession;
 
 mod server_args;
 use server_args::{parse_args, ServerArgs};
+mod server_config;
+use server_config::config;
+
+mod user_admin;
+mod tokens;
+mod oidc_login;
+mod account;
+mod jobs_api;
+mod admin_status;
+mod metrics;
+mod health;
+mod cors;
+mod catalogue;
+
+mod cookie_key;
+
+mod session_store;
+use session_store::AnySessionStore;
+
+mod login_throttle;
+use login_throttle::LoginThrottle;
 
 use pytf_web::{
-    authentication::{self, LoginToken, UserCredentials},
+    authentication::{self, LoginRequest, LoginToken, Role, SessionUser},
+    guests::{GuestManager, GUESTS},
+    oidc::{OidcProvider, OIDC},
+    user_sessions::SESSIONS,
     input_config::ConfigSettings,
-    pytf_config::{AVAILABLE_MOLECULES, RESOURCES_DIR},
-    pytf_frame::WS_FRAME_SIZE_LIMIT
+    pytf_config::AVAILABLE_MOLECULES,
+    Reloadable,
 };
 
-const FRONTEND_ROOT: &'static str = "./pytf-viewer/build";
+/// How often to check for expired guests
+const GUEST_EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How often to write the times sessions were last used to the sessions file
+const SESSION_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
 
 async fn index(request: HttpRequest) -> impl Responder {
-    match NamedFile::open_async(format!("{FRONTEND_ROOT}/index.html")).await {
+    match NamedFile::open_async(config().server.frontend_root.join("index.html")).await {
         Ok(file) => file.respond_to(&request),
         Err(e) => {
             log::error!("Error fetching index.html: {e}");
@@ -42,128 +73,254 @@ async fn index(request: HttpRequest) -> impl Responder {
     }
 }
 
+/// Get the IP address of the client for login throttling
+fn client_ip(request: &HttpRequest, trust_proxy: bool) -> Option<IpAddr> {
+    if trust_proxy {
+        let info = request.connection_info();
+        let addr = info.realip_remote_addr()?;
+        addr.parse::<IpAddr>().ok()
+            .or_else(|| addr.parse::<SocketAddr>().ok().map(|a| a.ip()))
+    } else {
+        request.peer_addr().map(|a| a.ip())
+    }
+}
+
 #[post("/login")]
-async fn login(request: HttpRequest, credentials: web::Json<UserCredentials>) -> impl Responder {
+async fn login(
+    request: HttpRequest,
+    login_request: web::Json<LoginRequest>,
+    throttle: web::Data<LoginThrottle>,
+) -> Result<HttpResponse, actix_web::Error> {
     log::debug!("Received login request.");
-    if !authentication::USER_DB
-        .get()
-        .unwrap()
-        .validate_user(&credentials)
-    {
-        return HttpResponse::Unauthorized().body("Incorrect username or password.");
+    let credentials = match login_request.into_inner() {
+        LoginRequest::Credentials(credentials) => credentials,
+        LoginRequest::Guest { guest: true } => return Ok(guest_login(&request)),
+        LoginRequest::Guest { guest: false } => {
+            return Ok(HttpResponse::BadRequest().body("Missing username and password."))
+        }
+    };
+    let username = credentials.username.clone();
+    let ip = client_ip(&request, throttle.settings.trust_proxy);
+    if let Err(remaining) = throttle.check(&username, ip) {
+        // Round up so clients don't retry just before the lockout ends
+        let secs = remaining.as_secs() + (remaining.subsec_nanos() > 0) as u64;
+        log::debug!("Rejected login attempt for \"{username}\" during lockout");
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((http::header::RETRY_AFTER, secs))
+            .body(format!("Too many failed login attempts. Try again in {secs} seconds.")));
     }
 
-    match Identity::login(&request.extensions(), credentials.get_id()) {
-        Ok(user) => {
-            let Ok(user_id) = user.id() else {
-                log::error!("Failed to get id from user identity.");
-                return HttpResponse::InternalServerError().body("User session corrupted.")
-            };
-            log::info!("Logged in ({user_id})");
-            HttpResponse::Ok().json(LoginToken::from(user_id))
+    // Password hashing is slow, so keep it off the worker thread
+    let Some(mut session_user) = web::block(move || {
+        authentication::USER_DB
+            .get()
+            .unwrap()
+            .validate_user(&credentials)
+    }).await?
+    else {
+        throttle.record_failure(&username, ip);
+        return Ok(HttpResponse::Unauthorized().body("Incorrect username or password."));
+    };
+    throttle.record_success(&username);
+    account::start_session(&request, &mut session_user, throttle.settings.trust_proxy).await?;
+
+    Ok(match Identity::login(&request.extensions(), session_user.to_id()) {
+        Ok(_) => {
+            log::info!("Logged in ({}, {})", session_user.username, session_user.role);
+            HttpResponse::Ok().json(LoginToken::from(session_user))
         }
         Err(e) => HttpResponse::ExpectationFailed().body(format!("{e}")),
+    })
+}
+
+/// Log in as a new guest, if guest mode is enabled
+fn guest_login(request: &HttpRequest) -> HttpResponse {
+    let Some(guests) = GUESTS.get() else {
+        return HttpResponse::Forbidden().body("Guest mode is not enabled.")
+    };
+    let Some(guest) = guests.create() else {
+        return HttpResponse::ServiceUnavailable().body("Too many guests at the moment. Try again later.")
+    };
+    match Identity::login(&request.extensions(), guest.to_id()) {
+        Ok(_) => {
+            log::info!("Logged in ({}, {})", guest.username, guest.role);
+            HttpResponse::Ok().json(LoginToken::from(guest))
+        }
+        Err(e) => {
+            guests.remove(&guest.username);
+            HttpResponse::ExpectationFailed().body(format!("{e}"))
+        }
     }
 }
 
+/// Whether guest mode is enabled, so the login page knows whether to offer it
+#[get("/guest-mode")]
+async fn get_guest_mode() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "enabled": GUESTS.get().is_some() }))
+}
+
 #[post("/logout")]
 async fn logout(user: Identity) -> impl Responder {
-    let Ok(user_id) = user.id() else {
+    let Some(session_user) = SessionUser::from_identity(&user) else {
         log::error!("Failed to get id from user identity.");
         return HttpResponse::InternalServerError().body("User session corrupted.")
     };
-    log::info!("Logged out ({user_id})");
+    if session_user.role == Role::Guest {
+        if let Some(guests) = GUESTS.get() {
+            guests.remove(&session_user.username);
+        }
+    }
+    if let Some(id) = session_user.session.clone() {
+        let username = session_user.username.clone();
+        match web::block(move || SESSIONS.get().unwrap().revoke(&username, &id)).await {
+            Ok(Err(e)) => log::debug!("Failed to remove session of \"{}\": {e}", session_user.username),
+            Err(e) => log::debug!("Failed to remove session of \"{}\": {e}", session_user.username),
+            Ok(Ok(())) => (),
+        }
+    }
+    log::info!("Logged out ({})", session_user.username);
     user.logout();
     HttpResponse::Ok().finish()
 }
 
 #[post("/user-token")]
-async fn user_token(user: Identity) -> impl Responder {
-    let Ok(user_id) = user.id() else {
-        log::error!("Failed to get id from user identity.");
-        return HttpResponse::InternalServerError().body("User session corrupted.")
-    };
-    log::info!("Sending cached token ({})", user_id);
-    HttpResponse::Ok().json(LoginToken::from(user_id))
+async fn user_token(user: SessionUser) -> impl Responder {
+    log::info!("Sending cached token ({})", user.username);
+    HttpResponse::Ok().json(LoginToken::from(user))
 }
 
 #[get("/socket")]
 async fn socket(
     req: HttpRequest,
-    user: Identity,
+    user: SessionUser,
     stream: web::Payload,
     srv: web::Data<Addr<JobServer>>,
-    input_config: web::Data<Arc<ConfigSettings>>,
+    input_config: web::Data<Reloadable<ConfigSettings>>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let Ok(uid) = user.id() else {
-        return Err(Error::new(ErrorKind::InvalidData, "User session corrupted.").into())
-    };
-    if uid == "worker" {
-        ws::WsResponseBuilder::new(
-            WorkerWsSession::new(srv.get_ref().clone()),
-            &req,
-            stream,
-        ).frame_size(WS_FRAME_SIZE_LIMIT).start()
-    } else {
-        ws::WsResponseBuilder::new(
-            ClientWsSession::new(uid, srv.get_ref().clone(), input_config.get_ref().clone()),
-            &req,
-            stream,
-        ).frame_size(WS_FRAME_SIZE_LIMIT).start()
+    match user.role {
+        Role::Worker => {
+            ws::WsResponseBuilder::new(
+                WorkerWsSession::new(user.username, srv.get_ref().clone()),
+                &req,
+                stream,
+            ).frame_size(config().websocket.frame_size_limit).start()
+        }
+        Role::Admin | Role::Instructor | Role::Student | Role::Guest => {
+            ws::WsResponseBuilder::new(
+                ClientWsSession::new(user, srv.get_ref().clone(), input_config.into_inner()),
+                &req,
+                stream,
+            ).frame_size(config().websocket.frame_size_limit).start()
+        }
     }
 }
 
 #[get("/molecules")]
-async fn molecules(_user: Identity) -> impl Responder {
-    HttpResponse::Ok().json(AVAILABLE_MOLECULES.get())
+async fn molecules(user: SessionUser) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Guest)?;
+    let molecules = AVAILABLE_MOLECULES.get().unwrap().get();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "molecules": molecules.visible().collect::<Vec<_>>() })))
 }
 
 #[get("/input-config")]
-async fn get_input_config(_user: Identity, input_config: web::Data<Arc<ConfigSettings>>) -> impl Responder {
-    HttpResponse::Ok().json(&**input_config.get_ref())
+async fn get_input_config(user: SessionUser, input_config: web::Data<Reloadable<ConfigSettings>>)
+-> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Guest)?;
+    Ok(HttpResponse::Ok().json(&*input_config.get()))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    let Some(ServerArgs { address: server, redis_address: redis}) =
+    let Some(ServerArgs {
+        address: server,
+        redis_address: redis,
+        session_backend,
+        login_throttle,
+        cookie_key,
+        previous_cookie_key,
+        cookie_secure,
+        tls,
+        guest_mode,
+        oidc,
+    }) =
     (match parse_args() {
         Ok(addr) => addr,
         Err(e) => {
             return Err(Error::new(ErrorKind::InvalidInput,
-                format!("Error parsing command line arguments: {e}")));
+                format!("Error in server configuration: {e}")));
         }
     }) else { return Ok(()) };
 
 
-    let secret_key = Key::generate();
-    let redis_store = RedisSessionStore::new(format!("redis://{}:{}", redis.address, redis.port))
-        .await
-        .expect("Could not connect to redis-server");
+    let session_store = AnySessionStore::new(
+        &session_backend,
+        format!("redis://{}:{}", redis.address, redis.port)
+    ).await.map_err(|e| Error::new(ErrorKind::Other, format!("Could not create session store: {e}")))?;
 
     let job_server = JobServer::new().start();
-    let input_config = Arc::new(
-        ConfigSettings::open(RESOURCES_DIR.get().unwrap().join("input_config.yml"))
+    let login_throttle = web::Data::new(LoginThrottle::new(login_throttle));
+    let input_config = web::Data::new(Reloadable::new(
+        ConfigSettings::open(config().paths.input_config_file())
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?
-    );
-
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            // TODO: Can we make this more restrictive?
-            // .allowed_origin(format!("http://localhost:{port}").as_str())
-            // .allowed_origin(format!("http://127.0.0.1:{port}").as_str())
-            .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST"])
-            .allowed_headers(vec![
-                http::header::AUTHORIZATION,
-                http::header::ACCEPT,
-                http::header::CONTENT_TYPE,
-            ])
-            .max_age(3600);
+    ));
+
+    if let Some(guest_settings) = guest_mode {
+        let _ = GUESTS.set(GuestManager::new(guest_settings, &input_config.get()));
+        log::info!("Guest mode enabled");
+        // Close the sessions of guests once their time is up
+        let expiry_server = job_server.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(GUEST_EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                for name in GUESTS.get().unwrap().remove_expired() {
+                    let _ = expiry_server.send(UserDeactivated { name: name.clone(), removed: true }).await;
+                    log::info!("Guest session of \"{name}\" expired");
+                }
+            }
+        });
+    }
+
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(SESSION_SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match web::block(|| SESSIONS.get().unwrap().save_last_seen()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::error!("Failed to save sessions: {e}"),
+                Err(e) => log::error!("Failed to save sessions: {e}"),
+            }
+        }
+    });
+
+    if let Some(oidc_settings) = oidc {
+        let provider = OidcProvider::discover(oidc_settings).await
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Could not set up OIDC login: {e}")))?;
+        let _ = OIDC.set(provider);
+    }
+
+    // Reload the users file, molecules and input configuration on SIGHUP
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reload_server = job_server.clone();
+    let reload_input_config = input_config.clone();
+    actix_rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("Received SIGHUP");
+            user_admin::reload_users(&reload_server).await;
+            if let Err(e) = catalogue::reload(&reload_server, &reload_input_config).await {
+                log::error!("Failed to reload resources: {e}. Keeping the current molecules and settings.");
+            }
+        }
+    });
+
+    let http_server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(job_server.clone()))
-            .app_data(web::Data::new(input_config.clone()))
+            .app_data(input_config.clone())
+            .app_data(login_throttle.clone())
+            .app_data(web::Data::new(session_store.clone()))
             .wrap(
                 IdentityMiddleware::builder()
                     .visit_deadline(Some(std::time::Duration::from_secs(24 * 60 * 60)))
@@ -172,23 +329,51 @@ async fn main() -> std::io::Result<()> {
             // SessionMiddleware must be mounted *after* IdentityMiddleware
             .wrap(
                 SessionMiddleware::builder(
-                    redis_store.clone(),
-                    secret_key.clone(),
+                    session_store.clone(),
+                    cookie_key.clone(),
                 )
-                .cookie_secure(false)
+                .cookie_name(cookie_key::SESSION_COOKIE.into())
+                .cookie_secure(cookie_secure)
                 .build()
             )
-            .wrap(cors)
+            // Must be mounted *after* SessionMiddleware so cookies are rotated before it sees them
+            .wrap_fn({
+                let keys = previous_cookie_key.clone().map(|prev| (cookie_key.clone(), prev));
+                move |mut req, srv| {
+                    if let Some((current, previous)) = &keys {
+                        cookie_key::rotate_session_cookie(&mut req, current, previous);
+                    }
+                    srv.call(req)
+                }
+            })
+            .wrap(cors::cors(&config().cors, config().login.trust_proxy))
             .service(web::resource("/").to(index))
             .service(login)
             .service(logout)
             .service(user_token)
+            .service(get_guest_mode)
             .service(socket)
+            .configure(user_admin::configure)
+            .configure(tokens::configure)
+            .configure(oidc_login::configure)
+            .configure(account::configure)
+            .configure(jobs_api::configure)
+            .configure(admin_status::configure)
+            .configure(metrics::configure)
+            .configure(health::configure)
+            .configure(catalogue::configure)
             .service(molecules)
             .service(get_input_config)
-            .service(Files::new("/", FRONTEND_ROOT))
-    })
-    .bind((server.address, server.port))?
-    .run()
-    .await
+            .service(Files::new("/", &config().server.frontend_root))
+    });
+    let http_server = match tls {
+        Some(tls) => {
+            log::info!("Serving https on {}:{}", server.address, server.port);
+            http_server.bind_rustls((server.address, server.port), tls)?
+        }
+        None => http_server.bind((server.address, server.port))?,
+    };
+    http_server.run().await?;
+    // Don't lose when sessions were last used since the last periodic save
+    SESSIONS.get().unwrap().save_last_seen()
 }
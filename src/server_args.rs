@@ -1,11 +1,28 @@
-use std::{io::{Error, ErrorKind}, path::PathBuf};
+use std::{io::{Error, ErrorKind}, path::{Path, PathBuf}};
+
+use clap::Parser;
 
 use pytf_web::{
     pytf_config::{AVAILABLE_MOLECULES, MoleculeResources, RESOURCES_DIR},
-    authentication::{USER_DB, UserDB}
+    api_tokens::{TOKEN_DB, TokenDB},
+    authentication::{USER_DB, UserDB},
+    user_sessions::{SESSIONS, SessionRegistry},
+    guests::GuestSettings,
+    oidc::OidcSettings,
+    tls,
+    Reloadable,
 };
 
-use crate::job_queue::ARCHIVE_DIR;
+use actix_web::cookie::Key;
+
+use crate::{
+    cookie_key,
+    cors,
+    job_queue::ARCHIVE_DIR,
+    login_throttle::ThrottleSettings,
+    server_config::{ServerConfig, CONFIG_FILE_ENV, SERVER_CONFIG},
+    session_store::SessionBackend,
+};
 
 
 #[derive(Clone, Debug)]
@@ -14,135 +31,330 @@ pub struct Connection {
     pub port: u16,
 }
 
-/// Parse command line arguments for the server to set relevant globals
-/// Returns connection details for the server and the redis server (in that order)
-#[derive(Clone, Debug)]
+/// Settings the server is started with, built by `parse_args()` from the layered
+/// configuration: defaults, then the config file, then `PYTF_` environment variables,
+/// then command line flags. Files the configuration refers to have already been loaded.
+#[derive(Clone)]
 pub struct ServerArgs {
     pub address: Connection,
     pub redis_address: Connection,
+    pub session_backend: SessionBackend,
+    pub login_throttle: ThrottleSettings,
+    /// Key for encrypting session cookies
+    pub cookie_key: Key,
+    /// Previous cookie key, to keep sessions made before a key rotation
+    pub previous_cookie_key: Option<Key>,
+    pub cookie_secure: bool,
+    /// Serve over https if set
+    pub tls: Option<rustls::ServerConfig>,
+    /// Guest mode settings, if guest mode is enabled
+    pub guest_mode: Option<GuestSettings>,
+    /// OIDC login settings, if OIDC login is enabled
+    pub oidc: Option<OidcSettings>,
 }
 
-pub fn parse_args() -> anyhow::Result<Option<ServerArgs>> {
-    let mut args = std::env::args().skip(1).peekable();
-    let mut resources = None;
-    let mut archive_dir = None;
-    let mut mols_file = None;
-    let mut users_file = None;
-    let mut address = Connection { address: "127.0.0.1".into(), port: 8080 };
-    let mut redis_address = Connection { address: "127.0.0.1".into(), port: 6379 };
-    while let Some(arg) = args.next() {
-        match arg.as_ref() {
-            "-m" | "--molecules" => {
-                mols_file = args.next();
-                if mols_file.is_none() {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for molecules .json file"))?;
-                };
-            }
-            "-r" | "--resources" => {
-                resources = args.next();
-                if resources.is_none() {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for resources directory"))?;
-                }
-            }
-            "-a" | "--archive" => {
-                archive_dir = args.next();
-                if archive_dir.is_none() {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for archive directory"))?;
-                }
-            }
-            "-u" | "--users" => {
-                users_file = args.next();
-                if users_file.is_none() {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for users file"))?;
-                };
-            }
-            "-ip" => {
-                let Some(addr) = args.next() else {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for server ip address"))?;
-                    unreachable!();
-                };
-                address.address = addr;
-            }
-            "-p" | "--port" => {
-                let Some(port) = args.next() else {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for server port"))?;
-                    unreachable!();
-                };
-                address.port = port.parse()?;
-            }
-            "--redis-ip" => {
-                let Some(addr) = args.next() else {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for server ip address"))?;
-                    unreachable!();
-                };
-                redis_address.address = addr;
-            }
-            "--redis-port" => {
-                let Some(port) = args.next() else {
-                    Err(Error::new(ErrorKind::InvalidInput, "Missing argument for server port"))?;
-                    unreachable!();
-                };
-                redis_address.port = port.parse()?;
-            }
-            "-h" | "--help" => {
-                println!("{HELP_MSG}");
-                return Ok(None);
-            }
-            _ => {
-                Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("Unknown argument {arg}")
-                ))?;
-            }
+/// Command line options. Most are shortcuts for a setting in the config file, and
+/// override both the config file and PYTF_<SECTION>_<KEY> environment variables.
+#[derive(Parser, Debug)]
+#[command(name = "pytf-server", version, about = "Web server for PyThinFilm simulations")]
+struct Cli {
+    /// TOML config file. See docs for the available settings.
+    #[arg(short, long, value_name = "FILE", env = CONFIG_FILE_ENV)]
+    config: Option<PathBuf>,
+
+    /// Print the effective settings as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Override a setting from the config file, e.g. --set jobs.max_age_secs=600.
+    /// Can be used multiple times.
+    #[arg(long = "set", value_name = "SECTION.KEY=VALUE")]
+    settings: Vec<String>,
+
+    /// File containing usernames, roles and password hashes, one per line, separated
+    /// by commas. Can be generated from plaintext .csv using pytf-hash-users. [paths.users]
+    #[arg(short, long, value_name = "FILE")]
+    users: Option<PathBuf>,
+
+    /// File to store (hashed) API tokens in. Created if it doesn't exist. Defaults to the
+    /// users file with .tokens appended. [paths.tokens]
+    #[arg(short, long, value_name = "FILE")]
+    tokens: Option<PathBuf>,
+
+    /// File to keep the list of logged in sessions in. Created if it doesn't exist.
+    /// Defaults to the users file with .sessions appended. [paths.session_list]
+    #[arg(long, value_name = "FILE")]
+    session_list: Option<PathBuf>,
+
+    /// JSON file containing the available molecules. Defaults to molecules.json in the
+    /// resources directory. [paths.molecules]
+    #[arg(short, long, value_name = "FILE")]
+    molecules: Option<PathBuf>,
+
+    /// Resources directory. Defaults to ./resources [paths.resources]
+    #[arg(short, long, value_name = "DIR")]
+    resources: Option<PathBuf>,
+
+    /// Directory to store old inactive jobs. Defaults to ./archive [paths.archive]
+    #[arg(short, long, value_name = "DIR")]
+    archive: Option<PathBuf>,
+
+    /// IP address of the server (-ip also works). Defaults to 127.0.0.1 [server.address]
+    #[arg(long, value_name = "IP")]
+    ip: Option<String>,
+
+    /// Port to listen on. Defaults to 8080 [server.port]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Where to store user sessions: redis, memory (lost on restart) or file (one file per
+    /// session in --session-dir). Defaults to redis [sessions.store]
+    #[arg(short, long, value_name = "STORE")]
+    session_store: Option<String>,
+
+    /// Directory for the file session store. Defaults to ./sessions [sessions.dir]
+    #[arg(long, value_name = "DIR")]
+    session_dir: Option<PathBuf>,
+
+    /// IP address of the Redis server. Defaults to 127.0.0.1 [sessions.redis_address]
+    #[arg(long, value_name = "IP")]
+    redis_ip: Option<String>,
+
+    /// Port of the Redis server. Defaults to 6379 [sessions.redis_port]
+    #[arg(long, value_name = "PORT")]
+    redis_port: Option<u16>,
+
+    /// Failed login attempts allowed for a username before it is locked out.
+    /// Defaults to 5 [login.attempts]
+    #[arg(long, value_name = "NUM")]
+    login_attempts: Option<u32>,
+
+    /// Failed login attempts allowed from an IP address before it is locked out.
+    /// Defaults to 20 [login.attempts_ip]
+    #[arg(long, value_name = "NUM")]
+    login_attempts_ip: Option<u32>,
+
+    /// Length of the first lockout. Doubles with each further lockout of the same
+    /// username or IP address. Defaults to 30 [login.lockout_secs]
+    #[arg(long, value_name = "SECS")]
+    login_lockout: Option<u64>,
+
+    /// Maximum lockout length. Failed attempts are forgotten after this long without
+    /// any attempts. Defaults to 3600 [login.max_lockout_secs]
+    #[arg(long, value_name = "SECS")]
+    login_max_lockout: Option<u64>,
+
+    /// Use the X-Forwarded-For header to get client IP addresses for login limits. Only use
+    /// this behind a reverse proxy which sets the header. [login.trust_proxy]
+    #[arg(long)]
+    trust_proxy: bool,
+
+    /// PEM file containing the TLS certificate chain. Serves https (and wss for web sockets)
+    /// instead of http. Requires --tls-key. [tls.cert]
+    #[arg(long, value_name = "FILE")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file containing the private key for --tls-cert. [tls.key]
+    #[arg(long, value_name = "FILE")]
+    tls_key: Option<PathBuf>,
+
+    /// File containing the key used to encrypt session cookies, so users stay logged in when
+    /// the server restarts. Generated if it doesn't exist. [cookies.key]
+    #[arg(long, value_name = "FILE")]
+    cookie_key: Option<PathBuf>,
+
+    /// Previous cookie key file. Sessions made with this key are kept after rotating to a
+    /// new --cookie-key. [cookies.old_key]
+    #[arg(long, value_name = "FILE")]
+    cookie_key_old: Option<PathBuf>,
+
+    /// Only send session cookies over https. Always on with --tls-cert. [cookies.secure]
+    #[arg(long)]
+    cookie_secure: bool,
+
+    /// Enable login through an OpenID Connect identity provider, with the settings in the
+    /// specified yaml file. See docs for details. [login.oidc]
+    #[arg(long, value_name = "FILE")]
+    oidc: Option<PathBuf>,
+
+    /// Enable guest mode with the settings in the specified yaml file. See docs for
+    /// details. [login.guest_mode]
+    #[arg(long, value_name = "FILE")]
+    guest_mode: Option<PathBuf>,
+}
+
+impl Cli {
+    /// All settings given on the command line, as `section.key=value`. Settings from
+    /// their own flags come after (and so override) those from --set.
+    fn settings(&self) -> Vec<String> {
+        fn path(p: &Option<PathBuf>) -> Option<String> {
+            p.as_ref().map(|p| p.to_string_lossy().into_owned())
         }
+        let flags = [
+            ("paths.users", path(&self.users)),
+            ("paths.tokens", path(&self.tokens)),
+            ("paths.session_list", path(&self.session_list)),
+            ("paths.molecules", path(&self.molecules)),
+            ("paths.resources", path(&self.resources)),
+            ("paths.archive", path(&self.archive)),
+            ("server.address", self.ip.clone()),
+            ("server.port", self.port.map(|p| p.to_string())),
+            ("sessions.store", self.session_store.clone()),
+            ("sessions.dir", path(&self.session_dir)),
+            ("sessions.redis_address", self.redis_ip.clone()),
+            ("sessions.redis_port", self.redis_port.map(|p| p.to_string())),
+            ("login.attempts", self.login_attempts.map(|n| n.to_string())),
+            ("login.attempts_ip", self.login_attempts_ip.map(|n| n.to_string())),
+            ("login.lockout_secs", self.login_lockout.map(|n| n.to_string())),
+            ("login.max_lockout_secs", self.login_max_lockout.map(|n| n.to_string())),
+            ("login.trust_proxy", self.trust_proxy.then(|| "true".into())),
+            ("login.oidc", path(&self.oidc)),
+            ("login.guest_mode", path(&self.guest_mode)),
+            ("tls.cert", path(&self.tls_cert)),
+            ("tls.key", path(&self.tls_key)),
+            ("cookies.key", path(&self.cookie_key)),
+            ("cookies.old_key", path(&self.cookie_key_old)),
+            ("cookies.secure", self.cookie_secure.then(|| "true".into())),
+        ];
+        self.settings.iter().cloned()
+            .chain(flags.into_iter().filter_map(|(key, value)| Some(format!("{key}={}", value?))))
+            .collect()
     }
+}
+
+/// Append a suffix to a file name, e.g. users.hashed -> users.hashed.tokens
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Build the server configuration from the config file, environment and command line,
+/// then load the files it refers to and set the relevant globals.
+/// Returns `None` if the server shouldn't be started (e.g. for --print-config).
+pub fn parse_args() -> anyhow::Result<Option<ServerArgs>> {
+    // -ip was accepted before the arguments were parsed with clap, so keep it working
+    let cli = Cli::parse_from(std::env::args()
+        .map(|arg| if arg == "-ip" { "--ip".into() } else { arg }));
+    let config = ServerConfig::layered(cli.config.as_deref(), std::env::vars(), &cli.settings())?;
+    if cli.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(None);
+    }
+    let args = load_config(&config)?;
+    let _ = SERVER_CONFIG.set(config);
+    Ok(Some(args))
+}
+
+/// Load the files referred to by the configuration and set the relevant globals
+fn load_config(config: &ServerConfig) -> anyhow::Result<ServerArgs> {
+    let paths = &config.paths;
 
     // Resources directory
-    let _ = RESOURCES_DIR.set(PathBuf::from(resources.unwrap_or("resources".into())));
-    let _ = ARCHIVE_DIR.set(PathBuf::from(archive_dir.unwrap_or("archive".into())));
+    let _ = RESOURCES_DIR.set(paths.resources.clone());
+    let _ = ARCHIVE_DIR.set(paths.archive.clone());
 
     // Molecules
-    let mols_file = match mols_file {
-        Some(fname) => std::path::PathBuf::from(fname),
-        None => std::path::PathBuf::from(RESOURCES_DIR.get().unwrap()).join("molecules.json"),
-    };
-    let _ = AVAILABLE_MOLECULES.set(MoleculeResources::load(mols_file)?);
+    let _ = AVAILABLE_MOLECULES.set(Reloadable::new(MoleculeResources::load(paths.molecules_file())?));
 
     // Users
-    let _ = USER_DB.set(match users_file {
-        Some(fname) => UserDB::load(std::path::PathBuf::from(fname))?,
+    let _ = USER_DB.set(match &paths.users {
+        Some(fname) => UserDB::load(fname)?,
         None => {
             log::warn!("No user database provided.");
             UserDB::default()
         }
     });
-    Ok(Some(ServerArgs {address, redis_address}))
-}
-
-const HELP_MSG: &str = "
-USAGE: pytf-server [OPTIONS]
-
-  OPTION          ARG       DESCRIPTION
 
-  -u/--users      <file>    File containing usernames and password hashes, one per line,
-                            separated by a comma. Can be generated from plaintext .csv using
-                            the included pytf-hash-users tool.
+    // Logged in sessions
+    let _ = SESSIONS.set(match (&paths.session_list, &paths.users) {
+        (Some(fname), _) => SessionRegistry::load(fname)?,
+        (None, Some(users_file)) => SessionRegistry::load(with_suffix(users_file, ".sessions"))?,
+        (None, None) => SessionRegistry::default(),
+    });
 
-  -m/--molecules  <file>    JSON file containing the available molecules. See docs for details.
-                            Defaults to {RESOURCES_DIR}/molecules.json
+    // API tokens
+    let _ = TOKEN_DB.set(match (&paths.tokens, &paths.users) {
+        (Some(fname), _) => TokenDB::load(fname)?,
+        (None, Some(users_file)) => TokenDB::load(with_suffix(users_file, ".tokens"))?,
+        (None, None) => TokenDB::default(),
+    });
 
-  -r/--resources  <dir>     Resources directory to use. Defaults to ./resources
+    let mut login_throttle = config.login.throttle_settings();
+    if login_throttle.max_user_attempts == 0 || login_throttle.max_ip_attempts == 0 {
+        Err(Error::new(ErrorKind::InvalidInput, "Number of login attempts must be at least 1"))?;
+    }
+    if login_throttle.max_lockout < login_throttle.lockout {
+        log::warn!("Maximum login lockout is less than the initial lockout. Using {}s for both.",
+            login_throttle.max_lockout.as_secs());
+        login_throttle.lockout = login_throttle.max_lockout;
+    }
 
-  -a/--archive    <dir>     Archive directory to store old inactive jobs. Defaults to ./archive
+    // Sessions
+    let mut session_backend: SessionBackend = config.sessions.store.parse()?;
+    if let SessionBackend::File(dir) = &mut session_backend {
+        *dir = config.sessions.dir.clone();
+    }
 
-  -ip             <IP>      IP address of server. Defaults to 127.0.0.1
+    // TLS
+    let tls = match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        (None, None) => None,
+        _ => Err(Error::new(ErrorKind::InvalidInput, "--tls-cert and --tls-key must be used together"))?,
+    };
+    let mut cookie_secure = config.cookies.secure;
+    if tls.is_some() && !cookie_secure {
+        log::info!("Serving over https, so only sending session cookies over https.");
+        cookie_secure = true;
+    }
 
-  --port          <port>    Port for the server to listen on. Defaults to 8080
+    // Cross-origin requests
+    cors::validate(&config.cors)?;
+    if config.cors.allow_any_origin {
+        log::warn!("Allowing cross-origin requests from any origin.");
+    }
 
-  --redis-ip      <IP>      IP address of the Redis server. Defaults to 127.0.0.1
+    // Cookie keys
+    let cookie_key = match &config.cookies.key {
+        Some(fname) => cookie_key::load_or_create(fname)?,
+        None => {
+            log::warn!("No cookie key file provided. Users will be logged out when the server restarts.");
+            Key::generate()
+        }
+    };
+    let previous_cookie_key = match &config.cookies.old_key {
+        Some(fname) => Some(cookie_key::load(fname)?),
+        None => None,
+    };
 
-  --redis-port    <port>    Port of the Redis server. Defaults to 6379
+    // Guest mode
+    let guest_mode = match &config.login.guest_mode {
+        Some(fname) => Some(GuestSettings::open(fname)?),
+        None => None,
+    };
 
-  -h/--help                 Show this message and exit.
-";
+    // OIDC login
+    let oidc = match &config.login.oidc {
+        Some(fname) => Some(OidcSettings::open(fname)?),
+        None => None,
+    };
 
+    Ok(ServerArgs {
+        address: Connection {
+            address: config.server.address.clone(),
+            port: config.server.port,
+        },
+        redis_address: Connection {
+            address: config.sessions.redis_address.clone(),
+            port: config.sessions.redis_port,
+        },
+        session_backend,
+        login_throttle,
+        cookie_key,
+        previous_cookie_key,
+        cookie_secure,
+        tls,
+        guest_mode,
+        oidc,
+    })
+}
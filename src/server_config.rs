@@ -0,0 +1,465 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use toml::{value::Table, Value};
+
+use pytf_web::pytf_frame::WS_FRAME_SIZE_LIMIT;
+
+use crate::{login_throttle::ThrottleSettings, scheduler::Policy};
+
+/// Prefix of environment variables which override settings, as `PYTF_<SECTION>_<KEY>`
+pub const ENV_PREFIX: &str = "PYTF_";
+
+/// Environment variable holding the path of the config file
+pub const CONFIG_FILE_ENV: &str = "PYTF_CONFIG";
+
+/// Effective server configuration, set once the command line has been parsed
+pub static SERVER_CONFIG: OnceLock<ServerConfig> = OnceLock::new();
+
+/// Get the effective server configuration, or the defaults if it hasn't been set (e.g. in tests)
+pub fn config() -> &'static ServerConfig {
+    SERVER_CONFIG.get_or_init(ServerConfig::default)
+}
+
+/// Settings for the server. Each section corresponds to a table in the TOML config file.
+/// Settings are layered: built-in defaults, then the config file, then `PYTF_<SECTION>_<KEY>`
+/// environment variables, then command line flags.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ServerSection,
+    pub paths: PathsSection,
+    pub sessions: SessionsSection,
+    pub cookies: CookiesSection,
+    pub login: LoginSection,
+    pub tls: TlsSection,
+    pub jobs: JobsSection,
+    pub quotas: QuotasSection,
+    pub websocket: WebsocketSection,
+    pub cors: CorsSection,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    /// IP address to listen on
+    pub address: String,
+    pub port: u16,
+    /// Directory containing the built pytf-viewer front end
+    pub frontend_root: PathBuf,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1".into(),
+            port: 8080,
+            frontend_root: "./pytf-viewer/build".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsSection {
+    pub resources: PathBuf,
+    /// Directory to store old inactive jobs
+    pub archive: PathBuf,
+    /// Defaults to `molecules.json` in the resources directory
+    pub molecules: Option<PathBuf>,
+    pub users: Option<PathBuf>,
+    /// Defaults to the users file with `.tokens` appended
+    pub tokens: Option<PathBuf>,
+    /// Defaults to the users file with `.sessions` appended
+    pub session_list: Option<PathBuf>,
+}
+
+impl PathsSection {
+    /// JSON file listing the available molecules
+    pub fn molecules_file(&self) -> PathBuf {
+        self.molecules.clone().unwrap_or_else(|| self.resources.join("molecules.json"))
+    }
+
+    /// Settings for the protocol section of the web page
+    pub fn input_config_file(&self) -> PathBuf {
+        self.resources.join("input_config.yml")
+    }
+}
+
+impl Default for PathsSection {
+    fn default() -> Self {
+        Self {
+            resources: "resources".into(),
+            archive: "archive".into(),
+            molecules: None,
+            users: None,
+            tokens: None,
+            session_list: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsSection {
+    /// One of redis, memory or file
+    pub store: String,
+    /// Directory for the file session store
+    pub dir: PathBuf,
+    pub redis_address: String,
+    pub redis_port: u16,
+}
+
+impl Default for SessionsSection {
+    fn default() -> Self {
+        Self {
+            store: "redis".into(),
+            dir: "sessions".into(),
+            redis_address: "127.0.0.1".into(),
+            redis_port: 6379,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookiesSection {
+    /// File containing the key used to encrypt session cookies
+    pub key: Option<PathBuf>,
+    /// Previous cookie key file, to keep sessions made before a key rotation
+    pub old_key: Option<PathBuf>,
+    /// Only send session cookies over https
+    pub secure: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginSection {
+    /// Failed attempts allowed for a username before it is locked out
+    pub attempts: u32,
+    /// Failed attempts allowed from an IP address before it is locked out
+    pub attempts_ip: u32,
+    pub lockout_secs: u64,
+    pub max_lockout_secs: u64,
+    pub trust_proxy: bool,
+    /// OIDC settings file. OIDC login is disabled if not set.
+    pub oidc: Option<PathBuf>,
+    /// Guest mode settings file. Guest mode is disabled if not set.
+    pub guest_mode: Option<PathBuf>,
+}
+
+impl Default for LoginSection {
+    fn default() -> Self {
+        let throttle = ThrottleSettings::default();
+        Self {
+            attempts: throttle.max_user_attempts,
+            attempts_ip: throttle.max_ip_attempts,
+            lockout_secs: throttle.lockout.as_secs(),
+            max_lockout_secs: throttle.max_lockout.as_secs(),
+            trust_proxy: throttle.trust_proxy,
+            oidc: None,
+            guest_mode: None,
+        }
+    }
+}
+
+impl LoginSection {
+    pub fn throttle_settings(&self) -> ThrottleSettings {
+        ThrottleSettings {
+            max_user_attempts: self.attempts,
+            max_ip_attempts: self.attempts_ip,
+            lockout: Duration::from_secs(self.lockout_secs),
+            max_lockout: Duration::from_secs(self.max_lockout_secs),
+            trust_proxy: self.trust_proxy,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    /// PEM file containing the certificate chain. Serves https if set.
+    pub cert: Option<PathBuf>,
+    /// PEM file containing the private key for `cert`
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsSection {
+    /// How often to check for jobs to archive
+    pub cleanup_interval_secs: u64,
+    /// Time since a job was last used before it can be archived
+    pub max_age_secs: u64,
+    /// How to choose which waiting job runs next
+    pub scheduler: Policy,
+    /// Run jobs which instructors are attached to before any others
+    pub instructor_priority: bool,
+}
+
+impl Default for JobsSection {
+    fn default() -> Self {
+        Self {
+            cleanup_interval_secs: 150,
+            max_age_secs: 300,
+            scheduler: Policy::FairShare,
+            instructor_priority: false,
+        }
+    }
+}
+
+impl JobsSection {
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_secs)
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_secs)
+    }
+}
+
+/// Limits on the jobs each student or guest can start. Instructors and admins aren't limited.
+/// A limit of 0 means no limit.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotasSection {
+    /// Unfinished jobs each user can have held by the server at once
+    pub max_active_jobs: usize,
+    /// Jobs each user can start or resume in any one minute
+    pub max_submissions_per_minute: usize,
+    /// Cycles still to run across each user's unfinished jobs
+    pub max_queued_cycles: usize,
+}
+
+impl Default for QuotasSection {
+    fn default() -> Self {
+        Self { max_active_jobs: 3, max_submissions_per_minute: 10, max_queued_cycles: 0 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebsocketSection {
+    /// How often to ping clients
+    pub client_heartbeat_secs: u64,
+    /// Time without a response before a client is disconnected
+    pub client_timeout_secs: u64,
+    /// How often to ping workers
+    pub worker_heartbeat_secs: u64,
+    /// Time without a response before a worker is disconnected
+    pub worker_timeout_secs: u64,
+    /// Largest web socket frame accepted, in bytes
+    pub frame_size_limit: usize,
+}
+
+impl Default for WebsocketSection {
+    fn default() -> Self {
+        Self {
+            client_heartbeat_secs: 10,
+            client_timeout_secs: 30,
+            worker_heartbeat_secs: 10,
+            worker_timeout_secs: 90,
+            frame_size_limit: WS_FRAME_SIZE_LIMIT,
+        }
+    }
+}
+
+impl WebsocketSection {
+    pub fn client_heartbeat(&self) -> Duration {
+        Duration::from_secs(self.client_heartbeat_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn worker_heartbeat(&self) -> Duration {
+        Duration::from_secs(self.worker_heartbeat_secs)
+    }
+
+    pub fn worker_timeout(&self) -> Duration {
+        Duration::from_secs(self.worker_timeout_secs)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSection {
+    /// Other origins allowed to make cross-origin requests, e.g. "https://example.com".
+    /// Requests from the server's own origin are always allowed.
+    pub allowed_origins: Vec<String>,
+    /// Allow cross-origin requests from any origin. Can't be used with `supports_credentials`.
+    pub allow_any_origin: bool,
+    /// Methods allowed in cross-origin requests
+    pub allowed_methods: Vec<String>,
+    /// Let allowed origins send session cookies with cross-origin requests
+    pub supports_credentials: bool,
+    /// How long browsers can cache the result of a preflight request
+    pub max_age_secs: usize,
+}
+
+impl Default for CorsSection {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allow_any_origin: false,
+            allowed_methods: vec!["GET".into(), "POST".into(), "DELETE".into()],
+            supports_credentials: false,
+            max_age_secs: 3600,
+        }
+    }
+}
+
+/// Merge `overlay` into `base`, replacing values but keeping any tables it doesn't mention
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => { base.insert(key, value); }
+        }
+    }
+}
+
+/// Whether `key` is a setting in `section` of `config`. Settings which are unset by default
+/// (all of them paths to optional files) are missing from `config`, so are checked by trying them.
+fn is_setting(config: &Table, section: &str, key: &str) -> bool {
+    let Some(Value::Table(section_table)) = config.get(section) else { return false };
+    if section_table.contains_key(key) { return true }
+    let mut probe = config.clone();
+    if let Some(Value::Table(section_table)) = probe.get_mut(section) {
+        section_table.insert(key.into(), Value::String(String::new()));
+    }
+    Value::Table(probe).try_into::<ServerConfig>().is_ok()
+}
+
+/// Set a setting from a string given on the command line or in an environment variable.
+/// The value is parsed as TOML (e.g. a number, boolean or array) unless the setting is a string.
+fn set_value(config: &mut Table, section: &str, key: &str, value: &str) -> anyhow::Result<()> {
+    let Some(Value::Table(section_table)) = config.get_mut(section) else {
+        return Err(anyhow!("Unknown config section \"{section}\""))
+    };
+    let value = match section_table.get(key) {
+        Some(Value::String(_)) | None => Value::String(value.into()),
+        Some(_) => toml::from_str::<Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut t| t.remove("value"))
+            .unwrap_or_else(|| Value::String(value.into())),
+    };
+    section_table.insert(key.into(), value);
+    Ok(())
+}
+
+impl ServerConfig {
+    /// Build the configuration from the defaults, the config file (if any), environment
+    /// variables starting with `PYTF_`, then `overrides` of the form `section.key=value`.
+    /// Environment variables which aren't settings are skipped with a warning,
+    /// but unknown `overrides` are an error.
+    pub fn layered(
+        file: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+        overrides: &[String],
+    ) -> anyhow::Result<Self> {
+        let Value::Table(mut config) = Value::try_from(Self::default())? else { unreachable!() };
+        if let Some(file) = file {
+            let contents = std::fs::read_to_string(file)
+                .map_err(|e| anyhow!("Could not read config file {file:?}: {e}"))?;
+            let file_config: Table = toml::from_str(&contents)
+                .map_err(|e| anyhow!("Invalid config file {file:?}: {e}"))?;
+            merge(&mut config, file_config);
+        }
+
+        for (var, value) in env {
+            let Some(name) = var.strip_prefix(ENV_PREFIX) else { continue };
+            if var == CONFIG_FILE_ENV { continue }
+            let name = name.to_ascii_lowercase();
+            // Section names don't contain underscores, so the first one ends the section.
+            // Other tools may use the same prefix, so skip anything which isn't a setting.
+            let Some((section, key)) = name.split_once('_').filter(|(section, key)| is_setting(&config, section, key)) else {
+                log::warn!("Ignoring environment variable {var}, which isn't a setting named {ENV_PREFIX}<SECTION>_<KEY>");
+                continue
+            };
+            set_value(&mut config, section, key, &value)
+                .map_err(|e| anyhow!("Environment variable {var}: {e}"))?;
+        }
+
+        for setting in overrides {
+            let Some((name, value)) = setting.split_once('=') else {
+                return Err(anyhow!("Setting \"{setting}\" should be of the form <section>.<key>=<value>"))
+            };
+            let Some((section, key)) = name.trim().split_once('.') else {
+                return Err(anyhow!("Setting name \"{name}\" should be of the form <section>.<key>"))
+            };
+            set_value(&mut config, section, key, value.trim())?;
+        }
+
+        Value::Table(config).try_into()
+            .map_err(|e| anyhow!("Invalid configuration: {e}"))
+    }
+
+    /// Effective settings as TOML, in the format of the config file
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_layers() {
+        let file = std::env::temp_dir().join(format!("pytf-config-{}.toml", std::process::id()));
+        std::fs::write(&file, "[server]\nport = 9000\naddress = \"0.0.0.0\"\n[jobs]\nmax_age_secs = 600\n").unwrap();
+        let env = [
+            ("PYTF_JOBS_MAX_AGE_SECS".to_string(), "900".to_string()),
+            ("PYTF_CORS_ALLOWED_ORIGINS".to_string(), "[\"https://example.com\"]".to_string()),
+            ("PYTF_PATHS_USERS".to_string(), "users.hashed".to_string()),
+            ("PYTF_CONFIG".to_string(), "ignored.toml".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let config = ServerConfig::layered(
+            Some(&file),
+            env,
+            &["server.port=9001".into(), "websocket.client_timeout_secs = 60".into()],
+        ).unwrap();
+        assert_eq!(config.server.port, 9001);
+        assert_eq!(config.server.address, "0.0.0.0");
+        assert_eq!(config.jobs.max_age(), Duration::from_secs(900));
+        assert_eq!(config.jobs.cleanup_interval_secs, JobsSection::default().cleanup_interval_secs);
+        assert_eq!(config.cors.allowed_origins, vec!["https://example.com".to_string()]);
+        assert_eq!(config.paths.users, Some("users.hashed".into()));
+        assert_eq!(config.websocket.client_timeout(), Duration::from_secs(60));
+
+        // Printed settings can be read back in
+        std::fs::write(&file, config.to_toml().unwrap()).unwrap();
+        assert_eq!(ServerConfig::layered(Some(&file), [], &[]).unwrap(), config);
+
+        std::fs::write(&file, "[jobs]\nmax_age = 600\n").unwrap();
+        assert!(ServerConfig::layered(Some(&file), [], &[]).is_err());
+        std::fs::remove_file(&file).unwrap();
+        assert!(ServerConfig::layered(None, [("PYTF_SERVER_PORT".into(), "http".into())], &[]).is_err());
+        assert!(ServerConfig::layered(None, [], &["server.port".into()]).is_err());
+        assert!(ServerConfig::layered(None, [], &["nope.port=1".into()]).is_err());
+        assert!(ServerConfig::layered(None, [], &["server.nope=1".into()]).is_err());
+
+        // Environment variables which aren't settings are skipped
+        let env = [
+            ("PYTF_NOPE_PORT".to_string(), "1".to_string()),
+            ("PYTF_SERVER_NOPE".to_string(), "1".to_string()),
+            ("PYTF_WORKER".to_string(), "w1".to_string()),
+            ("PYTF_PATHS_TOKENS".to_string(), "tokens.csv".to_string()),
+        ];
+        let config = ServerConfig::layered(None, env, &[]).unwrap();
+        assert_eq!(config.paths.tokens, Some("tokens.csv".into()));
+        assert_eq!(config.server, ServerConfig::default().server);
+
+        // The example config file lists the defaults
+        let example = ServerConfig::layered(Some(Path::new("resources/server.example.toml")), [], &[]).unwrap();
+        assert_eq!(example, ServerConfig::default());
+    }
+}
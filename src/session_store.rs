@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration as StdDuration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_session::storage::{
+    LoadError, RedisSessionStore, SaveError, SessionKey, SessionStore, UpdateError,
+};
+use actix_web::{cookie::time::Duration, web};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use pytf_web::to_hex;
+use serde::{Deserialize, Serialize};
+
+type SessionState = HashMap<String, String>;
+
+/// How often expired sessions are cleared out
+const PRUNE_INTERVAL: StdDuration = StdDuration::from_secs(10 * 60);
+
+/// Where to keep session state, as selected on the command line
+#[derive(Clone, Debug)]
+pub enum SessionBackend {
+    /// Redis server at the address given by --redis-ip and --redis-port
+    Redis,
+    /// In server memory. Sessions are lost when the server restarts.
+    Memory,
+    /// One file per session in the specified directory
+    File(PathBuf),
+}
+
+impl FromStr for SessionBackend {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(Self::Redis),
+            "memory" => Ok(Self::Memory),
+            "file" => Ok(Self::File(PathBuf::from("sessions"))),
+            _ => Err(Error::new(ErrorKind::InvalidInput,
+                format!("Unknown session store \"{s}\". Expected redis, memory or file."))),
+        }
+    }
+}
+
+/// Generate a random session key
+fn generate_key() -> SessionKey {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes).try_into().expect("Hex string should be a valid session key")
+}
+
+fn expiry(ttl: &Duration) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now + StdDuration::from_secs(ttl.whole_seconds().max(0) as u64)).as_secs()
+}
+
+fn is_expired(expires: u64) -> bool {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() >= expires
+}
+
+fn save_to_update_error(e: SaveError) -> UpdateError {
+    match e {
+        SaveError::Serialization(e) => UpdateError::Serialization(e),
+        SaveError::Other(e) => UpdateError::Other(e),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredSession {
+    /// Unix time (in seconds) after which the session is no longer valid
+    expires: u64,
+    state: SessionState,
+}
+
+
+/// Session store which keeps sessions in server memory
+#[derive(Clone, Default)]
+pub struct MemorySessionStore {
+    sessions: Arc<Mutex<HashMap<String, StoredSession>>>,
+    last_prune: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl MemorySessionStore {
+    fn prune(&self) {
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if last_prune.map_or(false, |t| t.elapsed().unwrap_or_default() < PRUNE_INTERVAL) { return }
+        *last_prune = Some(SystemTime::now());
+        self.sessions.lock().unwrap().retain(|_, s| !is_expired(s.expires));
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        Ok(self.sessions.lock().unwrap()
+            .get(session_key.as_ref())
+            .filter(|s| !is_expired(s.expires))
+            .map(|s| s.state.clone()))
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        self.prune();
+        let key = generate_key();
+        self.sessions.lock().unwrap().insert(
+            key.as_ref().to_owned(),
+            StoredSession { expires: expiry(ttl), state: session_state },
+        );
+        Ok(key)
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration)
+    -> Result<SessionKey, UpdateError> {
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.get_mut(session_key.as_ref()) {
+                *session = StoredSession { expires: expiry(ttl), state: session_state };
+                return Ok(session_key)
+            }
+        }
+        // Session has been removed in the meantime, so start a new one
+        self.save(session_state, ttl).await.map_err(save_to_update_error)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(session_key.as_ref()) {
+            session.expires = expiry(ttl);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        self.sessions.lock().unwrap().remove(session_key.as_ref());
+        Ok(())
+    }
+}
+
+
+/// Session store which keeps each session in its own file,
+/// so sessions survive a server restart without needing Redis
+#[derive(Clone)]
+pub struct FileSessionStore {
+    dir: Arc<PathBuf>,
+    last_prune: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl FileSessionStore {
+    pub fn new(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&dir)?;
+        let out = Self { dir: Arc::new(dir.as_ref().to_owned()), last_prune: Default::default() };
+        out.prune();
+        Ok(out)
+    }
+
+    /// Path of the file for the given session. Returns `None` for keys which
+    /// weren't generated by this store, so they can't be used to access other files.
+    fn path(&self, session_key: &SessionKey) -> Option<PathBuf> {
+        let key = session_key.as_ref();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_hexdigit()) { return None }
+        Some(self.dir.join(format!("{key}.json")))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Option<StoredSession>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write via a temporary file, so sessions are never left partially written.
+    /// Session files are only readable by the current user, since they hold session tokens.
+    fn write(path: &Path, session: &StoredSession) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut fid = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fid.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        fid.write_all(&serde_json::to_vec(session)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Remove expired session files
+    fn prune(&self) {
+        {
+            let mut last_prune = self.last_prune.lock().unwrap();
+            if last_prune.map_or(false, |t| t.elapsed().unwrap_or_default() < PRUNE_INTERVAL) { return }
+            *last_prune = Some(SystemTime::now());
+        }
+        let Ok(entries) = fs::read_dir(self.dir.as_ref()) else {
+            log::warn!("Failed to read session directory {}", self.dir.to_string_lossy());
+            return
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension().map_or(true, |ext| ext != "json") { continue }
+            match Self::read(&path) {
+                Ok(Some(session)) if !is_expired(session.expires) => (),
+                Ok(None) => (),
+                _ => if let Err(e) = fs::remove_file(&path) {
+                    log::warn!("Failed to remove expired session {}: {e}", path.to_string_lossy());
+                },
+            }
+        }
+    }
+}
+
+/// Run file operations on the blocking thread pool, to keep them off the async executor
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> anyhow::Result<T> + Send + 'static) -> anyhow::Result<T> {
+    web::block(f).await?
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for FileSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let Some(path) = self.path(session_key) else { return Ok(None) };
+        Ok(blocking(move || Self::read(&path))
+            .await
+            .map_err(LoadError::Other)?
+            .filter(|s| !is_expired(s.expires))
+            .map(|s| s.state))
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let key = generate_key();
+        let path = self.path(&key).expect("Generated session key should be valid");
+        let session = StoredSession { expires: expiry(ttl), state: session_state };
+        let store = self.clone();
+        blocking(move || {
+            store.prune();
+            Self::write(&path, &session)
+        }).await.map_err(SaveError::Other)?;
+        Ok(key)
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration)
+    -> Result<SessionKey, UpdateError> {
+        if let Some(path) = self.path(&session_key) {
+            let session = StoredSession { expires: expiry(ttl), state: session_state.clone() };
+            let updated = blocking(move || {
+                if !path.exists() { return Ok(false) }
+                Self::write(&path, &session).map(|_| true)
+            }).await.map_err(UpdateError::Other)?;
+            if updated { return Ok(session_key) }
+        }
+        // Session has been removed in the meantime, so start a new one
+        self.save(session_state, ttl).await.map_err(save_to_update_error)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        let Some(path) = self.path(session_key) else { return Ok(()) };
+        let expires = expiry(ttl);
+        blocking(move || {
+            if let Some(mut session) = Self::read(&path)? {
+                session.expires = expires;
+                Self::write(&path, &session)?;
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let Some(path) = self.path(session_key) else { return Ok(()) };
+        blocking(move || match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }).await
+    }
+}
+
+
+/// Session store selected at runtime, since `SessionMiddleware` is generic over its store
+#[derive(Clone)]
+pub enum AnySessionStore {
+    Redis(RedisSessionStore),
+    Memory(MemorySessionStore),
+    File(FileSessionStore),
+}
+
+impl AnySessionStore {
+    /// Create the session store for the selected backend
+    pub async fn new(backend: &SessionBackend, redis_url: String) -> anyhow::Result<Self> {
+        Ok(match backend {
+            SessionBackend::Redis => {
+                log::info!("Storing sessions in Redis at {redis_url}");
+                Self::Redis(RedisSessionStore::new(redis_url).await?)
+            }
+            SessionBackend::Memory => {
+                log::info!("Storing sessions in memory. Users will be logged out when the server restarts.");
+                Self::Memory(MemorySessionStore::default())
+            }
+            SessionBackend::File(dir) => {
+                log::info!("Storing sessions in {}", dir.to_string_lossy());
+                Self::File(FileSessionStore::new(dir)?)
+            }
+        })
+    }
+
+    /// Check that the store can be reached by looking up a session which doesn't exist
+    pub async fn check(&self) -> anyhow::Result<()> {
+        self.load(&generate_key()).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for AnySessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            Self::Redis(s) => s.load(session_key).await,
+            Self::Memory(s) => s.load(session_key).await,
+            Self::File(s) => s.load(session_key).await,
+        }
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        match self {
+            Self::Redis(s) => s.save(session_state, ttl).await,
+            Self::Memory(s) => s.save(session_state, ttl).await,
+            Self::File(s) => s.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration)
+    -> Result<SessionKey, UpdateError> {
+        match self {
+            Self::Redis(s) => s.update(session_key, session_state, ttl).await,
+            Self::Memory(s) => s.update(session_key, session_state, ttl).await,
+            Self::File(s) => s.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Redis(s) => s.update_ttl(session_key, ttl).await,
+            Self::Memory(s) => s.update_ttl(session_key, ttl).await,
+            Self::File(s) => s.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Redis(s) => s.delete(session_key).await,
+            Self::Memory(s) => s.delete(session_key).await,
+            Self::File(s) => s.delete(session_key).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_file_session_store() {
+        let dir = std::env::temp_dir().join(format!("pytf-sessions-{}", std::process::id()));
+        let store = FileSessionStore::new(&dir).unwrap();
+        let state = SessionState::from([("user".to_string(), "Foo".to_string())]);
+
+        let key = store.save(state.clone(), &Duration::hours(1)).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), Some(state.clone()));
+
+        // Sessions are read back from disk, so survive a restart
+        let store = FileSessionStore::new(&dir).unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), Some(state.clone()));
+
+        store.update_ttl(&key, &Duration::seconds(0)).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+        store.delete(&key).await.unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = store.save(state.clone(), &Duration::hours(1)).await.unwrap();
+            let mode = fs::metadata(dir.join(format!("{}.json", key.as_ref()))).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let bad_key: SessionKey = "../users".to_string().try_into().unwrap();
+        assert_eq!(store.load(&bad_key).await.unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
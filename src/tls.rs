@@ -0,0 +1,70 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig};
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read all certificates from a PEM file
+fn load_certs(fname: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(fname)?))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("No certificates found in {}", fname.to_string_lossy())))
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Read the first private key (PKCS8, RSA or EC) from a PEM file
+fn load_private_key(fname: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(fname)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => (),
+        }
+    }
+    Err(invalid_data(format!("No private key found in {}", fname.to_string_lossy())))
+}
+
+/// Create the TLS configuration for the server from a PEM certificate chain and private key
+pub fn server_config(cert_file: impl AsRef<Path>, key_file: impl AsRef<Path>) -> io::Result<ServerConfig> {
+    let certs = load_certs(cert_file.as_ref())?;
+    let key = load_private_key(key_file.as_ref())?;
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(format!("Invalid TLS certificate or key: {e}")))
+}
+
+/// Create the TLS configuration for connecting to the server. Server certificates are checked
+/// against the certificates in `ca_file` if provided (e.g. for a self-signed certificate),
+/// or against the standard web root certificates otherwise.
+pub fn client_config(ca_file: Option<impl AsRef<Path>>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(ca_file) => for cert in load_certs(ca_file.as_ref())? {
+            roots.add(&cert).map_err(|e| invalid_data(format!(
+                "Invalid CA certificate in {}: {e}", ca_file.as_ref().to_string_lossy()
+            )))?;
+        },
+        None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        })),
+    }
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    // Web sockets need HTTP/1.1
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,90 @@
+use std::io::ErrorKind;
+
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use pytf_web::{
+    api_tokens::{TokenInfo, TOKEN_DB},
+    authentication::{Role, SessionUser, USER_DB},
+};
+
+/// Request body for creating an API token
+#[derive(Debug, Deserialize)]
+pub struct NewToken {
+    /// Description of what the token is for
+    pub name: String,
+    /// User to create the token for. Only admins can create tokens for other users.
+    pub username: Option<String>,
+}
+
+/// Response to creating an API token. This is the only time the token itself is available.
+#[derive(Debug, Serialize)]
+pub struct CreatedToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: TokenInfo,
+}
+
+/// List the user's own API tokens, or all tokens for admins
+#[get("/tokens")]
+async fn list_tokens(user: SessionUser) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Student)?;
+    let username = if user.role == Role::Admin { None } else { Some(user.username.as_str()) };
+    Ok(HttpResponse::Ok().json(TOKEN_DB.get().unwrap().list(username)))
+}
+
+#[post("/tokens")]
+async fn create_token(user: SessionUser, new_token: web::Json<NewToken>) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Student)?;
+    if user.external {
+        // Tokens are checked against the users file, which external users aren't in
+        return Ok(HttpResponse::Forbidden().body("API tokens are only available to users in the users file."))
+    }
+    let NewToken { name, username } = new_token.into_inner();
+    let username = match username {
+        Some(username) if username != user.username => {
+            user.require(Role::Admin)?;
+            if USER_DB.get().unwrap().active_role(&username).is_none() {
+                return Ok(HttpResponse::NotFound().body(format!("Unknown user \"{username}\"")))
+            }
+            username
+        }
+        _ => user.username.clone(),
+    };
+    match TOKEN_DB.get().unwrap().create(&username, &name) {
+        Ok((info, token)) => {
+            log::info!("API token {} for \"{username}\" created by {}", info.id, user.username);
+            Ok(HttpResponse::Created().json(CreatedToken { token, info }))
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidInput => Ok(HttpResponse::BadRequest().body(e.to_string())),
+        Err(e) => {
+            log::error!("Failed to create API token for \"{username}\": {e}");
+            Ok(HttpResponse::InternalServerError().body(e.to_string()))
+        }
+    }
+}
+
+/// Revoke one of the user's own API tokens. Admins can revoke any token.
+#[delete("/tokens/{id}")]
+async fn revoke_token(user: SessionUser, id: web::Path<String>) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Student)?;
+    let owner = if user.role == Role::Admin { None } else { Some(user.username.as_str()) };
+    match TOKEN_DB.get().unwrap().revoke(&id, owner) {
+        Ok(info) => {
+            log::info!("API token {} of \"{}\" revoked by {}", info.id, info.username, user.username);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HttpResponse::NotFound().body(e.to_string())),
+        Err(e) => {
+            log::error!("Failed to revoke API token {id}: {e}");
+            Ok(HttpResponse::InternalServerError().body(e.to_string()))
+        }
+    }
+}
+
+/// Register the API token endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_tokens)
+        .service(create_token)
+        .service(revoke_token);
+}
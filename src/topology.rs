@@ -0,0 +1,131 @@
+use anyhow::anyhow;
+
+/// Largest difference from a whole number allowed in the net charge of a molecule
+const CHARGE_TOLERANCE: f64 = 1e-2;
+
+/// Longest residue name which fits in the residue name column (18-21) of a .pdb file
+const MAX_RES_NAME_LEN: usize = 4;
+
+/// Check that a residue name is safe to use in file names and GROMACS files,
+/// and fits in a .pdb file
+pub fn check_res_name(res_name: &str) -> anyhow::Result<()> {
+    if res_name.is_empty() || res_name.len() > MAX_RES_NAME_LEN {
+        return Err(anyhow!("Residue name must be between 1 and {MAX_RES_NAME_LEN} characters long"))
+    }
+    if !res_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(anyhow!("Residue name may only contain letters, numbers and underscores"))
+    }
+    Ok(())
+}
+
+/// Check that every atom in the contents of a .pdb file belongs to residue `res_name`
+pub fn check_pdb_residue(pdb: &str, res_name: &str) -> anyhow::Result<()> {
+    for line in pdb.lines().filter(|l| l.starts_with("HETATM") || l.starts_with("ATOM")) {
+        let name = line.get(17..21).map(str::trim).unwrap_or_default();
+        if name != res_name {
+            return Err(anyhow!("Atom in .pdb file has residue name \"{name}\" instead of \"{res_name}\""))
+        }
+    }
+    Ok(())
+}
+
+/// Check the contents of a GROMACS .itp file for a molecule with residue name `res_name`
+/// and `n_atoms` atoms (as read from its .pdb file).
+/// The file must define a single molecule type named `res_name`, with atoms numbered
+/// from 1 to `n_atoms` in residue `res_name` and a whole number net charge,
+/// and bonds only between those atoms.
+pub fn check_topology(itp: &str, res_name: &str, n_atoms: usize) -> anyhow::Result<()> {
+    let mut section = String::new();
+    let mut molecule_types: Vec<&str> = Vec::new();
+    let mut atoms = 0;
+    let mut charge = 0.;
+    for (i, line) in itp.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default().trim();
+        if line.is_empty() { continue }
+        let err = |msg: String| anyhow!("Line {} of .itp file: {msg}", i + 1);
+        if line.starts_with("#include") {
+            return Err(err("Included files aren't supported".into()))
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_owned();
+            continue
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match section.as_str() {
+            "moleculetype" => {
+                if !molecule_types.is_empty() {
+                    return Err(err("Expected a single molecule type".into()))
+                }
+                molecule_types.push(fields[0]);
+            }
+            "atoms" => {
+                if fields.len() < 7 {
+                    return Err(err(format!("Expected at least 7 columns in atom definition, found {}", fields.len())))
+                }
+                atoms += 1;
+                if fields[0].parse::<usize>().ok() != Some(atoms) {
+                    return Err(err(format!("Expected atom number {atoms}, found \"{}\"", fields[0])))
+                }
+                if fields[3] != res_name {
+                    return Err(err(format!("Atom has residue name \"{}\" instead of \"{res_name}\"", fields[3])))
+                }
+                charge += fields[6].parse::<f64>().map_err(|_| err(format!("Invalid charge \"{}\"", fields[6])))?;
+            }
+            "bonds" => {
+                for atom in fields.iter().take(2) {
+                    if !atom.parse::<usize>().is_ok_and(|a| a >= 1 && a <= atoms) {
+                        return Err(err(format!("Bond to unknown atom \"{atom}\"")))
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    match molecule_types.first() {
+        None => return Err(anyhow!("No molecule type defined in .itp file")),
+        Some(&name) if name != res_name => return Err(anyhow!(
+            "Molecule type in .itp file is \"{name}\" instead of \"{res_name}\""
+        )),
+        _ => (),
+    }
+    if atoms != n_atoms {
+        return Err(anyhow!(".itp file defines {atoms} atoms, but the .pdb file has {n_atoms}"))
+    }
+    if (charge - charge.round()).abs() > CHARGE_TOLERANCE {
+        return Err(anyhow!("Net charge of molecule is {charge:.3}, which isn't a whole number"))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_topology() {
+        let pdb = std::fs::read_to_string("resources/molecules/5GOY.pdb").unwrap();
+        let itp = std::fs::read_to_string("resources/molecules/5GOY.itp").unwrap();
+        check_res_name("5GOY").unwrap();
+        check_pdb_residue(&pdb, "5GOY").unwrap();
+        check_topology(&itp, "5GOY", 14).unwrap();
+
+        assert!(check_res_name("../5GOY").is_err());
+        assert!(check_pdb_residue(&pdb, "LCHU").is_err());
+        assert!(check_topology(&itp, "LCHU", 14).is_err());
+        assert!(check_topology(&itp, "5GOY", 13).is_err());
+        assert!(check_topology(&itp.replacen("0.163", "0.5", 1), "5GOY", 14).is_err());
+        assert!(check_topology(&itp.replacen("[ moleculetype ]", "#include \"other.itp\"\n[ moleculetype ]", 1), "5GOY", 14).is_err());
+        assert!(check_topology(&itp.replacen("   11   12    2", "   11   15    2", 1), "5GOY", 14).is_err());
+    }
+
+    #[test]
+    fn test_check_res_name() {
+        check_res_name("A").unwrap();
+        check_res_name("LC_1").unwrap();
+        assert!(check_res_name("").is_err());
+        // Longer names would run into the next column of .pdb files
+        assert!(check_res_name("5GOYA").is_err());
+        assert!(check_res_name("A.B").is_err());
+    }
+}
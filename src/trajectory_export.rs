@@ -0,0 +1,255 @@
+use std::{fmt::Write, str::FromStr};
+
+use actix_web::web::Bytes;
+use anyhow::anyhow;
+
+use crate::pytf_frame::{TrajectorySegment, ELEMENTS};
+
+/// Text formats trajectories can be exported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrajectoryFormat {
+    /// Extended XYZ, coordinates in Angstrom
+    Xyz,
+    /// PDB with one model per frame, coordinates in Angstrom
+    Pdb,
+    /// GROMACS .gro with one frame after another, coordinates in nm
+    Gro,
+}
+
+impl FromStr for TrajectoryFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "xyz" => Ok(Self::Xyz),
+            "pdb" => Ok(Self::Pdb),
+            "gro" => Ok(Self::Gro),
+            _ => Err(format!("Unknown trajectory format \"{s}\". Expected xyz, pdb or gro.")),
+        }
+    }
+}
+
+impl TrajectoryFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Xyz => "xyz",
+            Self::Pdb => "pdb",
+            Self::Gro => "gro",
+        }
+    }
+}
+
+/// Frames unpacked from the binary format of a `TrajectorySegment`
+#[derive(Debug)]
+pub struct SegmentFrames<'a> {
+    pub segment_id: u32,
+    pub n_frames: usize,
+    pub types: &'a [u8],
+    coords: &'a [u8],
+}
+
+impl<'a> SegmentFrames<'a> {
+    /// Check the segment's header against its length, and split it into atom types and coordinates
+    pub fn parse(data: &'a [u8]) -> anyhow::Result<Self> {
+        let read_u32 = |at: usize| data.get(at..at + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| anyhow!("Segment header is truncated"));
+        let segment_id = read_u32(0)?;
+        let n_frames = read_u32(4)? as usize;
+        let n_particles = read_u32(8)? as usize;
+        let expected = n_frames.checked_mul(n_particles * 12)
+            .and_then(|coords| coords.checked_add(12 + n_particles));
+        if expected != Some(data.len()) {
+            let expected = expected.map_or("more".into(), |n| n.to_string());
+            return Err(anyhow!("Segment {segment_id} has {} bytes, expected {expected}", data.len()))
+        }
+        Ok(Self {
+            segment_id,
+            n_frames,
+            types: &data[12..12 + n_particles],
+            coords: &data[12 + n_particles..],
+        })
+    }
+
+    /// Positions (in nm) of the atoms in one frame
+    pub fn frame(&self, idx: usize) -> impl Iterator<Item = [f32; 3]> + 'a {
+        let n_particles = self.types.len();
+        self.coords[idx * n_particles * 12..(idx + 1) * n_particles * 12]
+            .chunks_exact(12)
+            .map(|xyz| [0, 4, 8].map(|i| f32::from_le_bytes(xyz[i..i + 4].try_into().unwrap())))
+    }
+}
+
+/// Element symbol for an atom type, capitalised as usual (e.g. "Cl")
+fn element_symbol(atom_type: u8) -> String {
+    let symbol = ELEMENTS.get(atom_type as usize).unwrap_or(&"X");
+    let mut out = symbol[..1].to_string();
+    out.push_str(&symbol[1..].to_ascii_lowercase());
+    out
+}
+
+/// Iterator over chunks of a trajectory file, one frame at a time, so downloads can be
+/// streamed without converting the whole trajectory first.
+/// Segments which fail to parse are skipped.
+pub struct TrajectoryExport<I> {
+    format: TrajectoryFormat,
+    title: String,
+    segments: I,
+    /// Segment being exported, with the symbols of its atoms
+    current: Option<(TrajectorySegment, Vec<String>)>,
+    /// Index of the next frame within the current segment
+    next_frame: usize,
+    /// Number of frames exported so far
+    frame_count: usize,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = TrajectorySegment>> TrajectoryExport<I> {
+    pub fn new(format: TrajectoryFormat, title: impl Into<String>, segments: I) -> Self {
+        Self {
+            format,
+            title: title.into(),
+            segments,
+            current: None,
+            next_frame: 0,
+            frame_count: 0,
+            finished: false,
+        }
+    }
+
+    fn write_frame(&self, frames: &SegmentFrames, symbols: &[String], out: &mut String) -> std::fmt::Result {
+        let n_particles = symbols.len();
+        let positions = frames.frame(self.next_frame);
+        match self.format {
+            TrajectoryFormat::Xyz => {
+                writeln!(out, "{n_particles}")?;
+                writeln!(out, "Properties=species:S:1:pos:R:3 frame={} segment={} title=\"{}\"",
+                    self.frame_count, frames.segment_id, self.title)?;
+                for (symbol, [x, y, z]) in symbols.iter().zip(positions) {
+                    writeln!(out, "{symbol:<2} {:12.5} {:12.5} {:12.5}", x * 10., y * 10., z * 10.)?;
+                }
+            }
+            TrajectoryFormat::Pdb => {
+                if self.frame_count == 0 {
+                    writeln!(out, "TITLE     {}", self.title)?;
+                }
+                writeln!(out, "MODEL     {:>4}", (self.frame_count + 1) % 10000)?;
+                for (idx, (symbol, [x, y, z])) in symbols.iter().zip(positions).enumerate() {
+                    // One letter element names start in the second column of the atom name
+                    let name = if symbol.len() == 1 { format!(" {symbol}") } else { symbol.clone() };
+                    writeln!(out, "HETATM{:>5} {name:<4} MOL A   1    {:8.3}{:8.3}{:8.3}{:6.2}{:6.2}          {:>2}",
+                        (idx + 1) % 100000, x * 10., y * 10., z * 10., 1.0, 0.0, symbol.to_ascii_uppercase())?;
+                }
+                writeln!(out, "ENDMDL")?;
+            }
+            TrajectoryFormat::Gro => {
+                writeln!(out, "{}, frame {}", self.title, self.frame_count)?;
+                writeln!(out, "{n_particles:>5}")?;
+                // Box size isn't stored with the trajectory, so use the extent of the atoms
+                let mut extent = [0f32; 3];
+                for (idx, (symbol, pos)) in symbols.iter().zip(positions).enumerate() {
+                    for (e, p) in extent.iter_mut().zip(pos) {
+                        *e = e.max(p);
+                    }
+                    let [x, y, z] = pos;
+                    writeln!(out, "{:>5}{:<5}{symbol:>5}{:>5}{x:8.3}{y:8.3}{z:8.3}",
+                        1, "MOL", (idx + 1) % 100000)?;
+                }
+                writeln!(out, "{:10.5}{:10.5}{:10.5}", extent[0], extent[1], extent[2])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<I: Iterator<Item = TrajectorySegment>> Iterator for TrajectoryExport<I> {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished { return None }
+        loop {
+            if let Some((segment, symbols)) = &self.current {
+                let data = segment.data();
+                // Already checked when the segment was loaded
+                let frames = SegmentFrames::parse(&data).unwrap();
+                if self.next_frame < frames.n_frames {
+                    let mut out = String::with_capacity(80 * (symbols.len() + 3));
+                    self.write_frame(&frames, symbols, &mut out).unwrap();
+                    self.next_frame += 1;
+                    self.frame_count += 1;
+                    return Some(out.into())
+                }
+            }
+            let Some(segment) = self.segments.next() else { break };
+            let symbols = match SegmentFrames::parse(&segment.data()) {
+                Ok(frames) => frames.types.iter().copied().map(element_symbol).collect(),
+                Err(e) => {
+                    log::warn!("Skipping segment while exporting \"{}\": {e}", self.title);
+                    continue
+                }
+            };
+            self.current = Some((segment, symbols));
+            self.next_frame = 0;
+        }
+        self.finished = true;
+        match self.format {
+            TrajectoryFormat::Pdb => Some(Bytes::from_static(b"END\n")),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Segment with a carbon and a chlorine atom, moving along x
+    fn test_segment(segment_id: u32, n_frames: u32) -> TrajectorySegment {
+        let mut data = Vec::new();
+        data.extend_from_slice(&segment_id.to_le_bytes());
+        data.extend_from_slice(&n_frames.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[5, 16]);
+        for frame in 0..n_frames {
+            for pos in [[0.1 * frame as f32, 0.2, 0.3], [1.0, 2.0, 3.0]] {
+                for x in pos {
+                    data.extend_from_slice(&x.to_le_bytes());
+                }
+            }
+        }
+        TrajectorySegment::new(data.into())
+    }
+
+    fn export(format: TrajectoryFormat, segments: Vec<TrajectorySegment>) -> String {
+        let chunks: Vec<Bytes> = TrajectoryExport::new(format, "test", segments.into_iter()).collect();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    #[test]
+    fn test_export() {
+        assert!(SegmentFrames::parse(&test_segment(1, 2).data()[..20]).is_err());
+        let bad = TrajectorySegment::new(Bytes::from_static(b"not a segment"));
+        let segments = vec![test_segment(1, 2), bad, test_segment(2, 1)];
+
+        let xyz = export(TrajectoryFormat::Xyz, segments.clone());
+        assert_eq!(xyz.lines().count(), 3 * 4);
+        assert!(xyz.starts_with("2\nProperties=species:S:1:pos:R:3 frame=0 segment=1 title=\"test\"\n"));
+        assert!(xyz.contains("frame=2 segment=2"));
+        assert_eq!(xyz.lines().nth(3).unwrap(), "Cl     10.00000     20.00000     30.00000");
+
+        let pdb = export(TrajectoryFormat::Pdb, segments.clone());
+        assert_eq!(pdb.matches("ENDMDL").count(), 3);
+        assert!(pdb.ends_with("ENDMDL\nEND\n"));
+        let atom = pdb.lines().find(|l| l.starts_with("HETATM")).unwrap();
+        assert_eq!(atom.len(), 78);
+        assert_eq!(&atom[12..16], " C  ");
+        assert_eq!(&atom[30..54], "   0.000   2.000   3.000");
+        assert_eq!(&atom[76..78], " C");
+
+        let gro = export(TrajectoryFormat::Gro, segments);
+        let lines: Vec<&str> = gro.lines().collect();
+        assert_eq!(lines.len(), 3 * 5);
+        assert_eq!(lines[0], "test, frame 0");
+        assert_eq!(lines[3], "    1MOL     Cl    2   1.000   2.000   3.000");
+        assert_eq!(lines[4], "   1.00000   2.00000   3.00000");
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::ErrorKind;
+
+use actix::Addr;
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+
+use pytf_web::{
+    api_tokens::TOKEN_DB,
+    authentication::{Role, SessionUser, USER_DB},
+};
+
+use crate::{
+    account::revoke_sessions,
+    job_queue::{JobServer, UserDeactivated},
+};
+
+/// Request body for creating a new user
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    pub role: Role,
+}
+
+/// Request body for resetting a user's password
+#[derive(Debug, Deserialize)]
+pub struct NewPassword {
+    pub password: String,
+}
+
+/// Convert an error from modifying the user database into a response
+fn error_response(action: &str, name: &str, e: std::io::Error) -> HttpResponse {
+    log::warn!("Failed to {action} \"{name}\": {e}");
+    match e.kind() {
+        ErrorKind::NotFound => HttpResponse::NotFound().body(e.to_string()),
+        ErrorKind::InvalidInput => HttpResponse::BadRequest().body(e.to_string()),
+        ErrorKind::AlreadyExists => HttpResponse::Conflict().body(e.to_string()),
+        _ => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Close any open sessions of a user who can no longer log in.
+/// The job history of a `removed` user is deleted too.
+async fn disconnect(srv: &Addr<JobServer>, name: String, removed: bool) -> usize {
+    srv.send(UserDeactivated { name, removed }).await.unwrap_or(0)
+}
+
+#[get("/users")]
+async fn list_users(user: SessionUser) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    Ok(HttpResponse::Ok().json(USER_DB.get().unwrap().list_users()))
+}
+
+#[post("/users")]
+async fn add_user(user: SessionUser, new_user: web::Json<NewUser>) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    let NewUser { username, password, role } = new_user.into_inner();
+    // Password hashing is slow, so keep it off the worker thread
+    let name = username.clone();
+    if let Err(e) = web::block(move || USER_DB.get().unwrap().add_user(&name, &password, role)).await? {
+        return Ok(error_response("add user", &username, e))
+    }
+    log::info!("User \"{username}\" ({role}) added by {}", user.username);
+    Ok(HttpResponse::Created().finish())
+}
+
+#[post("/users/{name}/disable")]
+async fn disable_user(
+    user: SessionUser,
+    name: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    let name = name.into_inner();
+    if name == user.username {
+        return Ok(HttpResponse::BadRequest().body("Can't disable your own account."))
+    }
+    let username = name.clone();
+    if let Err(e) = web::block(move || USER_DB.get().unwrap().set_locked(&username, true)).await? {
+        return Ok(error_response("disable user", &name, e))
+    }
+    let disconnected = disconnect(&srv, name.clone(), false).await;
+    log::info!("User \"{name}\" disabled by {} ({disconnected} session(s) closed)", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/users/{name}/enable")]
+async fn enable_user(user: SessionUser, name: web::Path<String>) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    let name = name.into_inner();
+    let username = name.clone();
+    if let Err(e) = web::block(move || USER_DB.get().unwrap().set_locked(&username, false)).await? {
+        return Ok(error_response("enable user", &name, e))
+    }
+    log::info!("User \"{name}\" enabled by {}", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/users/{name}/reset")]
+async fn reset_password(
+    user: SessionUser,
+    name: web::Path<String>,
+    new_password: web::Json<NewPassword>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    let name = name.into_inner();
+    let password = new_password.into_inner().password;
+    let username = name.clone();
+    if let Err(e) = web::block(move || USER_DB.get().unwrap().reset_password(&username, &password)).await? {
+        return Ok(error_response("reset password for", &name, e))
+    }
+    let revoked = revoke_sessions(&srv, &name, None).await;
+    log::info!("Password for \"{name}\" reset by {} ({revoked} session(s) logged out)", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/users/{name}")]
+async fn remove_user(
+    user: SessionUser,
+    name: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    let name = name.into_inner();
+    if name == user.username {
+        return Ok(HttpResponse::BadRequest().body("Can't remove your own account."))
+    }
+    let username = name.clone();
+    if let Err(e) = web::block(move || USER_DB.get().unwrap().remove_user(&username)).await? {
+        return Ok(error_response("remove user", &name, e))
+    }
+    // Don't let the tokens come back to life if a user with the same name is added later
+    if let Err(e) = TOKEN_DB.get().unwrap().revoke_user(&name) {
+        log::error!("Failed to revoke API tokens of removed user \"{name}\": {e}");
+    }
+    revoke_sessions(&srv, &name, None).await;
+    let disconnected = disconnect(&srv, name.clone(), true).await;
+    log::info!("User \"{name}\" removed by {} ({disconnected} session(s) closed)", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/workers/{name}/revoke")]
+async fn revoke_worker(
+    user: SessionUser,
+    name: web::Path<String>,
+    srv: web::Data<Addr<JobServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    user.require(Role::Admin)?;
+    let name = name.into_inner();
+    let worker = name.clone();
+    if let Err(e) = web::block(move || USER_DB.get().unwrap().revoke_worker(&worker)).await? {
+        return Ok(error_response("revoke worker", &name, e))
+    }
+    let disconnected = disconnect(&srv, name.clone(), false).await;
+    log::info!("Worker \"{name}\" revoked by {} ({disconnected} session(s) closed)", user.username);
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Re-read the users file and disconnect anyone who has been removed or disabled
+pub async fn reload_users(srv: &Addr<JobServer>) {
+    // Find who has been removed (rather than disabled) and revoke their tokens along with the reload
+    let reloaded = web::block(|| USER_DB.get().unwrap().reload().map(|deactivated| {
+        let users = USER_DB.get().unwrap().list_users();
+        deactivated.into_iter().map(|name| {
+            let removed = !users.iter().any(|u| u.username == name);
+            if removed {
+                if let Err(e) = TOKEN_DB.get().unwrap().revoke_user(&name) {
+                    log::error!("Failed to revoke API tokens of removed user \"{name}\": {e}");
+                }
+            }
+            (name, removed)
+        }).collect::<Vec<_>>()
+    })).await;
+    match reloaded {
+        Ok(Ok(deactivated)) => for (name, removed) in deactivated {
+            let disconnected = disconnect(srv, name.clone(), removed).await;
+            log::info!("User \"{name}\" no longer active after reload ({disconnected} session(s) closed)");
+        }
+        Ok(Err(e)) => log::error!("Failed to reload users file: {e}. Keeping existing users."),
+        Err(e) => log::error!("Failed to reload users file: {e}. Keeping existing users."),
+    }
+}
+
+/// Register the user management endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_users)
+        .service(add_user)
+        .service(disable_user)
+        .service(enable_user)
+        .service(reset_password)
+        .service(remove_user)
+        .service(revoke_worker);
+}
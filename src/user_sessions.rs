@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Mutex, OnceLock, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::write_atomic, to_hex};
+
+/// Logged in sessions of all users
+pub static SESSIONS: OnceLock<SessionRegistry> = OnceLock::new();
+
+/// Sessions are forgotten after this long without being used.
+/// Matches the visit deadline of user identities.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Details of a logged in session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// Identifier used to revoke the session. Stored in the session's `SessionUser`.
+    pub id: String,
+    pub username: String,
+    /// Login time, in seconds since the unix epoch
+    pub created: u64,
+    /// Last time the session was used, in seconds since the unix epoch
+    pub last_seen: u64,
+    /// IP address the session was started from
+    pub ip: String,
+    /// Browser (or other client) the session was started from
+    pub user_agent: String,
+}
+
+type SessionMap = HashMap<String, SessionInfo>;
+
+/// Registry of logged in sessions, so users can see where they are logged in and log out
+/// other devices. A session is only accepted while it is in the registry.
+/// Stored as lines of id,username,created,last_seen,ip,user_agent
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    sessions: RwLock<SessionMap>,
+    /// File the registry was loaded from, and which changes are written back to.
+    file: Option<PathBuf>,
+    /// Whether sessions have been used since the file was last written. Updating `last_seen`
+    /// doesn't write the file on every request, so it's saved by `save_last_seen` instead.
+    touched: AtomicBool,
+    /// Held while changing the sessions, so that `sessions` is only locked to swap in the result
+    writer: Mutex<()>,
+}
+
+impl SessionRegistry {
+    /// Load the registry from the specified file, or start an empty registry
+    /// (saved to that file once someone logs in) if it doesn't exist.
+    pub fn load(fname: impl AsRef<Path>) -> io::Result<Self> {
+        let fname = fname.as_ref();
+        let sessions = match fs::File::open(fname) {
+            Ok(fid) => Self::parse_csv(fid)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SessionMap::new(),
+            Err(e) => return Err(e),
+        };
+        log::debug!("Loaded {} sessions", sessions.len());
+        Ok(Self { sessions: RwLock::new(sessions), file: Some(fname.to_owned()), ..Default::default() })
+    }
+
+    fn parse_csv(file: impl io::Read) -> io::Result<SessionMap> {
+        let mut sessions = SessionMap::new();
+        for (idx, line) in io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() { continue }
+            // User agent goes last, since it can contain commas
+            let fields: Vec<&str> = line.splitn(6, ',').collect();
+            let [id, username, created, last_seen, ip, user_agent] = fields[..] else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Wrong number of fields on line {} of sessions file", idx + 1)));
+            };
+            let (Ok(created), Ok(last_seen)) = (created.parse(), last_seen.parse()) else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Invalid time on line {} of sessions file", idx + 1)));
+            };
+            sessions.insert(id.into(), SessionInfo {
+                id: id.into(),
+                username: username.into(),
+                created,
+                last_seen,
+                ip: ip.into(),
+                user_agent: user_agent.into(),
+            });
+        }
+        Ok(sessions)
+    }
+
+    fn write_csv(sessions: &SessionMap, file: impl io::Write) -> io::Result<()> {
+        let mut entries: Vec<_> = sessions.values().collect();
+        entries.sort_by_key(|info| (info.created, &info.id));
+        let mut fid = io::BufWriter::new(file);
+        for info in entries {
+            writeln!(fid, "{},{},{},{},{},{}",
+                info.id, info.username, info.created, info.last_seen, info.ip, info.user_agent)?;
+        }
+        fid.flush()
+    }
+
+    /// Write `sessions` to the file the registry was loaded from
+    fn save(&self, sessions: &SessionMap) -> io::Result<()> {
+        let Some(fname) = &self.file else { return Ok(()) };
+        write_atomic(fname, |fid| {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fid.set_permissions(fs::Permissions::from_mode(0o600))?;
+            }
+            Self::write_csv(sessions, fid)
+        })
+    }
+
+    /// Apply `change` to a copy of the sessions (with idle sessions removed), write the result
+    /// to the sessions file, then swap it in. The registry is left untouched if anything fails.
+    /// Sessions can still be checked and used while the file is written, so this blocks,
+    /// and should be kept off the executor.
+    fn update<T>(&self, change: impl FnOnce(&mut SessionMap) -> io::Result<T>) -> io::Result<T> {
+        let _writer = self.writer.lock().unwrap();
+        // Sessions used from here on are written by the next update
+        let touched = self.touched.swap(false, Ordering::Relaxed);
+        let cutoff = now().saturating_sub(SESSION_IDLE_TIMEOUT.as_secs());
+        let mut new_sessions: SessionMap = self.sessions.read().unwrap().iter()
+            .filter(|(_, info)| info.last_seen >= cutoff)
+            .map(|(id, info)| (id.clone(), info.clone()))
+            .collect();
+        let out = change(&mut new_sessions).and_then(|out| self.save(&new_sessions).map(|()| out));
+        if out.is_err() {
+            self.touched.fetch_or(touched, Ordering::Relaxed);
+            return out
+        }
+        let mut sessions = self.sessions.write().unwrap();
+        // Keep the times of any sessions used while the file was being written
+        for (id, info) in new_sessions.iter_mut() {
+            if let Some(current) = sessions.get(id) {
+                info.last_seen = info.last_seen.max(current.last_seen);
+            }
+        }
+        *sessions = new_sessions;
+        out
+    }
+
+    /// Register a new session for `username`, returning its id
+    pub fn create(&self, username: &str, ip: &str, user_agent: &str) -> io::Result<String> {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let time = now();
+        let info = SessionInfo {
+            id: to_hex(&bytes),
+            username: username.into(),
+            created: time,
+            last_seen: time,
+            ip: ip.replace(',', ""),
+            // Keep to one line, and not too long
+            user_agent: user_agent.chars().filter(|c| !c.is_control()).take(200).collect(),
+        };
+        let id = info.id.clone();
+        self.update(|sessions| {
+            sessions.insert(info.id.clone(), info);
+            Ok(())
+        })?;
+        Ok(id)
+    }
+
+    /// Check that a session is still active, and mark it as used
+    pub fn touch(&self, username: &str, id: &str) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let Some(info) = sessions.get_mut(id) else { return false };
+        let time = now();
+        if info.username != username || info.last_seen + SESSION_IDLE_TIMEOUT.as_secs() < time {
+            return false
+        }
+        info.last_seen = time;
+        self.touched.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Write the times sessions were last used to the sessions file, if any have been used
+    /// since it was last written. Called periodically and when the server shuts down.
+    pub fn save_last_seen(&self) -> io::Result<()> {
+        if !self.touched.load(Ordering::Relaxed) { return Ok(()) }
+        self.update(|_| Ok(()))
+    }
+
+    /// List a user's sessions, most recently used first
+    pub fn list(&self, username: &str) -> Vec<SessionInfo> {
+        let cutoff = now().saturating_sub(SESSION_IDLE_TIMEOUT.as_secs());
+        let mut out: Vec<SessionInfo> = self.sessions.read().unwrap()
+            .values()
+            .filter(|info| info.username == username && info.last_seen >= cutoff)
+            .cloned()
+            .collect();
+        out.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then_with(|| a.id.cmp(&b.id)));
+        out
+    }
+
+    /// Revoke one of a user's sessions
+    pub fn revoke(&self, username: &str, id: &str) -> io::Result<()> {
+        self.update(|sessions| {
+            match sessions.get(id) {
+                Some(info) if info.username == username => {
+                    sessions.remove(id);
+                    Ok(())
+                }
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown session {id}"))),
+            }
+        })
+    }
+
+    /// Revoke all of a user's sessions, apart from `keep` if specified.
+    /// Returns the ids of the revoked sessions.
+    pub fn revoke_user(&self, username: &str, keep: Option<&str>) -> io::Result<Vec<String>> {
+        let revoke = |info: &SessionInfo| info.username == username && Some(info.id.as_str()) != keep;
+        if !self.sessions.read().unwrap().values().any(revoke) {
+            return Ok(Vec::new())
+        }
+        self.update(|sessions| {
+            let ids: Vec<String> = sessions.values()
+                .filter(|info| revoke(info))
+                .map(|info| info.id.clone())
+                .collect();
+            for id in &ids {
+                sessions.remove(id);
+            }
+            Ok(ids)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let registry = SessionRegistry::default();
+        let first = registry.create("Foo", "127.0.0.1", "Firefox, probably").unwrap();
+        let second = registry.create("Foo", "10.0.0.1", "curl").unwrap();
+        let other = registry.create("Bar", "10.0.0.2", "curl").unwrap();
+        assert!(registry.touch("Foo", &first));
+        assert!(!registry.touch("Bar", &first));
+        assert_eq!(registry.list("Foo").len(), 2);
+        assert!(registry.touched.load(Ordering::Relaxed));
+        registry.save_last_seen().unwrap();
+        assert!(!registry.touched.load(Ordering::Relaxed));
+
+        let mut csv = Vec::new();
+        SessionRegistry::write_csv(&registry.sessions.read().unwrap(), &mut csv).unwrap();
+        assert_eq!(SessionRegistry::parse_csv(csv.as_slice()).unwrap(), *registry.sessions.read().unwrap());
+
+        assert!(registry.revoke("Bar", &first).is_err());
+        registry.revoke("Foo", &second).unwrap();
+        assert!(!registry.touch("Foo", &second));
+        registry.create("Foo", "10.0.0.1", "curl").unwrap();
+        assert_eq!(registry.revoke_user("Foo", Some(&first)).unwrap().len(), 1);
+        assert!(registry.touch("Foo", &first));
+        assert_eq!(registry.revoke_user("Foo", None).unwrap(), vec![first.clone()]);
+        assert!(!registry.touch("Foo", &first));
+        assert!(registry.touch("Bar", &other));
+
+        // Idle sessions are forgotten
+        registry.sessions.write().unwrap().get_mut(&other).unwrap().last_seen = 0;
+        assert!(!registry.touch("Bar", &other));
+        assert!(registry.list("Bar").is_empty());
+    }
+}
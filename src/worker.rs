@@ -6,7 +6,7 @@ use anyhow::anyhow;
 use pytf_web::pytf_config::{RESOURCES_DIR, WORK_DIR};
 use pytf_web::pytf_frame::{AtomNameMap, ATOM_NAME_MAP};
 
-use pytf_web::worker_client::PytfWorker;
+use pytf_web::worker_client::{PytfWorker, ServerAddress};
 
 #[actix_rt::main]
 async fn main() -> anyhow::Result<()> {
@@ -23,6 +23,8 @@ async fn main() -> anyhow::Result<()> {
 
     let mut resources = None;
     let mut work_dir = None;
+    let mut name = None;
+    let mut ca_cert = None;
 
     while let Some(arg) = args.next() {
         match arg.as_ref() {
@@ -32,6 +34,18 @@ async fn main() -> anyhow::Result<()> {
                     return Err(anyhow!("Missing argument for resources directory"));
                 }
             }
+            "-n" | "--name" => {
+                name = args.next();
+                if name.is_none() {
+                    return Err(anyhow!("Missing argument for worker name"));
+                }
+            }
+            "-c" | "--ca-cert" => {
+                ca_cert = args.next();
+                if ca_cert.is_none() {
+                    return Err(anyhow!("Missing argument for CA certificate file"));
+                }
+            }
             "-w" | "--work-dir" => {
                 work_dir = args.next();
                 if work_dir.is_none() {
@@ -54,8 +68,11 @@ async fn main() -> anyhow::Result<()> {
     let _ = ATOM_NAME_MAP.set(AtomNameMap::create());
 
     // Set up connection to server. Will retry if server is unavailable or connection fails.
+    let server = ServerAddress::new(&server_addr, ca_cert.as_deref())?;
     let running = Arc::new(AtomicBool::new(true));
-    let _ = PytfWorker::connect(server_addr, key, running.clone()).await;
+    let name = name.unwrap_or("worker".into());
+    log::info!("Connecting to {server} as worker {name}");
+    let _ = PytfWorker::connect(server, name, key, running.clone()).await;
 
     // Gracefully handle Ctrl-C so worker doesn't try to reconnect when stopped
     ctrlc::set_handler(move || { running.store(false, Ordering::SeqCst); })?;
@@ -68,8 +85,22 @@ async fn main() -> anyhow::Result<()> {
 const HELP_MSG: &str = "
 USAGE: pytf-worker <server_ip:port> <worker_password> [OPTIONS]
 
+Prefix the server address with https:// to connect securely (e.g. https://example.com:8443).
+
+<worker_password> can also be an API token (starting with pytf_) created for the
+worker's user, in which case the worker connects without logging in.
+
   OPTION            ARG         DESCRIPTION
 
+  -n/--name         <name>      Username of this worker on the server. Each worker should
+                                have its own name and key, so that keys can be revoked
+                                individually. Defaults to \"worker\".
+
+  -c/--ca-cert      <file>      PEM file of CA certificates to check the server's TLS
+                                certificate against, instead of the standard web root
+                                certificates (e.g. for a self-signed certificate).
+                                Implies https.
+
   -r/--resources    <dir>       Resources directory to use. Defaults to ./resources
 
   -w/--work-dir     <dir>       Working directory in which to store PyThinFilm runs.
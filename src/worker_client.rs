@@ -12,11 +12,13 @@ use futures_util::{stream::StreamExt, Future};
 use futures::stream::{SplitSink, SplitStream};
 
 use crate::{
+    api_tokens::TOKEN_PREFIX,
     authentication::UserCredentials,
     pytf_runner::{ PytfRunner, PytfStop, PytfPauseFiles, PytfCycle },
     pytf_frame::{SegmentProcessor, NewSocket, WS_FRAME_SIZE_LIMIT},
     pytf_config::PytfConfig,
     split_nullterm_utf8_str,
+    tls,
 };
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
@@ -41,8 +43,66 @@ pub const RESUME_HEADER:  &[u8] = b"resume\0";
 type WsFramedSink = SplitSink<Framed<BoxedSocket, ws::Codec>, ws::Message>;
 type WsFramedStream = SplitStream<Framed<BoxedSocket, ws::Codec>>;
 
+/// Address of the server to connect to, and whether to use TLS (https/wss)
+#[derive(Clone)]
+pub struct ServerAddress {
+    /// Address (and port, if not standard) of the server, without the http(s):// prefix
+    pub address: String,
+    /// TLS configuration, if connecting via https/wss
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl ServerAddress {
+    /// Parse a server address, which may start with http:// or https://.
+    /// https:// addresses connect with TLS, checking the server's certificate
+    /// against `ca_file` if provided, or the standard web root certificates otherwise.
+    /// Providing a `ca_file` implies https.
+    pub fn new(address: &str, ca_file: Option<&str>) -> std::io::Result<Self> {
+        let (address, https) = match (address.strip_prefix("https://"), address.strip_prefix("http://")) {
+            (Some(address), _) => (address, true),
+            (_, Some(address)) if ca_file.is_none() => (address, false),
+            (_, Some(_)) => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "A CA certificate was provided for an http:// server address"
+            )),
+            (None, None) => (address, ca_file.is_some()),
+        };
+        Ok(Self {
+            address: address.trim_end_matches('/').to_owned(),
+            tls: if https { Some(tls::client_config(ca_file)?) } else { None },
+        })
+    }
+
+    /// Create a HTTP client for this server
+    fn client(&self) -> awc::Client {
+        match &self.tls {
+            Some(tls) => awc::Client::builder()
+                .connector(awc::Connector::new().rustls(tls.clone()))
+                .finish(),
+            None => awc::Client::new(),
+        }
+    }
+
+    fn http_url(&self, path: &str) -> String {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{scheme}://{}{path}", self.address)
+    }
+
+    fn ws_url(&self, path: &str) -> String {
+        let scheme = if self.tls.is_some() { "wss" } else { "ws" };
+        format!("{scheme}://{}{path}", self.address)
+    }
+}
+
+impl std::fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.http_url(""))
+    }
+}
+
 pub struct PytfWorker {
-    server_addr: String,
+    server: ServerAddress,
+    name: String,
     key: String,
     socket_sink: SinkWrite<ws::Message, WsFramedSink>,
     heartbeat: Instant,
@@ -51,51 +111,58 @@ pub struct PytfWorker {
     running: Arc<AtomicBool>,
 }
 
-async fn open_ws_connection(server_addr: String, key: String) -> anyhow::Result<(WsFramedSink, WsFramedStream)> {
-    // Log in to server to allow web socket connection
-    let login = match awc::Client::new()
-        .post(format!("http://{}/login", server_addr))
-        .send_json(&UserCredentials {
-            username: "worker".into(),
-            password: key.clone(),
-        }).await
-        {
-            Ok(login) => login,
-            Err(e) => return Err(anyhow!("{e}")),
-        };
+async fn open_ws_connection(server: ServerAddress, name: String, key: String) -> anyhow::Result<(WsFramedSink, WsFramedStream)> {
+    let client = server.client();
+    let socket = client
+        .ws(server.ws_url("/socket"))
+        .max_frame_size(WS_FRAME_SIZE_LIMIT);
+
+    // API tokens can be used to connect directly. Otherwise, log in to the server to get a session.
+    let socket = if key.starts_with(TOKEN_PREFIX) {
+        socket.bearer_auth(key)
+    } else {
+        let login = match client
+            .post(server.http_url("/login"))
+            .send_json(&UserCredentials {
+                username: name,
+                password: key,
+            }).await
+            {
+                Ok(login) => login,
+                Err(e) => return Err(anyhow!("{e}")),
+            };
+
+        if !login.status().is_success() {
+            return Err(anyhow!("Login failed with status {}. Check the worker name and key.", login.status()))
+        }
 
-    // Get ID cookie
-    let Some(login_id) = login.cookie("id") else {
-        return Err(anyhow!("Login failed: Didn't receive id cookie."))
+        // Get ID cookie
+        let Some(login_id) = login.cookie("id") else {
+            return Err(anyhow!("Login failed: Didn't receive id cookie."))
+        };
+        socket.cookie(login_id)
     };
 
-
     // Connect to web socket
-    let socket = match awc::Client::new()
-        .ws(format!("ws://{}/socket", server_addr))
-        .cookie(login_id)
-        .max_frame_size(WS_FRAME_SIZE_LIMIT)
-        .connect()
-        .await
-        {
-            Ok((_, socket)) => socket,
-            Err(e) => return Err(anyhow!("Error connecting to web socket: {e}")),
-        };
+    let socket = match socket.connect().await {
+        Ok((_, socket)) => socket,
+        Err(e) => return Err(anyhow!("Error connecting to web socket: {e}")),
+    };
     Ok(socket.split())
 }
 
 #[async_recursion::async_recursion(?Send)]
-async fn delay_and_reconnect(server_addr: String, key: String) -> (WsFramedSink, WsFramedStream) {
+async fn delay_and_reconnect(server: ServerAddress, name: String, key: String) -> (WsFramedSink, WsFramedStream) {
     log::debug!("Waiting to try reconnection");
     actix_rt::time::sleep(RECONNECT_TIMER).await;
-    match open_ws_connection(server_addr.clone(), key.clone()).await {
+    match open_ws_connection(server.clone(), name.clone(), key.clone()).await {
         Ok(connection) => {
             log::info!("Reconnected.");
             connection
         }
         _ => {
             log::warn!("Failed to reconnect! Trying again in {}s...", RECONNECT_TIMER.as_secs());
-            delay_and_reconnect(server_addr, key).await
+            delay_and_reconnect(server, name, key).await
         }
     }
 }
@@ -104,19 +171,19 @@ async fn delay_and_reconnect(server_addr: String, key: String) -> (WsFramedSink,
 impl PytfWorker {
     /// Initialise a web socket client. Waits and attempts reconnection
     /// if client initialisation fails or the server can't return a test ping.
-    pub async fn connect(server_addr: String, key: String, running: Arc<AtomicBool>) -> Addr<Self> {
-        let (sink, stream) = match open_ws_connection(server_addr.clone(), key.clone()).await {
+    pub async fn connect(server: ServerAddress, name: String, key: String, running: Arc<AtomicBool>) -> Addr<Self> {
+        let (sink, stream) = match open_ws_connection(server.clone(), name.clone(), key.clone()).await {
             Ok(connection) => connection,
             Err(e) => {
                 log::warn!("Error while connecting to server: {e}");
-                delay_and_reconnect(server_addr.clone(), key.clone()).await
+                delay_and_reconnect(server.clone(), name.clone(), key.clone()).await
             }
         };
         Self::create(|ctx| {
             ctx.add_stream(stream);
             let addr = ctx.address();
             Self {
-                server_addr, key,
+                server, name, key,
                 socket_sink: SinkWrite::new(sink, ctx),
                 heartbeat: Instant::now(),
                 worker: None,
@@ -133,7 +200,7 @@ impl PytfWorker {
             if Instant::now().duration_since(act.heartbeat) > SERVER_TIMEOUT {
                 log::warn!("Lost connection to server. Attempting to reconnect...");
                 act.socket_sink.close();
-                delay_and_reconnect(act.server_addr.clone(), act.key.clone())
+                delay_and_reconnect(act.server.clone(), act.name.clone(), act.key.clone())
                     .into_actor(act)
                     .then(|(sink, socket), act, ctx| {
                         act.socket_sink = SinkWrite::new(sink, ctx);
@@ -383,7 +450,7 @@ impl Actor for PytfWorker {
         if !self.socket_sink.closed() && self.running.load(Ordering::SeqCst) {
             log::debug!("Setting up reconnection");
             self.socket_sink.close();
-            delay_and_reconnect(self.server_addr.clone(), self.key.clone())
+            delay_and_reconnect(self.server.clone(), self.name.clone(), self.key.clone())
                 .into_actor(self)
                 .then(move |(sink, stream), act, _ctx| {
                     log::debug!("Creating new socket.");
@@ -394,7 +461,8 @@ impl Actor for PytfWorker {
                     let addr = Self::create(|ctx| {
                             ctx.add_stream(stream);
                             Self {
-                                server_addr: act.server_addr.clone(),
+                                server: act.server.clone(),
+                                name: act.name.clone(),
                                 key: act.key.clone(),
                                 socket_sink: SinkWrite::new(sink, ctx),
                                 heartbeat: Instant::now(),
@@ -1,4 +1,4 @@
-use std::{time::{Duration, Instant}, str};
+use std::{time::Instant, str, sync::Arc};
 
 use actix::prelude::*;
 use actix_web_actors::ws;
@@ -22,12 +22,10 @@ use crate::{
         PausedJobData, UnhandledTrajectorySegment, AddSegmentResult, job_add_seg_and_notify
     },
     client_session::JobFailed,
+    metrics::{Metrics, METRICS},
+    server_config::config,
 };
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
-
-const WORKER_TIMEOUT: Duration = Duration::from_secs(90);
-
 /** MESSAGES TO WORKER NODE:
 *
 * binary(b"job\0{config}")=> new job to run
@@ -56,6 +54,9 @@ const WORKER_TIMEOUT: Duration = Duration::from_secs(90);
 /// Handler for a connected worker node
 #[derive(Debug)]
 pub struct WorkerWsSession {
+    /// Username the worker logged in with
+    pub name: Arc<String>,
+
     pub heartbeat: Instant,
 
     pub job: Option<Job>,
@@ -75,9 +76,15 @@ pub struct WorkerIdle {
     pub addr: Addr<WorkerWsSession>,
 }
 
+/// Sent by the server when this worker's key is revoked
+#[derive(Debug, Clone, Message)]
+#[rtype(result="()")]
+pub struct WorkerForceDisconnect {}
+
 impl WorkerWsSession {
-    pub fn new(job_server: Addr<JobServer>) -> Self {
+    pub fn new(name: String, job_server: Addr<JobServer>) -> Self {
         Self {
+            name: Arc::new(name),
             heartbeat: Instant::now(),
             job: None,
             job_server,
@@ -85,9 +92,11 @@ impl WorkerWsSession {
     }
 
     fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.heartbeat) > WORKER_TIMEOUT {
-                log::info!("Lost connection to worker");
+        let settings = &config().websocket;
+        let timeout = settings.worker_timeout();
+        ctx.run_interval(settings.worker_heartbeat(), move |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > timeout {
+                log::info!("Lost connection to worker {}", act.name);
                 // act.job_server.do_send(WorkerDisconnect { id: act.id });
                 ctx.stop();
                 return;
@@ -102,11 +111,12 @@ impl Actor for WorkerWsSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        Metrics::connected(&METRICS.worker_connections, &METRICS.worker_connections_total);
         self.heartbeat(ctx);
 
         let addr = ctx.address();
         self.job_server
-            .send(WorkerConnect { addr })
+            .send(WorkerConnect { name: self.name.clone(), addr })
             .into_actor(self)
             .then(|res, _act, ctx| {
                 match res {
@@ -144,6 +154,10 @@ impl Actor for WorkerWsSession {
         }
         Running::Stop
     }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        Metrics::dec(&METRICS.worker_connections);
+    }
 }
 
 impl Handler<JobAssignment> for WorkerWsSession {
@@ -153,10 +167,11 @@ impl Handler<JobAssignment> for WorkerWsSession {
     fn handle(&mut self, msg: JobAssignment, ctx: &mut Self::Context) -> Self::Result {
         let job = msg.job;
         let mut job_lock = job.write().unwrap();
-        log::info!("Got job assignment: {}", job_lock.config.name);
+        log::info!("Worker {} got job assignment: {}", self.name, job_lock.config.name);
         match &job_lock.status {
             JobStatus::Waiting => {
                 job_lock.status = JobStatus::Running(ctx.address());
+                job_lock.worker = Some(self.name.clone());
                 match serde_json::to_string(&job_lock.config) {
                     Ok(config) => {
                         // Sanitize old job in case messages got jumbled (probably not needed)
@@ -178,7 +193,7 @@ impl Handler<JobAssignment> for WorkerWsSession {
                             };
                         }
                         ctx.binary([JOB_HEADER, config.as_bytes()].concat());
-                        log::info!("Sent job to worker");
+                        log::info!("Sent job to worker {}", self.name);
                         true
                     }
                     Err(e) => {
@@ -191,6 +206,7 @@ impl Handler<JobAssignment> for WorkerWsSession {
             JobStatus::Steal(data) => {
                 let data = data.clone();
                 job_lock.status = JobStatus::Stealing(data.clone(), ctx.address());
+                job_lock.worker = Some(self.name.clone());
                 match serde_json::to_string(&job_lock.config) {
                     Ok(config) => {
                         // Sanitize old job in case messages got jumbled (probably not needed)
@@ -212,7 +228,7 @@ impl Handler<JobAssignment> for WorkerWsSession {
                             };
                         }
                         ctx.binary([STEAL_HEADER, config.as_bytes(), b"\0", data.data.as_ref()].concat());
-                        log::info!("Sent resume job to worker");
+                        log::info!("Sent resume job to worker {}", self.name);
                         true
                     }
                     Err(e) => {
@@ -227,6 +243,16 @@ impl Handler<JobAssignment> for WorkerWsSession {
     }
 }
 
+impl Handler<WorkerForceDisconnect> for WorkerWsSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: WorkerForceDisconnect, ctx: &mut Self::Context) -> Self::Result {
+        log::info!("Closing connection to worker {}", self.name);
+        ctx.close(Some(ws::CloseCode::Policy.into()));
+        ctx.stop();
+    }
+}
+
 impl Handler<WorkerPause> for WorkerWsSession {
     type Result = ();
 
@@ -255,7 +281,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WorkerWsSession {
                 self.heartbeat = Instant::now();
             }
             ws::Message::Text(_) => {
-                log::warn!("Unexpected text from worker");
+                log::warn!("Unexpected text from worker {}", self.name);
             }
             ws::Message::Binary(mut bytes) => {
                 if bytes.starts_with(PAUSE_HEADER) {
@@ -321,7 +347,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WorkerWsSession {
                         return
                     }
                     // Package back core data with header removed to be forwarded on to clients
-                    let segment_id = u32::from_le_bytes(bytes[..4].as_ref().try_into().unwrap()) as usize;
+                    let segment_id = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
                     let segment = TrajectorySegment::new(bytes);
                     if let Some(job) = &self.job {
                         match job_add_seg_and_notify(job, jobname, segment_id, segment) {
@@ -351,7 +377,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WorkerWsSession {
                             return
                         }
                     };
-                    log::warn!("Worker session received fail message for job {jobname}.");
+                    log::warn!("Worker {} reported failure of job {jobname}.", self.name);
                     if self.job.as_ref().and_then(
                         |j| Some(j.read().unwrap().config.name == jobname)
                     ) != Some(true) {
@@ -362,11 +388,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WorkerWsSession {
                         let clients = {
                             let mut job_lock = job.write().unwrap();
                             job_lock.status = JobStatus::Failed;
+                            Metrics::inc(&METRICS.jobs_failed);
                             let clients = std::mem::replace(&mut job_lock.clients, Vec::new());
                             clients
                         };
                         for client in clients {
-                            client.do_send(JobFailed { jobname: jobname.to_owned(), });
+                            client.do_send(JobFailed { jobname: jobname.to_owned(), worker: self.name.clone() });
                         }
                     }
                     // Get a new job, or add worker back to idle list
@@ -380,12 +407,13 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WorkerWsSession {
                             return
                         }
                     };
-                    log::info!("Job {jobname} is finished.");
+                    log::info!("Job {jobname} finished on worker {}.", self.name);
                     // Mark job as done and add worker to idle list
                     if let Some(job) = self.job.take() {
                         let mut job_lock = job.write().unwrap();
                         if job_lock.config.name == jobname {
                             job_lock.status = JobStatus::Finished;
+                            Metrics::inc(&METRICS.jobs_finished);
                         } else {
                             self.job = Some(job.clone());
                             log::error!("Received done message for different job. This should never happen.");